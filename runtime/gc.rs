@@ -0,0 +1,160 @@
+// Printing and garbage collection of snek values. Shared by start.rs and by the compiler's
+// JIT (src/jit.rs), which runs programs in process, so nothing here refers to globals.
+
+pub const TRUE_VAL: u64 = 3;
+pub const FALSE_VAL: u64 = 1;
+const TAG_MASK: u64 = 7;
+const TUPLE_TAG: u64 = 5;
+const CLOSURE_TAG: u64 = 7;
+
+// builds the printed form of a value, following tuple pointers recursively
+pub fn snek_str(val: u64) -> String {
+    if val & 1 == 0 {
+        format!("{}", (val as i64) >> 1)
+    }
+    else if val == TRUE_VAL { String::from("true") }
+    else if val == FALSE_VAL { String::from("false") }
+    else if val & TAG_MASK == TUPLE_TAG {
+        let addr = (val - TUPLE_TAG) as *const u64;
+        let len = unsafe { *addr.add(1) } as usize;
+        let mut parts = vec![String::from("tuple")];
+        for i in 2..len + 2 {
+            parts.push(snek_str(unsafe { *addr.add(i) }));
+        }
+        format!("({})", parts.join(" "))
+    }
+    else if val & TAG_MASK == CLOSURE_TAG { String::from("<function>") }
+    else { format!("Unknown: {}", val) }
+}
+
+// Heap objects are laid out as [GC word][length n][n words], and a value refers to one by
+// its address tagged in the low bits. The GC word is 0 outside of a collection. Closures
+// store their arity and raw code address in the first two words, which never look like
+// references into the heap.
+
+// bounds of the heap handed to our code
+#[derive(Clone, Copy)]
+pub struct Heap {
+    pub start: *mut u64,
+    pub end: *mut u64,
+}
+
+// address of the heap object a value refers to, if it is a heap value inside [heap.start, heap_ptr)
+unsafe fn heap_object(heap: Heap, val: u64, heap_ptr: *const u64) -> Option<*mut u64> {
+    if val & TAG_MASK != TUPLE_TAG && val & TAG_MASK != CLOSURE_TAG {
+        return None;
+    }
+    let addr = (val & !TAG_MASK) as *mut u64;
+    if addr >= heap.start && (addr as *const u64) < heap_ptr { Some(addr) } else { None }
+}
+
+// Every frame starts with its caller's rbp, which is 0 for main, and keeps the number of
+// slots it reserved in the slot at [rbp - 8]. The roots are the other slots of each frame
+// and the arguments its caller pushed, which run from above the return address up to the
+// caller's slots.
+unsafe fn stack_roots(curr_rbp: *const u64) -> Vec<*mut u64> {
+    let mut roots = Vec::new();
+    let mut rbp = curr_rbp as *mut u64;
+    loop {
+        let slots = *rbp.sub(1) as usize;
+        for i in 2..=slots {
+            roots.push(rbp.sub(i));
+        }
+        let caller = *rbp as *mut u64;
+        if caller.is_null() {
+            return roots;
+        }
+        let caller_slots = caller.sub(*caller.sub(1) as usize);
+        let mut arg = rbp.add(2);
+        while arg < caller_slots {
+            roots.push(arg);
+            arg = arg.add(1);
+        }
+        rbp = caller;
+    }
+}
+
+unsafe fn mark(heap: Heap, roots: &[*mut u64], heap_ptr: *const u64) {
+    let mut worklist: Vec<*mut u64> = roots.iter().filter_map(|r| heap_object(heap, **r, heap_ptr)).collect();
+    while let Some(obj) = worklist.pop() {
+        if *obj != 0 {
+            continue;
+        }
+        *obj = 1;
+        let len = *obj.add(1) as usize;
+        for i in 2..len + 2 {
+            if let Some(child) = heap_object(heap, *obj.add(i), heap_ptr) {
+                worklist.push(child);
+            }
+        }
+    }
+}
+
+// stores the destination of every marked object in its GC word (keeping the mark bit)
+// and returns the new heap pointer
+unsafe fn compute_forwarding(heap: Heap, heap_ptr: *const u64) -> *mut u64 {
+    let mut free = heap.start;
+    let mut obj = heap.start;
+    while (obj as *const u64) < heap_ptr {
+        let size = *obj.add(1) as usize + 2;
+        if *obj != 0 {
+            *obj = free as u64 | 1;
+            free = free.add(size);
+        }
+        obj = obj.add(size);
+    }
+    free
+}
+
+unsafe fn forward(heap: Heap, slot: *mut u64, heap_ptr: *const u64) {
+    if let Some(obj) = heap_object(heap, *slot, heap_ptr) {
+        *slot = (*obj & !1) | (*slot & TAG_MASK);
+    }
+}
+
+unsafe fn update_references(heap: Heap, roots: &[*mut u64], heap_ptr: *const u64) {
+    for root in roots {
+        forward(heap, *root, heap_ptr);
+    }
+    let mut obj = heap.start;
+    while (obj as *const u64) < heap_ptr {
+        let len = *obj.add(1) as usize;
+        if *obj != 0 {
+            for i in 2..len + 2 {
+                forward(heap, obj.add(i), heap_ptr);
+            }
+        }
+        obj = obj.add(len + 2);
+    }
+}
+
+// slides every live object down to its forwarding address and clears its GC word
+unsafe fn compact(heap: Heap, heap_ptr: *const u64) {
+    let mut obj = heap.start;
+    while (obj as *const u64) < heap_ptr {
+        let size = *obj.add(1) as usize + 2;
+        if *obj != 0 {
+            let dest = (*obj & !1) as *mut u64;
+            std::ptr::copy(obj, dest, size);
+            *dest = 0;
+        }
+        obj = obj.add(size);
+    }
+}
+
+// Runs a mark-compact collection using the snek stack as roots. Returns the new heap
+// pointer, or None when there are still fewer than `count` free words.
+pub unsafe fn collect(heap: Heap, count: u64, heap_ptr: *const u64, curr_rbp: *const u64) -> Option<*mut u64> {
+    let roots = stack_roots(curr_rbp);
+
+    mark(heap, &roots, heap_ptr);
+    let new_heap_ptr = compute_forwarding(heap, heap_ptr);
+    update_references(heap, &roots, heap_ptr);
+    compact(heap, heap_ptr);
+
+    if (heap.end as usize - new_heap_ptr as usize) / 8 < count as usize {
+        None
+    } else {
+        Some(new_heap_ptr)
+    }
+}
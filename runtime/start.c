@@ -0,0 +1,416 @@
+// Runtime for the C backend (src/c.rs), which puts this file at the top of every program
+// it generates. It is start.rs and gc.rs for C: values are tagged the same way, errors
+// print the same messages and exit codes, and the heap is collected with the same
+// mark-compact algorithm. The generated code keeps every frame on a stack of its own,
+// snek_stack, so the collector can find the roots without looking at the C stack. The
+// helpers for the generated code are inline so programs that do not use them compile
+// without warnings.
+
+#include <inttypes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef uint64_t val;
+
+// a function of the program, called with the address of its frame; the arguments are
+// in the first slots
+typedef val (*snek_fn)(val *fp);
+
+#define TRUE_VAL ((val)3)
+#define FALSE_VAL ((val)1)
+#define TAG_MASK ((val)7)
+#define TUPLE_TAG ((val)5)
+#define CLOSURE_TAG ((val)7)
+
+#define OVERFLOW_ERROR_CODE 5
+#define INVALID_ARGUMENT_ERROR_CODE 7
+#define INDEX_ERROR_CODE 9
+#define OUT_OF_MEMORY_ERROR_CODE 11
+#define NOT_A_FUNCTION_ERROR_CODE 13
+#define ARITY_ERROR_CODE 15
+#define DIVIDE_BY_ZERO_ERROR_CODE 17
+#define NO_MATCH_ERROR_CODE 19
+
+// default number of 8-byte words available for heap allocation
+#define DEFAULT_HEAP_SIZE 100000
+
+// words for the frames of every active call
+#define STACK_SIZE (32 << 20)
+
+static val *heap_start, *heap_ptr, *heap_end;
+static val *snek_stack, *stack_top, *stack_end;
+
+// set by a call in tail position for the snek_call that made the current call
+static snek_fn tail_fn;
+
+static val our_code_starts_here(val input);
+
+static void snek_error(int code) {
+    const char *msg;
+    switch (code) {
+    case OVERFLOW_ERROR_CODE: msg = "overflow"; break;
+    case INVALID_ARGUMENT_ERROR_CODE: msg = "invalid argument"; break;
+    case INDEX_ERROR_CODE: msg = "index out of bounds or not a tuple"; break;
+    case OUT_OF_MEMORY_ERROR_CODE: msg = "out of memory"; break;
+    case NOT_A_FUNCTION_ERROR_CODE: msg = "called a value that is not a function"; break;
+    case ARITY_ERROR_CODE: msg = "wrong number of arguments"; break;
+    case DIVIDE_BY_ZERO_ERROR_CODE: msg = "division by zero"; break;
+    case NO_MATCH_ERROR_CODE: msg = "no matching clause"; break;
+    default: msg = ""; break;
+    }
+    fprintf(stderr, "an error ocurred - %s\n", msg);
+    exit(1);
+}
+
+// writes the printed form of a value, following tuple pointers recursively
+static void snek_write(FILE *out, val v) {
+    if ((v & 1) == 0) {
+        fprintf(out, "%" PRId64, (int64_t)v >> 1);
+    } else if (v == TRUE_VAL) {
+        fputs("true", out);
+    } else if (v == FALSE_VAL) {
+        fputs("false", out);
+    } else if ((v & TAG_MASK) == TUPLE_TAG) {
+        val *addr = (val *)(uintptr_t)(v - TUPLE_TAG);
+        fputs("(tuple", out);
+        for (val i = 2; i < addr[1] + 2; i++) {
+            fputc(' ', out);
+            snek_write(out, addr[i]);
+        }
+        fputc(')', out);
+    } else if ((v & TAG_MASK) == CLOSURE_TAG) {
+        fputs("<function>", out);
+    } else {
+        fprintf(out, "Unknown: %" PRIu64, v);
+    }
+}
+
+static val snek_print(val v) {
+    snek_write(stdout, v);
+    fputc('\n', stdout);
+    return v;
+}
+
+// Heap objects are [GC word][length n][n words], see gc.rs. Closures keep their arity and
+// the index of their code in snek_code in the first two words.
+
+// address of the heap object a value refers to, or NULL when it is not a heap value
+static val *heap_object(val v) {
+    if ((v & TAG_MASK) != TUPLE_TAG && (v & TAG_MASK) != CLOSURE_TAG) {
+        return NULL;
+    }
+    val *addr = (val *)(uintptr_t)(v & ~TAG_MASK);
+    return addr >= heap_start && addr < heap_ptr ? addr : NULL;
+}
+
+// objects still to be marked
+struct worklist {
+    val **items;
+    size_t len, cap;
+};
+
+static void push(struct worklist *w, val *obj) {
+    if (w->len == w->cap) {
+        w->cap = w->cap == 0 ? 1024 : w->cap * 2;
+        w->items = realloc(w->items, w->cap * sizeof(val *));
+        if (w->items == NULL) {
+            snek_error(OUT_OF_MEMORY_ERROR_CODE);
+        }
+    }
+    w->items[w->len++] = obj;
+}
+
+static void mark(val *roots, val *roots_end) {
+    struct worklist w = { NULL, 0, 0 };
+    for (val *root = roots; root < roots_end; root++) {
+        val *obj = heap_object(*root);
+        if (obj != NULL) {
+            push(&w, obj);
+        }
+    }
+    while (w.len > 0) {
+        val *obj = w.items[--w.len];
+        if (obj[0] != 0) {
+            continue;
+        }
+        obj[0] = 1;
+        for (val i = 2; i < obj[1] + 2; i++) {
+            val *child = heap_object(obj[i]);
+            if (child != NULL) {
+                push(&w, child);
+            }
+        }
+    }
+    free(w.items);
+}
+
+// stores the destination of every marked object in its GC word (keeping the mark bit)
+// and returns the new heap pointer
+static val *compute_forwarding(void) {
+    val *free_ptr = heap_start;
+    for (val *obj = heap_start; obj < heap_ptr; obj += obj[1] + 2) {
+        if (obj[0] != 0) {
+            obj[0] = (val)(uintptr_t)free_ptr | 1;
+            free_ptr += obj[1] + 2;
+        }
+    }
+    return free_ptr;
+}
+
+static void forward(val *slot) {
+    val *obj = heap_object(*slot);
+    if (obj != NULL) {
+        *slot = (obj[0] & ~(val)1) | (*slot & TAG_MASK);
+    }
+}
+
+static void update_references(val *roots, val *roots_end) {
+    for (val *root = roots; root < roots_end; root++) {
+        forward(root);
+    }
+    for (val *obj = heap_start; obj < heap_ptr; obj += obj[1] + 2) {
+        if (obj[0] != 0) {
+            for (val i = 2; i < obj[1] + 2; i++) {
+                forward(&obj[i]);
+            }
+        }
+    }
+}
+
+// slides every live object down to its forwarding address and clears its GC word
+static void compact(void) {
+    val *obj = heap_start;
+    while (obj < heap_ptr) {
+        val size = obj[1] + 2;
+        if (obj[0] != 0) {
+            val *dest = (val *)(uintptr_t)(obj[0] & ~(val)1);
+            memmove(dest, obj, size * sizeof(val));
+            dest[0] = 0;
+        }
+        obj += size;
+    }
+}
+
+// returns room for `words` words on the heap, collecting garbage with the frames on
+// snek_stack as roots when there is not enough left
+static val *snek_alloc(val words) {
+    if (words > (val)(heap_end - heap_ptr)) {
+        mark(snek_stack, stack_top);
+        val *new_heap_ptr = compute_forwarding();
+        update_references(snek_stack, stack_top);
+        compact();
+        heap_ptr = new_heap_ptr;
+        if (words > (val)(heap_end - heap_ptr)) {
+            snek_error(OUT_OF_MEMORY_ERROR_CODE);
+        }
+    }
+    val *obj = heap_ptr;
+    heap_ptr += words;
+    return obj;
+}
+
+// a tuple of the n values starting at items
+static inline val snek_tuple(val *items, val n) {
+    val *obj = snek_alloc(n + 2);
+    obj[0] = 0;
+    obj[1] = n;
+    memcpy(obj + 2, items, n * sizeof(val));
+    return (val)(uintptr_t)obj + TUPLE_TAG;
+}
+
+// a closure with room for `captured` values, which the caller fills in
+static inline val snek_closure(val code, val arity, val captured) {
+    val *obj = snek_alloc(captured + 4);
+    obj[0] = 0;
+    obj[1] = captured + 2;
+    obj[2] = arity << 1;
+    obj[3] = code << 1;
+    return (val)(uintptr_t)obj + CLOSURE_TAG;
+}
+
+static inline val *closure_fields(val closure) {
+    return (val *)(uintptr_t)(closure - CLOSURE_TAG);
+}
+
+// sets up the frame of a function: the slots after the arguments start out zeroed so the
+// collector can treat each of them as a value
+static inline void snek_enter(val *fp, size_t arity, size_t slots) {
+    if (fp + slots > stack_end) {
+        fprintf(stderr, "stack overflow\n");
+        exit(1);
+    }
+    memset(fp + arity, 0, (slots - arity) * sizeof(val));
+    stack_top = fp + slots;
+}
+
+// calls f with its frame at fp, then whatever it tail calls in the same frame
+static inline val snek_call(snek_fn f, val *fp) {
+    val *top = stack_top;
+    val result = 0;
+    while (f != NULL) {
+        result = f(fp);
+        f = tail_fn;
+        tail_fn = NULL;
+    }
+    stack_top = top;
+    return result;
+}
+
+// moves the n arguments at args to the start of the current frame and has snek_call
+// call f next
+static inline val snek_tail(snek_fn f, val *fp, val *args, size_t n) {
+    memmove(fp, args, n * sizeof(val));
+    tail_fn = f;
+    return 0;
+}
+
+static inline val snek_num(int64_t n) {
+    return (val)n << 1;
+}
+
+static inline void check_num(val v) {
+    if ((v & 1) != 0) {
+        snek_error(INVALID_ARGUMENT_ERROR_CODE);
+    }
+}
+
+// booleans are 0b01 and 0b11, so masking with 0b101 leaves 1 only for them
+static inline void check_bool(val v) {
+    if ((v & 5) != 1) {
+        snek_error(INVALID_ARGUMENT_ERROR_CODE);
+    }
+}
+
+// a number and a non-number never compare
+static inline void check_same_type(val a, val b) {
+    if (((a ^ b) & 1) != 0) {
+        snek_error(INVALID_ARGUMENT_ERROR_CODE);
+    }
+}
+
+static inline val snek_bool(int b) {
+    return b ? TRUE_VAL : FALSE_VAL;
+}
+
+// arithmetic on tagged values overflows exactly when the 63-bit result does
+static inline val snek_add(val a, val b) {
+    int64_t r;
+    if (__builtin_add_overflow((int64_t)a, (int64_t)b, &r)) {
+        snek_error(OVERFLOW_ERROR_CODE);
+    }
+    return (val)r;
+}
+
+static inline val snek_sub(val a, val b) {
+    int64_t r;
+    if (__builtin_sub_overflow((int64_t)a, (int64_t)b, &r)) {
+        snek_error(OVERFLOW_ERROR_CODE);
+    }
+    return (val)r;
+}
+
+static inline val snek_mul(val a, val b) {
+    int64_t r;
+    if (__builtin_mul_overflow((int64_t)a, (int64_t)b >> 1, &r)) {
+        snek_error(OVERFLOW_ERROR_CODE);
+    }
+    return (val)r;
+}
+
+// Dividing tagged values gives the untagged quotient and the tagged remainder, rounded
+// towards negative infinity so the remainder takes the sign of the divisor. b is even, so
+// this never divides INT64_MIN by -1.
+static inline void floor_div(val a, val b, int64_t *q, int64_t *r) {
+    if (b == 0) {
+        snek_error(DIVIDE_BY_ZERO_ERROR_CODE);
+    }
+    *q = (int64_t)a / (int64_t)b;
+    *r = (int64_t)a % (int64_t)b;
+    if (*r != 0 && (*r ^ (int64_t)b) < 0) {
+        *q -= 1;
+        *r += (int64_t)b;
+    }
+}
+
+static inline val snek_div(val a, val b) {
+    int64_t q, r;
+    floor_div(a, b, &q, &r);
+    return snek_add((val)q, (val)q);
+}
+
+static inline val snek_mod(val a, val b) {
+    int64_t q, r;
+    floor_div(a, b, &q, &r);
+    return (val)r;
+}
+
+static inline val snek_index(val tuple, val idx) {
+    check_num(idx);
+    if ((tuple & TAG_MASK) != TUPLE_TAG) {
+        snek_error(INDEX_ERROR_CODE);
+    }
+    val *addr = (val *)(uintptr_t)(tuple - TUPLE_TAG);
+    int64_t i = (int64_t)idx >> 1;
+    if (i < 0 || (val)i >= addr[1]) {
+        snek_error(INDEX_ERROR_CODE);
+    }
+    return addr[2 + i];
+}
+
+// the callee of an application must be a closure expecting this many arguments
+static inline val *check_closure(val f, val arity) {
+    if ((f & TAG_MASK) != CLOSURE_TAG) {
+        snek_error(NOT_A_FUNCTION_ERROR_CODE);
+    }
+    val *fields = closure_fields(f);
+    if (fields[2] != arity << 1) {
+        snek_error(ARITY_ERROR_CODE);
+    }
+    return fields;
+}
+
+static val parse_input(const char *s) {
+    if (strcmp(s, "true") == 0) {
+        return TRUE_VAL;
+    }
+    if (strcmp(s, "false") == 0) {
+        return FALSE_VAL;
+    }
+    char *end;
+    long long n = strtoll(s, &end, 10);
+    if (*s == '\0' || *end != '\0') {
+        fprintf(stderr, "Invalid input %s\n", s);
+        exit(1);
+    }
+    return (val)n << 1;
+}
+
+int main(int argc, char **argv) {
+    val input = parse_input(argc >= 2 ? argv[1] : "false");
+
+    // optional second argument is the heap size in words
+    size_t heap_size = DEFAULT_HEAP_SIZE;
+    if (argc >= 3) {
+        char *end;
+        heap_size = strtoull(argv[2], &end, 10);
+        if (*argv[2] == '\0' || *end != '\0') {
+            fprintf(stderr, "Invalid heap size\n");
+            exit(1);
+        }
+    }
+
+    heap_start = calloc(heap_size + 1, sizeof(val));
+    heap_ptr = heap_start;
+    heap_end = heap_start + heap_size;
+    snek_stack = malloc(STACK_SIZE * sizeof(val));
+    stack_top = snek_stack;
+    stack_end = snek_stack + STACK_SIZE;
+    if (heap_start == NULL || snek_stack == NULL) {
+        snek_error(OUT_OF_MEMORY_ERROR_CODE);
+    }
+
+    snek_print(our_code_starts_here(input));
+    return 0;
+}
@@ -1,24 +1,29 @@
 use std::env;
 
+mod gc;
+
+const OUT_OF_MEMORY_ERROR_CODE: i64 = 11;
+
+// default number of 8-byte words available for heap allocation
+const DEFAULT_HEAP_SIZE: usize = 100000;
+
+// bounds of the heap handed to our code, used by the garbage collector
+static mut HEAP_START: *mut u64 = std::ptr::null_mut();
+static mut HEAP_END: *mut u64 = std::ptr::null_mut();
+
 #[link(name = "our_code")]
 extern "C" {
     // The \x01 here is an undocumented feature of LLVM that ensures
     // it does not add an underscore in front of the name.
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
-    fn our_code_starts_here(input: u64) -> u64;
+    fn our_code_starts_here(input: u64, heap_start: *mut u64, heap_end: *mut u64) -> u64;
 }
 
 #[no_mangle]
 #[export_name = "\x01snek_print"]
 fn snek_print(i:u64) -> u64 {
-    if i % 2 == 0 { 
-        let val:i64 = i as i64;
-        println!("{}", val >> 1) 
-    }
-    else if i == 3 { println!("true") }
-    else if i == 1 { println!("false") }
-    else { println!("Unknown: {}",i) }
+    println!("{}", gc::snek_str(i));
 
     return i; // fun note if anyone ever sees this...this is necessary to place the proper value back onto rax (otherwise rax is 0)
 }
@@ -26,24 +31,36 @@ fn snek_print(i:u64) -> u64 {
 #[no_mangle]
 #[export_name = "\x01snek_error"]
 pub extern "C" fn snek_error(errcode: i64) {
-    let i = errcode;
-    if i % 2 == 0 { 
-        let val:i64 = i as i64;
-        println!("{}", val >> 1) 
-    }
-    else if i == 3 { println!("true") }
-    else if i == 1 { println!("false") }
-    else { println!("Unknown: {}",i) }
-    let mut err_msg = "";
-    if errcode == 5 {
-        err_msg = "overflow";
-    } else if errcode == 7 {
-        err_msg = "invalid argument"
-    }
+    let err_msg = match errcode {
+        5 => "overflow",
+        7 => "invalid argument",
+        9 => "index out of bounds or not a tuple",
+        11 => "out of memory",
+        13 => "called a value that is not a function",
+        15 => "wrong number of arguments",
+        17 => "division by zero",
+        19 => "no matching clause",
+        _ => "",
+    };
     eprintln!("an error ocurred - {err_msg}");
     std::process::exit(1);
 }
 
+// Called by our code when an allocation of `count` words would run past the end of the heap.
+// Runs a mark-compact collection using the snek stack as roots and returns the new heap pointer.
+#[no_mangle]
+#[export_name = "\x01snek_try_gc"]
+pub unsafe extern "C" fn snek_try_gc(count: u64, heap_ptr: *const u64, curr_rbp: *const u64) -> *mut u64 {
+    let heap = gc::Heap { start: HEAP_START, end: HEAP_END };
+    match gc::collect(heap, count, heap_ptr, curr_rbp) {
+        Some(new_heap_ptr) => new_heap_ptr,
+        None => {
+            snek_error(OUT_OF_MEMORY_ERROR_CODE);
+            unreachable!()
+        }
+    }
+}
+
 fn parse_input(s: &str) -> u64 {
     if s == "true" { 3 }
     else if s == "false" { 1 }
@@ -58,9 +75,21 @@ fn parse_input(s: &str) -> u64 {
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let input = if args.len() == 2 { &args[1] } else { "false" };
+    let input = if args.len() >= 2 { &args[1] } else { "false" };
     let input = parse_input(&input);
 
-    let i: u64 = unsafe { our_code_starts_here(input) };
+    // optional second argument is the heap size in words
+    let heap_size = if args.len() >= 3 {
+        args[2].parse::<usize>().expect("Invalid heap size")
+    } else {
+        DEFAULT_HEAP_SIZE
+    };
+
+    let mut heap = vec![0u64; heap_size];
+    let i: u64 = unsafe {
+        HEAP_START = heap.as_mut_ptr();
+        HEAP_END = HEAP_START.add(heap_size);
+        our_code_starts_here(input, HEAP_START, HEAP_END)
+    };
     snek_print(i);
 }
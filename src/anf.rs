@@ -0,0 +1,412 @@
+use super::types;
+use super::closure;
+use super::fold;
+use super::callgraph;
+
+use types::Expr;
+use types::ExprKind;
+use types::Definition;
+use types::Op1;
+use types::Op2;
+use types::ErrorKind;
+use types::CompileError;
+
+use im::{HashMap, HashSet};
+use std::fmt;
+
+// A-normal form: the operands of every operation are immediates, so the order in which
+// subexpressions run is spelled out as a chain of lets and codegen never has to find room
+// for intermediate values. Conversion runs after constant folding, the removal of
+// functions main cannot reach and closure conversion, and renames every variable so that
+// names are unique within a function, which lets nested lets be flattened into one chain
+// without capturing anything.
+//
+// The conversion keeps the evaluation order and checks of the compiled code, see interp.rs:
+// an arithmetic operand is checked before the next operand runs (CheckNum), and a
+// variable is copied before a later operand can set! it.
+
+#[derive(Debug, Clone)]
+pub enum Imm {
+    // untagged
+    Num(i64),
+    Bool(bool),
+    Var(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum CExpr {
+    Imm(Imm),
+    Prim1(Op1, Imm),
+    Prim2(Op2, Imm, Imm),
+    // raise invalid argument unless the value is a number (CheckNum) or a boolean (CheckBool)
+    CheckNum(Imm),
+    CheckBool(Imm),
+    If(Imm, Box<AExpr>, Box<AExpr>),
+    Loop(Box<AExpr>),
+    Break(Imm),
+    Set(String, Imm),
+    NoMatch,
+    Call(String, Vec<Imm>),
+    App(Imm, Vec<Imm>),
+    Tuple(Vec<Imm>),
+    Index(Imm, Imm),
+    MakeClosure(String, usize, Vec<String>),
+    ClosureVar(usize),
+}
+
+#[derive(Debug, Clone)]
+pub enum AExpr {
+    Let(String, CExpr, Box<AExpr>),
+    // evaluated for its effect
+    Seq(CExpr, Box<AExpr>),
+    Ret(CExpr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: AExpr,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub defs: Vec<Function>,
+    pub main: AExpr,
+}
+
+// one step of a chain being built, turned into an AExpr by `chain`
+enum Bind {
+    Let(String, CExpr),
+    Seq(CExpr),
+}
+
+struct Converter<'a> {
+    funcs: &'a HashSet<String>,
+    // every name bound so far in the current function
+    used: HashSet<String>,
+    temps: usize,
+}
+
+pub fn convert(p: &types::Program) -> Result<Program, CompileError> {
+    let p = closure::convert(&callgraph::remove_unused(&fold::program(p)));
+
+    let mut defs = Vec::new();
+    for def in &p.defs {
+        let Definition::Fun(name, params, body, _) = def;
+        let mut conv = Converter { funcs: &p.func_list, used: params.iter().cloned().collect(), temps: 0 };
+        let env = params.iter().map(|param| (param.clone(), param.clone())).collect();
+        let body = conv.aexpr(body, &env, false)?;
+        defs.push(Function { name: name.clone(), params: params.clone(), body });
+    }
+
+    let mut conv = Converter { funcs: &p.func_list, used: HashSet::unit(String::from("input")), temps: 0 };
+    let main = conv.aexpr(&p.main, &HashMap::unit(String::from("input"), String::from("input")), false)?;
+    Ok(Program { defs, main })
+}
+
+fn chain(binds: Vec<Bind>, last: CExpr) -> AExpr {
+    binds.into_iter().rev().fold(AExpr::Ret(last), |body, bind| match bind {
+        Bind::Let(name, c) => AExpr::Let(name, c, Box::new(body)),
+        Bind::Seq(c) => AExpr::Seq(c, Box::new(body)),
+    })
+}
+
+fn is_atomic(e: &Expr) -> bool {
+    matches!(e.kind, ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_))
+}
+
+// whether evaluating e can set! a variable of the enclosing function; lambdas only
+// assign their own copies, and closure conversion has already lifted them
+fn assigns(e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::Set(..) => true,
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) | ExprKind::Lambda(..) => false,
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) => assigns(e1),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => assigns(e1) || assigns(e2),
+        ExprKind::If(cond, thn, els) => assigns(cond) || assigns(thn) || assigns(els),
+        ExprKind::Let(binds, body) => binds.iter().any(|(_, val)| assigns(val)) || assigns(body),
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es) | ExprKind::Call(_, es) =>
+            es.iter().any(assigns),
+        ExprKind::App(f, args) => assigns(f) || args.iter().any(assigns),
+    }
+}
+
+impl Converter<'_> {
+    // `base` unless that is taken, otherwise base.1, base.2 and so on
+    fn fresh(&mut self, base: &str) -> String {
+        let mut name = String::from(base);
+        let mut n = 0;
+        while self.used.contains(&name) {
+            n += 1;
+            name = format!("{base}.{n}");
+        }
+        self.used.insert(name.clone());
+        name
+    }
+
+    fn temp(&mut self) -> String {
+        loop {
+            let name = format!("%{}", self.temps);
+            self.temps += 1;
+            if !self.used.contains(&name) {
+                self.used.insert(name.clone());
+                return name;
+            }
+        }
+    }
+
+    fn aexpr(&mut self, e: &Expr, env: &HashMap<String, String>, in_loop: bool) -> Result<AExpr, CompileError> {
+        let mut binds = Vec::new();
+        let last = self.cexpr(e, env, in_loop, &mut binds)?;
+        Ok(chain(binds, last))
+    }
+
+    // an immediate holding the value of e, binding a temporary when e is not one already
+    fn imm(&mut self, e: &Expr, env: &HashMap<String, String>, in_loop: bool, binds: &mut Vec<Bind>) -> Result<Imm, CompileError> {
+        match &e.kind {
+            ExprKind::Number(n) => Ok(Imm::Num(*n as i64)),
+            ExprKind::Boolean(b) => Ok(Imm::Bool(*b)),
+            ExprKind::Id(name) => Ok(Imm::Var(lookup(env, name, e)?)),
+            _ => {
+                let c = self.cexpr(e, env, in_loop, binds)?;
+                let name = self.temp();
+                binds.push(Bind::Let(name.clone(), c));
+                Ok(Imm::Var(name))
+            },
+        }
+    }
+
+    // an immediate for one of several operands; a variable is copied when one of the
+    // operands evaluated after it could set! it
+    fn operand(&mut self, e: &Expr, later: &[&Expr], env: &HashMap<String, String>, in_loop: bool, binds: &mut Vec<Bind>) -> Result<Imm, CompileError> {
+        let imm = self.imm(e, env, in_loop, binds)?;
+        if !matches!(e.kind, ExprKind::Id(_)) || !later.iter().any(|later| assigns(later)) {
+            return Ok(imm);
+        }
+        let name = self.temp();
+        binds.push(Bind::Let(name.clone(), CExpr::Imm(imm)));
+        Ok(Imm::Var(name))
+    }
+
+    fn imms(&mut self, es: &[&Expr], env: &HashMap<String, String>, in_loop: bool, binds: &mut Vec<Bind>) -> Result<Vec<Imm>, CompileError> {
+        let mut imms = Vec::new();
+        for (i, e) in es.iter().enumerate() {
+            imms.push(self.operand(e, &es[i + 1..], env, in_loop, binds)?);
+        }
+        Ok(imms)
+    }
+
+    fn cexpr(&mut self, e: &Expr, env: &HashMap<String, String>, in_loop: bool, binds: &mut Vec<Bind>) -> Result<CExpr, CompileError> {
+        let span = e.span;
+        let c = match &e.kind {
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) => CExpr::Imm(self.imm(e, env, in_loop, binds)?),
+            ExprKind::Let(let_binds, body) => {
+                let mut nenv = env.clone();
+                let mut scope = std::collections::HashSet::new();
+                for (name, val) in let_binds {
+                    if !scope.insert(name) {
+                        return Err(CompileError::new(ErrorKind::DuplicateBinding, span, format!("Error - Duplicate binding of {name}.")));
+                    }
+                    let c = self.cexpr(val, &nenv, in_loop, binds)?;
+                    let unique = self.fresh(name);
+                    binds.push(Bind::Let(unique.clone(), c));
+                    nenv.insert(name.clone(), unique);
+                }
+                self.cexpr(body, &nenv, in_loop, binds)?
+            },
+            ExprKind::Set(name, val) => {
+                let var = lookup(env, name, e)?;
+                CExpr::Set(var, self.imm(val, env, in_loop, binds)?)
+            },
+            ExprKind::UnOp(op, e1) => CExpr::Prim1(op.clone(), self.imm(e1, env, in_loop, binds)?),
+            ExprKind::BinOp(op, e1, e2) => {
+                // equal and minus start with e2
+                let (first, second) = match op {
+                    Op2::Minus | Op2::Equal => (e2, e1),
+                    _ => (e1, e2),
+                };
+                let v1 = self.operand(first, &[second], env, in_loop, binds)?;
+                // arithmetic checks its first operand before the second one runs
+                if matches!(op, Op2::Plus | Op2::Minus | Op2::Times | Op2::Divide | Op2::Modulo) && !is_atomic(second) {
+                    binds.push(Bind::Seq(CExpr::CheckNum(v1.clone())));
+                }
+                let v2 = self.imm(second, env, in_loop, binds)?;
+                match op {
+                    Op2::Minus | Op2::Equal => CExpr::Prim2(op.clone(), v2, v1),
+                    _ => CExpr::Prim2(op.clone(), v1, v2),
+                }
+            },
+            ExprKind::If(cond, thn, els) => {
+                let cond = self.imm(cond, env, in_loop, binds)?;
+                CExpr::If(cond, Box::new(self.aexpr(thn, env, in_loop)?), Box::new(self.aexpr(els, env, in_loop)?))
+            },
+            ExprKind::Loop(body) => CExpr::Loop(Box::new(self.aexpr(body, env, true)?)),
+            ExprKind::Break(val) => {
+                if !in_loop {
+                    return Err(CompileError::new(ErrorKind::BreakOutsideLoop, span, "Error - break must be within a loop."));
+                }
+                CExpr::Break(self.imm(val, env, in_loop, binds)?)
+            },
+            ExprKind::Block(es) => {
+                let (last, init) = es.split_last().expect("Error - empty block");
+                for item in init {
+                    let c = self.cexpr(item, env, in_loop, binds)?;
+                    binds.push(Bind::Seq(c));
+                }
+                self.cexpr(last, env, in_loop, binds)?
+            },
+            ExprKind::And(es) | ExprKind::Or(es) => {
+                let and = matches!(e.kind, ExprKind::And(_));
+                self.logic(es, and, env, in_loop, binds)?
+            },
+            ExprKind::NoMatch => CExpr::NoMatch,
+            ExprKind::Call(name, args) => {
+                if !self.funcs.contains(name) {
+                    return Err(CompileError::new(ErrorKind::UnboundFunction, span, format!("Error - Invalid call to undefined function {name}.")));
+                }
+                let args: Vec<&Expr> = args.iter().collect();
+                CExpr::Call(name.clone(), self.imms(&args, env, in_loop, binds)?)
+            },
+            ExprKind::App(f, args) => {
+                let mut es = vec![&**f];
+                es.extend(args.iter());
+                let mut imms = self.imms(&es, env, in_loop, binds)?;
+                let f = imms.remove(0);
+                CExpr::App(f, imms)
+            },
+            ExprKind::Tuple(es) => {
+                let es: Vec<&Expr> = es.iter().collect();
+                CExpr::Tuple(self.imms(&es, env, in_loop, binds)?)
+            },
+            ExprKind::Index(tuple, idx) => {
+                let imms = self.imms(&[tuple, idx], env, in_loop, binds)?;
+                CExpr::Index(imms[0].clone(), imms[1].clone())
+            },
+            ExprKind::MakeClosure(label, arity, captured) => {
+                let captured = captured.iter().map(|name| lookup(env, name, e)).collect::<Result<_, _>>()?;
+                CExpr::MakeClosure(label.clone(), *arity, captured)
+            },
+            ExprKind::ClosureVar(i) => CExpr::ClosureVar(*i),
+            ExprKind::Lambda(..) => panic!("Error - lambda must be closure converted before conversion to ANF."),
+        };
+        Ok(c)
+    }
+
+    // each operand must be a boolean and only runs while the previous ones did not
+    // short-circuit; the value is the last operand that ran
+    fn logic(&mut self, es: &[Expr], and: bool, env: &HashMap<String, String>, in_loop: bool, binds: &mut Vec<Bind>) -> Result<CExpr, CompileError> {
+        let Some((first, rest)) = es.split_first() else { return Ok(CExpr::Imm(Imm::Bool(and))) };
+        let v = self.imm(first, env, in_loop, binds)?;
+        binds.push(Bind::Seq(CExpr::CheckBool(v.clone())));
+        if rest.is_empty() {
+            return Ok(CExpr::Imm(v));
+        }
+        let mut rest_binds = Vec::new();
+        let rest_last = self.logic(rest, and, env, in_loop, &mut rest_binds)?;
+        let rest = Box::new(chain(rest_binds, rest_last));
+        let done = Box::new(AExpr::Ret(CExpr::Imm(v.clone())));
+        Ok(if and { CExpr::If(v, rest, done) } else { CExpr::If(v, done, rest) })
+    }
+}
+
+fn lookup(env: &HashMap<String, String>, name: &str, e: &Expr) -> Result<String, CompileError> {
+    match env.get(name) {
+        Some(var) => Ok(var.clone()),
+        None => Err(CompileError::new(ErrorKind::UnboundVariable, e.span, format!("Error - Unbound variable identifier {name}"))),
+    }
+}
+
+impl fmt::Display for Imm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Imm::Num(n) => write!(f, "{n}"),
+            Imm::Bool(b) => write!(f, "{b}"),
+            Imm::Var(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+fn imms_str(imms: &[Imm]) -> String {
+    imms.iter().map(|imm| format!(" {imm}")).collect()
+}
+
+// Prints one step per line, nesting the branches of if and the body of loop:
+//
+//     %0 = (= lst false)
+//     if %0
+//       acc
+//     else
+//       ...
+fn write_cexpr(f: &mut fmt::Formatter<'_>, c: &CExpr, indent: usize) -> fmt::Result {
+    let pad = "  ".repeat(indent);
+    match c {
+        CExpr::Imm(imm) => write!(f, "{imm}"),
+        CExpr::Prim1(op, imm) => write!(f, "({op} {imm})"),
+        CExpr::Prim2(op, a, b) => write!(f, "({op} {a} {b})"),
+        CExpr::CheckNum(imm) => write!(f, "(check-num {imm})"),
+        CExpr::CheckBool(imm) => write!(f, "(check-bool {imm})"),
+        CExpr::If(cond, thn, els) => {
+            writeln!(f, "if {cond}")?;
+            write_aexpr(f, thn, indent + 1)?;
+            writeln!(f, "{pad}else")?;
+            write_aexpr(f, els, indent + 1)?;
+            write!(f, "{pad}end")
+        },
+        CExpr::Loop(body) => {
+            writeln!(f, "loop")?;
+            write_aexpr(f, body, indent + 1)?;
+            write!(f, "{pad}end")
+        },
+        CExpr::Break(imm) => write!(f, "(break {imm})"),
+        CExpr::Set(name, imm) => write!(f, "(set! {name} {imm})"),
+        CExpr::NoMatch => write!(f, "(cond)"),
+        CExpr::Call(name, args) => write!(f, "({name}{})", imms_str(args)),
+        CExpr::App(func, args) => write!(f, "(apply {func}{})", imms_str(args)),
+        CExpr::Tuple(items) => write!(f, "(tuple{})", imms_str(items)),
+        CExpr::Index(tuple, idx) => write!(f, "(index {tuple} {idx})"),
+        CExpr::MakeClosure(label, arity, captured) => write!(f, "<closure {label}/{arity} [{}]>", captured.join(" ")),
+        CExpr::ClosureVar(i) => write!(f, "<captured {i}>"),
+    }
+}
+
+fn write_aexpr(f: &mut fmt::Formatter<'_>, e: &AExpr, indent: usize) -> fmt::Result {
+    let pad = "  ".repeat(indent);
+    let mut e = e;
+    loop {
+        match e {
+            AExpr::Let(name, c, body) => {
+                write!(f, "{pad}{name} = ")?;
+                write_cexpr(f, c, indent)?;
+                writeln!(f)?;
+                e = body;
+            },
+            AExpr::Seq(c, body) => {
+                write!(f, "{pad}")?;
+                write_cexpr(f, c, indent)?;
+                writeln!(f)?;
+                e = body;
+            },
+            AExpr::Ret(c) => {
+                write!(f, "{pad}")?;
+                write_cexpr(f, c, indent)?;
+                return writeln!(f);
+            },
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for def in &self.defs {
+            let mut head = vec![def.name.clone()];
+            head.extend(def.params.iter().cloned());
+            writeln!(f, "(fun ({})", head.join(" "))?;
+            write_aexpr(f, &def.body, 1)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "(main)")?;
+        write_aexpr(f, &self.main, 1)
+    }
+}
@@ -0,0 +1,323 @@
+use super::types;
+use super::closure;
+use super::fold;
+use super::callgraph;
+use super::compiler;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use types::Op1;
+use types::Op2;
+use types::ErrorKind;
+use types::CompileError;
+
+use im::HashMap;
+
+// C backend: translates a program into one self-contained C file, runtime/start.c followed
+// by a C function per definition. It keeps the frame layout of the x86-64 backend: the
+// value of every expression goes to `rax` and intermediates to the slots of the frame
+// (fp[si]), evaluated in the same order and with the same checks, so programs print and
+// fail the same way. Frames live on snek_stack rather than the C stack, which lets the collector
+// find every root, and a call in tail position returns to the snek_call that made the
+// current call, which then makes the tail call in the same frame.
+
+const RUNTIME: &str = include_str!("../runtime/start.c");
+
+struct Gen {
+    out: String,
+    indent: usize,
+    // slots used by the function being generated
+    slots: usize,
+    // code of every closure, the table MakeClosure indexes into
+    closures: Vec<String>,
+    // whether any App looks up code in the table
+    applies: bool,
+}
+
+pub fn compile(p: &Program) -> Result<String, CompileError> {
+    // fold constants, drop the functions main cannot reach and lift lambdas into
+    // definitions of their own
+    let p = &closure::convert(&callgraph::remove_unused(&fold::program(p)));
+
+    let mut gen = Gen { out: String::new(), indent: 0, slots: 0, closures: Vec::new(), applies: false };
+    let mut bodies = String::new();
+    for def in &p.defs {
+        let Definition::Fun(name, params, body, _) = def;
+        let env = params.iter().enumerate().map(|(i, param)| (param.clone(), format!("fp[{i}]"))).collect();
+        gen.start(params.len());
+        gen.compile_expr(body, params.len(), &env, false, true)?;
+        bodies.push_str(&gen.finish(&format!("static val {}(val *fp)", compiler::fun_label(name)), "fp", params.len()));
+    }
+
+    // main keeps its input in the first slot
+    gen.start(1);
+    gen.compile_expr(&p.main, 1, &HashMap::unit(String::from("input"), String::from("fp[0]")), false, false)?;
+    let main = gen.finish("static val our_code_starts_here(val input)", "snek_stack", 0);
+
+    let mut out = String::from(RUNTIME);
+    out.push('\n');
+    for def in &p.defs {
+        let Definition::Fun(name, _, _, _) = def;
+        out.push_str(&format!("static val {}(val *fp);\n", compiler::fun_label(name)));
+    }
+    if gen.applies {
+        let mut table: Vec<String> = gen.closures.iter().map(|label| compiler::fun_label(label)).collect();
+        if table.is_empty() {
+            // C has no empty arrays
+            table.push(String::from("NULL"));
+        }
+        out.push_str(&format!("\nstatic const snek_fn snek_code[] = {{ {} }};\n", table.join(", ")));
+    }
+    out.push_str(&bodies);
+    out.push_str(&main);
+    Ok(out)
+}
+
+impl Gen {
+    fn start(&mut self, slots: usize) {
+        self.out.clear();
+        self.indent = 1;
+        self.slots = slots;
+    }
+
+    // the function around the statements generated since start
+    fn finish(&mut self, head: &str, frame: &str, arity: usize) -> String {
+        let mut out = format!("\n{head} {{\n");
+        if frame != "fp" {
+            out.push_str(&format!("    val *fp = {frame};\n"));
+        }
+        out.push_str("    val rax = 0;\n");
+        out.push_str(&format!("    snek_enter(fp, {arity}, {});\n", self.slots));
+        if frame != "fp" {
+            out.push_str("    fp[0] = input;\n");
+        }
+        out.push_str(&self.out);
+        out.push_str("    return rax;\n}\n");
+        out
+    }
+
+    fn line(&mut self, s: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    fn slot(&mut self, si: usize) -> String {
+        self.slots = self.slots.max(si + 1);
+        format!("fp[{si}]")
+    }
+
+    fn block(&mut self, head: &str, f: impl FnOnce(&mut Gen) -> Result<(), CompileError>) -> Result<(), CompileError> {
+        self.line(&format!("{head} {{"));
+        self.indent += 1;
+        f(self)?;
+        self.indent -= 1;
+        self.line("}");
+        Ok(())
+    }
+
+    // evaluates e and stores the value in fp[si]
+    fn compile_to_slot(&mut self, e: &Expr, si: usize, env: &HashMap<String, String>, in_loop: bool) -> Result<String, CompileError> {
+        self.compile_expr(e, si, env, in_loop, false)?;
+        let slot = self.slot(si);
+        self.line(&format!("{slot} = rax;"));
+        Ok(slot)
+    }
+
+    // `env` maps each variable to its slot; `tail` is set when e is in tail position
+    fn compile_expr(&mut self, e: &Expr, si: usize, env: &HashMap<String, String>, in_loop: bool, tail: bool) -> Result<(), CompileError> {
+        match &e.kind {
+            ExprKind::Number(n) => self.line(&format!("rax = snek_num({});", *n as i64)),
+            ExprKind::Boolean(b) => self.line(if *b { "rax = TRUE_VAL;" } else { "rax = FALSE_VAL;" }),
+            ExprKind::Id(name) => {
+                let slot = lookup(env, name, e)?;
+                self.line(&format!("rax = {slot};"));
+            },
+            ExprKind::Let(binds, body) => {
+                let mut nenv = env.clone();
+                let mut si = si;
+                for (name, val) in binds {
+                    let slot = self.compile_to_slot(val, si, &nenv, in_loop)?;
+                    nenv.insert(name.clone(), slot);
+                    si += 1;
+                }
+                self.compile_expr(body, si + 1, &nenv, in_loop, tail)?;
+            },
+            ExprKind::Set(name, val) => {
+                let slot = lookup(env, name, e)?;
+                self.compile_expr(val, si, env, in_loop, false)?;
+                self.line(&format!("{slot} = rax;"));
+            },
+            ExprKind::Block(es) => {
+                let (last, init) = es.split_last().expect("Error - empty block");
+                for item in init {
+                    self.compile_expr(item, si, env, in_loop, false)?;
+                }
+                self.compile_expr(last, si, env, in_loop, tail)?;
+            },
+            ExprKind::If(cond, thn, els) => {
+                self.compile_expr(cond, si, env, in_loop, false)?;
+                self.block("if (rax != FALSE_VAL)", |gen| gen.compile_expr(thn, si, env, in_loop, tail))?;
+                self.block("else", |gen| gen.compile_expr(els, si + 1, env, in_loop, tail))?;
+            },
+            ExprKind::Loop(body) => self.block("for (;;)", |gen| gen.compile_expr(body, si, env, true, false))?,
+            ExprKind::Break(val) => {
+                if !in_loop {
+                    return Err(CompileError::new(ErrorKind::BreakOutsideLoop, e.span, "Error - break must be within a loop."));
+                }
+                // a loop inside val has already ended, so this leaves the innermost loop around the break
+                self.compile_expr(val, si, env, in_loop, false)?;
+                self.line("break;");
+            },
+            ExprKind::And(es) | ExprKind::Or(es) => {
+                // each operand is only evaluated while the previous ones did not short-circuit
+                let (short_val, default_val) = match &e.kind {
+                    ExprKind::And(_) => ("FALSE_VAL", "TRUE_VAL"),
+                    _ => ("TRUE_VAL", "FALSE_VAL"),
+                };
+                self.line(&format!("rax = {default_val};"));
+                self.compile_logic(es, short_val, si, env, in_loop)?;
+            },
+            ExprKind::NoMatch => self.line("snek_error(NO_MATCH_ERROR_CODE);"),
+            ExprKind::UnOp(op, e1) => {
+                self.compile_expr(e1, si, env, in_loop, false)?;
+                match op {
+                    Op1::Add1 => {
+                        self.line("check_num(rax);");
+                        self.line("rax = snek_add(rax, snek_num(1));");
+                    },
+                    Op1::Sub1 => {
+                        self.line("check_num(rax);");
+                        self.line("rax = snek_sub(rax, snek_num(1));");
+                    },
+                    Op1::IsNum => self.line("rax = snek_bool((rax & 1) == 0);"),
+                    Op1::IsBool => self.line("rax = snek_bool((rax & 5) == 1);"),
+                    Op1::Not => {
+                        self.line("check_bool(rax);");
+                        self.line("rax ^= TRUE_VAL ^ FALSE_VAL;");
+                    },
+                    Op1::Print => self.line("rax = snek_print(rax);"),
+                }
+            },
+            ExprKind::BinOp(op, e1, e2) => self.compile_binop(op, e1, e2, si, env, in_loop)?,
+            ExprKind::Call(name, args) => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_to_slot(arg, si + i, env, in_loop)?;
+                }
+                let f = compiler::fun_label(name);
+                if tail {
+                    self.line(&format!("return snek_tail({f}, fp, fp + {si}, {});", args.len()));
+                } else {
+                    self.line(&format!("rax = snek_call({f}, fp + {si});"));
+                }
+            },
+            ExprKind::Tuple(es) => {
+                for (i, item) in es.iter().enumerate() {
+                    self.compile_to_slot(item, si + i, env, in_loop)?;
+                }
+                self.line(&format!("rax = snek_tuple(fp + {si}, {});", es.len()));
+            },
+            ExprKind::Index(tuple, idx) => {
+                let slot = self.compile_to_slot(tuple, si, env, in_loop)?;
+                self.compile_expr(idx, si + 1, env, in_loop, false)?;
+                self.line(&format!("rax = snek_index({slot}, rax);"));
+            },
+            ExprKind::MakeClosure(label, arity, captured) => {
+                let code = match self.closures.iter().position(|l| l == label) {
+                    Some(code) => code,
+                    None => {
+                        self.closures.push(label.clone());
+                        self.closures.len() - 1
+                    },
+                };
+                self.line(&format!("rax = snek_closure({code}, {arity}, {});", captured.len()));
+                for (i, name) in captured.iter().enumerate() {
+                    let slot = lookup(env, name, e)?;
+                    self.line(&format!("closure_fields(rax)[{}] = {slot};", 4 + i));
+                }
+            },
+            // the closure is always the first argument of a lifted function
+            ExprKind::ClosureVar(i) => self.line(&format!("rax = closure_fields(fp[0])[{}];", 4 + i)),
+            ExprKind::App(f, args) => {
+                let closure = self.compile_to_slot(f, si, env, in_loop)?;
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_to_slot(arg, si + 1 + i, env, in_loop)?;
+                }
+                self.applies = true;
+                let code = format!("snek_code[check_closure({closure}, {})[3] >> 1]", args.len());
+                if tail {
+                    self.line(&format!("return snek_tail({code}, fp, fp + {si}, {});", args.len() + 1));
+                } else {
+                    self.line(&format!("rax = snek_call({code}, fp + {si});"));
+                }
+            },
+            ExprKind::Lambda(..) => panic!("Error - lambda must be closure converted before compilation."),
+        }
+        Ok(())
+    }
+
+    fn compile_logic(&mut self, es: &[Expr], short_val: &str, si: usize, env: &HashMap<String, String>, in_loop: bool) -> Result<(), CompileError> {
+        let Some((first, rest)) = es.split_first() else { return Ok(()) };
+        self.compile_expr(first, si, env, in_loop, false)?;
+        self.line("check_bool(rax);");
+        if !rest.is_empty() {
+            self.block(&format!("if (rax != {short_val})"), |gen| gen.compile_logic(rest, short_val, si, env, in_loop))?;
+        }
+        Ok(())
+    }
+
+    fn compile_binop(&mut self, op: &Op2, e1: &Expr, e2: &Expr, si: usize, env: &HashMap<String, String>, in_loop: bool) -> Result<(), CompileError> {
+        match op {
+            // each operand is checked right after it is evaluated; minus starts with e2
+            Op2::Plus | Op2::Minus | Op2::Times | Op2::Divide | Op2::Modulo => {
+                let (first, second) = if let Op2::Minus = op { (e2, e1) } else { (e1, e2) };
+                self.compile_expr(first, si, env, in_loop, false)?;
+                self.line("check_num(rax);");
+                let slot = self.slot(si);
+                self.line(&format!("{slot} = rax;"));
+                self.compile_expr(second, si + 1, env, in_loop, false)?;
+                self.line("check_num(rax);");
+                let result = match op {
+                    Op2::Plus => format!("snek_add({slot}, rax)"),
+                    Op2::Minus => format!("snek_sub(rax, {slot})"),
+                    Op2::Times => format!("snek_mul({slot}, rax)"),
+                    Op2::Divide => format!("snek_div({slot}, rax)"),
+                    _ => format!("snek_mod({slot}, rax)"),
+                };
+                self.line(&format!("rax = {result};"));
+            },
+            Op2::Equal => {
+                let slot = self.compile_to_slot(e2, si, env, in_loop)?;
+                self.compile_expr(e1, si + 1, env, in_loop, false)?;
+                self.line(&format!("check_same_type(rax, {slot});"));
+                self.line(&format!("rax = snek_bool(rax == {slot});"));
+            },
+            // comparisons check both operands once they have been evaluated
+            Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+                let slot = self.compile_to_slot(e1, si, env, in_loop)?;
+                self.compile_expr(e2, si + 1, env, in_loop, false)?;
+                self.line(&format!("check_same_type(rax, {slot});"));
+                self.line("check_num(rax);");
+                let cmp = match op {
+                    Op2::Greater => ">",
+                    Op2::GreaterEqual => ">=",
+                    Op2::Less => "<",
+                    _ => "<=",
+                };
+                self.line(&format!("rax = snek_bool((int64_t){slot} {cmp} (int64_t)rax);"));
+            },
+        }
+        Ok(())
+    }
+}
+
+fn lookup(env: &HashMap<String, String>, name: &str, e: &Expr) -> Result<String, CompileError> {
+    match env.get(name) {
+        Some(slot) => Ok(slot.clone()),
+        None => Err(CompileError::new(ErrorKind::UnboundVariable, e.span, format!("Error - Unbound variable identifier {name}"))),
+    }
+}
@@ -0,0 +1,159 @@
+use super::types;
+use super::fold;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use types::CompileWarning;
+
+use im::OrdSet;
+
+// The call graph of a program: for the main expression and every function, the functions
+// it calls by name and the ones it uses as values, which may be called through the
+// closure. Lambdas belong to the function they are written in. Only the functions
+// reachable from main are compiled; the others are reported as unused.
+
+#[derive(Debug,Clone,Default)]
+pub struct Edges {
+    pub calls: OrdSet<String>,
+    pub values: OrdSet<String>,
+}
+
+#[derive(Debug,Clone)]
+pub struct CallGraph {
+    pub main: Edges,
+    // every function in the order it is defined
+    pub funcs: Vec<(String, Edges)>,
+}
+
+impl CallGraph {
+    pub fn new(p: &Program) -> CallGraph {
+        let edges = |e: &Expr| {
+            let mut edges = Edges::default();
+            collect(e, p, &mut edges);
+            edges
+        };
+        let funcs = p.defs.iter().map(|def| {
+            let Definition::Fun(name, _, body, _) = def;
+            (name.clone(), edges(body))
+        }).collect();
+        CallGraph { main: edges(&p.main), funcs }
+    }
+
+    pub fn edges(&self, name: &str) -> Option<&Edges> {
+        self.funcs.iter().find(|(f, _)| f == name).map(|(_, edges)| edges)
+    }
+
+    // the functions main can reach through calls and function values
+    pub fn reachable(&self) -> OrdSet<String> {
+        self.reachable_from(&self.main)
+    }
+
+    // the functions on a cycle, directly or mutually recursive
+    pub fn recursive(&self) -> OrdSet<String> {
+        self.funcs.iter().filter(|(name, edges)| self.reachable_from(edges).contains(name))
+            .map(|(name, _)| name.clone()).collect()
+    }
+
+    fn reachable_from(&self, edges: &Edges) -> OrdSet<String> {
+        let mut seen = OrdSet::new();
+        let mut work: Vec<&String> = edges.calls.iter().chain(&edges.values).collect();
+        while let Some(name) = work.pop() {
+            if seen.insert(name.clone()).is_none() {
+                if let Some(edges) = self.edges(name) {
+                    work.extend(edges.calls.iter().chain(&edges.values));
+                }
+            }
+        }
+        seen
+    }
+
+    // Graphviz, with main as a box, function values as dashed edges and unreachable
+    // functions in grey
+    pub fn to_dot(&self) -> String {
+        let reachable = self.reachable();
+        let mut out = String::from("digraph callgraph {\n  \"(main)\" [label=\"main\", shape=box];\n");
+        for (name, _) in &self.funcs {
+            let style = if reachable.contains(name) { "" } else { " [color=grey, fontcolor=grey]" };
+            out.push_str(&format!("  \"{}\"{style};\n", escape(name)));
+        }
+        let nodes = std::iter::once((String::from("(main)"), &self.main))
+            .chain(self.funcs.iter().map(|(name, edges)| (escape(name), edges)));
+        for (from, edges) in nodes {
+            for callee in &edges.calls {
+                out.push_str(&format!("  \"{from}\" -> \"{}\";\n", escape(callee)));
+            }
+            for value in &edges.values {
+                out.push_str(&format!("  \"{from}\" -> \"{}\" [style=dashed];\n", escape(value)));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// a variable with the name of a function counts as a use of it, which at worst keeps a
+// function that is not needed
+fn collect(e: &Expr, p: &Program, edges: &mut Edges) {
+    match &e.kind {
+        ExprKind::Id(name) if p.func_list.contains(name) => {
+            edges.values.insert(name.clone());
+        },
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => (),
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) | ExprKind::Set(_, e1) |
+        ExprKind::Lambda(_, e1) => collect(e1, p, edges),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => {
+            collect(e1, p, edges);
+            collect(e2, p, edges);
+        },
+        ExprKind::If(cond, thn, els) => {
+            collect(cond, p, edges);
+            collect(thn, p, edges);
+            collect(els, p, edges);
+        },
+        ExprKind::Let(binds, body) => {
+            binds.iter().for_each(|(_, val)| collect(val, p, edges));
+            collect(body, p, edges);
+        },
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es) =>
+            es.iter().for_each(|e| collect(e, p, edges)),
+        ExprKind::Call(name, args) => {
+            edges.calls.insert(name.clone());
+            args.iter().for_each(|e| collect(e, p, edges));
+        },
+        ExprKind::App(f, args) => {
+            collect(f, p, edges);
+            args.iter().for_each(|e| collect(e, p, edges));
+        },
+    }
+}
+
+// the program with only the functions reachable from main
+pub fn remove_unused(p: &Program) -> Program {
+    let reachable = CallGraph::new(p).reachable();
+    let defs = p.defs.iter().filter(|def| {
+        let Definition::Fun(name, _, _, _) = def;
+        reachable.contains(name)
+    }).cloned().collect();
+    let func_list = p.func_list.iter().filter(|name| reachable.contains(*name)).cloned().collect();
+    Program { defs, main: p.main.clone(), func_list }
+}
+
+// a warning for every function main cannot reach once constants are folded, which is
+// every function remove_unused drops except those only left unused by inlining
+pub fn unused_warnings(p: &Program) -> Vec<CompileWarning> {
+    let reachable = CallGraph::new(&fold::program(p)).reachable();
+    p.defs.iter().filter_map(|def| {
+        let Definition::Fun(name, _, _, span) = def;
+        (!reachable.contains(name)).then(|| CompileWarning {
+            message: format!("function {name} is never used."),
+            span: *span,
+        })
+    }).collect()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,274 @@
+use super::types;
+use super::compiler;
+
+use types::Instr;
+use types::Val;
+use types::Reg;
+
+use im::HashMap;
+
+// Control-flow graphs of the generated code, one per function and one for main. A basic
+// block is a run of straight-line instructions (calls included) that starts at a label or
+// after a jump and ends in an explicit terminator; falling into the next label becomes a
+// jump to its block. Jumps to labels outside the function, which are the error handlers
+// and tail calls to other functions, leave the graph, so successors and predecessors only
+// name blocks of the same function. `linearize` turns a graph back into instructions.
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Cond {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    // jo, which always goes to the overflow handler
+    Overflow,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Target {
+    Block(usize),
+    // a label outside the function
+    Label(String),
+    // the code address of a closure, for a tail call through it
+    Reg(Reg),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Terminator {
+    Jump(Target),
+    // goes to the target when the condition holds and on to the block otherwise
+    Branch(Cond, Target, usize),
+    Return,
+}
+
+#[derive(Debug,Clone)]
+pub struct Block {
+    // the label of the block in the generated code, if it had one
+    pub label: Option<String>,
+    pub instrs: Vec<Instr>,
+    pub term: Terminator,
+    pub succs: Vec<usize>,
+    pub preds: Vec<usize>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Function {
+    pub name: String,
+    // the entry is the first block
+    pub blocks: Vec<Block>,
+}
+
+impl Cond {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Cond::Equal => "je",
+            Cond::NotEqual => "jne",
+            Cond::Greater => "jg",
+            Cond::GreaterEqual => "jge",
+            Cond::Less => "jl",
+            Cond::LessEqual => "jle",
+            Cond::Overflow => "jo",
+        }
+    }
+}
+
+// a block as it is read, before its jump targets are resolved
+struct RawBlock {
+    label: Option<String>,
+    instrs: Vec<Instr>,
+    // the jump or ret that ends the block, or None when it falls into the next label
+    end: Option<Instr>,
+}
+
+impl Function {
+    // splits the instructions of one function, from its label to its last ret
+    pub fn new(name: &str, instrs: Vec<Instr>) -> Function {
+        let mut raw = Vec::new();
+        let mut current = RawBlock { label: None, instrs: Vec::new(), end: None };
+        for i in instrs {
+            match i {
+                Instr::Label(Val::Label(label)) => {
+                    if current.label.is_some() || !current.instrs.is_empty() {
+                        raw.push(current);
+                    }
+                    current = RawBlock { label: Some(label), instrs: Vec::new(), end: None };
+                },
+                Instr::Jmp(_) | Instr::JEqual(_) | Instr::JNotEqual(_) | Instr::JGreater(_) |
+                Instr::JGreaterEqual(_) | Instr::JLess(_) | Instr::JLessEqual(_) | Instr::OverFlow() | Instr::Ret() => {
+                    current.end = Some(i);
+                    raw.push(current);
+                    current = RawBlock { label: None, instrs: Vec::new(), end: None };
+                },
+                _ => current.instrs.push(i),
+            }
+        }
+        if current.label.is_some() || !current.instrs.is_empty() {
+            panic!("Error - function {name} runs past its last instruction.");
+        }
+
+        let labels: HashMap<String, usize> = raw.iter().enumerate()
+            .filter_map(|(i, b)| b.label.clone().map(|label| (label, i)))
+            .collect();
+        let target = |v: Val| match v {
+            Val::Label(label) => match labels.get(&label) {
+                Some(b) => Target::Block(*b),
+                None => Target::Label(label),
+            },
+            Val::Reg(reg) => Target::Reg(reg),
+            v => panic!("Error - cannot jump to {v:?}."),
+        };
+
+        let mut blocks = Vec::new();
+        for (i, b) in raw.into_iter().enumerate() {
+            let term = match b.end {
+                None => Terminator::Jump(Target::Block(i + 1)),
+                Some(Instr::Jmp(v)) => Terminator::Jump(target(v)),
+                Some(Instr::JEqual(v)) => Terminator::Branch(Cond::Equal, target(v), i + 1),
+                Some(Instr::JNotEqual(v)) => Terminator::Branch(Cond::NotEqual, target(v), i + 1),
+                Some(Instr::JGreater(v)) => Terminator::Branch(Cond::Greater, target(v), i + 1),
+                Some(Instr::JGreaterEqual(v)) => Terminator::Branch(Cond::GreaterEqual, target(v), i + 1),
+                Some(Instr::JLess(v)) => Terminator::Branch(Cond::Less, target(v), i + 1),
+                Some(Instr::JLessEqual(v)) => Terminator::Branch(Cond::LessEqual, target(v), i + 1),
+                Some(Instr::OverFlow()) => Terminator::Branch(Cond::Overflow, Target::Label(String::from("overflow")), i + 1),
+                Some(_) => Terminator::Return,
+            };
+            blocks.push(Block { label: b.label, instrs: b.instrs, term, succs: Vec::new(), preds: Vec::new() });
+        }
+
+        let mut f = Function { name: String::from(name), blocks };
+        f.link();
+        f
+    }
+
+    // recomputes the successor and predecessor lists from the terminators
+    pub fn link(&mut self) {
+        for b in &mut self.blocks {
+            b.succs = match &b.term {
+                Terminator::Jump(Target::Block(next)) => vec![*next],
+                Terminator::Branch(_, Target::Block(taken), next) if taken != next => vec![*next, *taken],
+                Terminator::Branch(_, _, next) => vec![*next],
+                Terminator::Jump(_) | Terminator::Return => vec![],
+            };
+            b.preds.clear();
+        }
+        for i in 0..self.blocks.len() {
+            for s in self.blocks[i].succs.clone() {
+                self.blocks[s].preds.push(i);
+            }
+        }
+    }
+
+    // the label of block i, making one up for blocks that had none
+    pub fn block_label(&self, i: usize) -> String {
+        match &self.blocks[i].label {
+            Some(label) => label.clone(),
+            None => format!("{}_bb{i}", self.name),
+        }
+    }
+
+    fn target_val(&self, t: &Target) -> Val {
+        match t {
+            Target::Block(b) => Val::Label(self.block_label(*b)),
+            Target::Label(label) => Val::Label(label.clone()),
+            Target::Reg(reg) => Val::Reg(*reg),
+        }
+    }
+
+    // the instructions of the terminator of block i, leaving out a jump to the block after it
+    fn term_instrs(&self, i: usize) -> Vec<Instr> {
+        let mut out = Vec::new();
+        match &self.blocks[i].term {
+            Terminator::Jump(Target::Block(b)) if *b == i + 1 => (),
+            Terminator::Jump(t) => out.push(Instr::Jmp(self.target_val(t))),
+            Terminator::Branch(cond, t, next) => {
+                let v = self.target_val(t);
+                out.push(match cond {
+                    Cond::Equal => Instr::JEqual(v),
+                    Cond::NotEqual => Instr::JNotEqual(v),
+                    Cond::Greater => Instr::JGreater(v),
+                    Cond::GreaterEqual => Instr::JGreaterEqual(v),
+                    Cond::Less => Instr::JLess(v),
+                    Cond::LessEqual => Instr::JLessEqual(v),
+                    Cond::Overflow => Instr::OverFlow(),
+                });
+                if *next != i + 1 {
+                    out.push(Instr::Jmp(Val::Label(self.block_label(*next))));
+                }
+            },
+            Terminator::Return => out.push(Instr::Ret()),
+        }
+        out
+    }
+
+    // the blocks in order, with a label wherever a block had one or is jumped to
+    pub fn linearize(&self) -> Vec<Instr> {
+        let mut jumped_to = vec![false; self.blocks.len()];
+        for (i, b) in self.blocks.iter().enumerate() {
+            match b.term {
+                Terminator::Jump(Target::Block(t)) if t != i + 1 => jumped_to[t] = true,
+                Terminator::Branch(_, ref t, next) => {
+                    if let Target::Block(t) = t {
+                        jumped_to[*t] = true;
+                    }
+                    if next != i + 1 {
+                        jumped_to[next] = true;
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let mut out = Vec::new();
+        for (i, b) in self.blocks.iter().enumerate() {
+            if b.label.is_some() || jumped_to[i] {
+                out.push(Instr::Label(Val::Label(self.block_label(i))));
+            }
+            out.extend(b.instrs.iter().cloned());
+            out.extend(self.term_instrs(i));
+        }
+        out
+    }
+}
+
+// Graphviz for the graphs, one cluster per function; the edge a branch takes when its
+// condition holds is labelled with the jump
+pub fn to_dot(fs: &[Function]) -> String {
+    let mut out = String::from("digraph cfg {\n  node [shape=box, fontname=\"monospace\"];\n");
+    for f in fs {
+        out.push_str(&format!("  subgraph \"cluster_{}\" {{\n    label=\"{}\";\n", escape(&f.name), escape(&f.name)));
+        for (i, b) in f.blocks.iter().enumerate() {
+            let mut text = format!("{}:\\l", escape(&f.block_label(i)));
+            let instrs = b.instrs.iter().cloned().chain(f.term_instrs(i));
+            for instr in instrs {
+                let line = compiler::instrs_to_str(&[instr], compiler::Syntax::Nasm);
+                text.push_str(&format!("  {}\\l", escape(line.trim())));
+            }
+            out.push_str(&format!("    \"{}\" [label=\"{text}\"];\n", node(f, i)));
+        }
+        out.push_str("  }\n");
+        for (i, b) in f.blocks.iter().enumerate() {
+            match &b.term {
+                Terminator::Jump(Target::Block(t)) => out.push_str(&format!("  \"{}\" -> \"{}\";\n", node(f, i), node(f, *t))),
+                Terminator::Branch(cond, t, next) => {
+                    if let Target::Block(t) = t {
+                        out.push_str(&format!("  \"{}\" -> \"{}\" [label=\"{}\"];\n", node(f, i), node(f, *t), cond.mnemonic()));
+                    }
+                    out.push_str(&format!("  \"{}\" -> \"{}\";\n", node(f, i), node(f, *next)));
+                },
+                Terminator::Jump(_) | Terminator::Return => (),
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn node(f: &Function, i: usize) -> String {
+    format!("{}.{i}", escape(&f.name))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,145 @@
+use super::types;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use types::CompileError;
+use types::ErrorKind;
+use types::Span;
+
+use im::{HashMap,HashSet};
+
+// Well-formedness checks that run between parsing and compilation. Unlike the parser,
+// which stops at the first malformed form, this pass keeps going and collects every
+// scoping, arity and break error in the program.
+
+struct Checker {
+    arities: HashMap<String, usize>,
+    errors: Vec<CompileError>,
+}
+
+// returns every error found, sorted by location
+pub fn check_program(p: &Program) -> Vec<CompileError> {
+    let mut checker = Checker { arities: HashMap::new(), errors: Vec::new() };
+
+    for def in &p.defs {
+        let Definition::Fun(name, args, _, span) = def;
+        if checker.arities.contains_key(name) {
+            checker.error(ErrorKind::DuplicateFunction, *span, format!("Error - function {name} is declared multiple times."));
+        } else {
+            checker.arities.insert(name.clone(), args.len());
+        }
+    }
+
+    for def in &p.defs {
+        let Definition::Fun(_, args, body, span) = def;
+        let bound = checker.bind_params(args, &HashSet::new(), *span);
+        checker.check_expr(body, &bound, false);
+    }
+
+    // input is only in scope in the main expression (and lambdas defined there)
+    checker.check_expr(&p.main, &HashSet::unit(String::from("input")), false);
+
+    checker.errors.sort_by_key(|err| err.span);
+    checker.errors
+}
+
+impl Checker {
+    fn error(&mut self, kind: ErrorKind, span: Span, message: String) {
+        self.errors.push(CompileError::new(kind, span, message));
+    }
+
+    // adds function or lambda parameters to the scope, reporting any that repeat
+    fn bind_params(&mut self, params: &[String], bound: &HashSet<String>, span: Span) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        for param in params {
+            if seen.contains(param) {
+                self.error(ErrorKind::DuplicateBinding, span, format!("Error - parameter {param} is declared twice."));
+            }
+            seen.insert(param.clone());
+        }
+        seen.union(bound.clone())
+    }
+
+    // `in_loop` is false at the start of every function and lambda body, since a break
+    // cannot jump out of a call
+    fn check_expr(&mut self, e: &Expr, bound: &HashSet<String>, in_loop: bool) {
+        match &e.kind {
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::NoMatch
+            | ExprKind::ClosureVar(_) | ExprKind::MakeClosure(..) => (),
+            ExprKind::Id(name) => {
+                // top-level functions can be used as values
+                if !bound.contains(name) && !self.arities.contains_key(name) {
+                    self.error(ErrorKind::UnboundVariable, e.span, format!("Error - Unbound variable identifier {name}"));
+                }
+            },
+            ExprKind::Let(binds, body) => {
+                let mut nbound = bound.clone();
+                let mut seen = HashSet::new();
+                for (name, val) in binds {
+                    if seen.contains(name) {
+                        self.error(ErrorKind::DuplicateBinding, e.span, format!("Error - Duplicate binding of {name}."));
+                    }
+                    seen.insert(name.clone());
+                    self.check_expr(val, &nbound, in_loop);
+                    nbound.insert(name.clone());
+                }
+                self.check_expr(body, &nbound, in_loop);
+            },
+            ExprKind::UnOp(_, e1) => self.check_expr(e1, bound, in_loop),
+            ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => {
+                self.check_expr(e1, bound, in_loop);
+                self.check_expr(e2, bound, in_loop);
+            },
+            ExprKind::If(cond, thn, els) => {
+                self.check_expr(cond, bound, in_loop);
+                self.check_expr(thn, bound, in_loop);
+                self.check_expr(els, bound, in_loop);
+            },
+            ExprKind::Loop(e1) => self.check_expr(e1, bound, true),
+            ExprKind::Break(e1) => {
+                if !in_loop {
+                    self.error(ErrorKind::BreakOutsideLoop, e.span, String::from("Error - break must be within a loop."));
+                }
+                self.check_expr(e1, bound, in_loop);
+            },
+            ExprKind::Set(name, e1) => {
+                if !bound.contains(name) {
+                    self.error(ErrorKind::UnboundVariable, e.span, format!("Error - Unbound variable identifier {name}"));
+                }
+                self.check_expr(e1, bound, in_loop);
+            },
+            ExprKind::Block(es) | ExprKind::Tuple(es) | ExprKind::And(es) | ExprKind::Or(es) => {
+                for item in es {
+                    self.check_expr(item, bound, in_loop);
+                }
+            },
+            ExprKind::Call(name, args) => {
+                match self.arities.get(name) {
+                    Some(arity) if *arity != args.len() => self.error(ErrorKind::Arity, e.span,
+                        format!("Error - {name} expects {arity} arguments but is called with {}.", args.len())),
+                    Some(_) => (),
+                    None => self.error(ErrorKind::UnboundFunction, e.span, format!("Error - unknown function {name}.")),
+                }
+                for item in args {
+                    self.check_expr(item, bound, in_loop);
+                }
+            },
+            ExprKind::App(f, args) => {
+                match &f.kind {
+                    ExprKind::Id(name) if !bound.contains(name) && !self.arities.contains_key(name) =>
+                        self.error(ErrorKind::UnboundFunction, f.span, format!("Error - unknown function {name}.")),
+                    _ => self.check_expr(f, bound, in_loop),
+                }
+                for item in args {
+                    self.check_expr(item, bound, in_loop);
+                }
+            },
+            ExprKind::Lambda(params, body) => {
+                let nbound = self.bind_params(params, bound, e.span);
+                self.check_expr(body, &nbound, false);
+            },
+        }
+    }
+}
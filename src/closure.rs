@@ -0,0 +1,238 @@
+use super::types;
+
+use types::Expr;
+use types::ExprKind;
+use types::Span;
+use types::Program;
+use types::Definition;
+
+use im::{HashMap,HashSet};
+
+// Closure conversion: every lambda becomes a top-level definition whose hidden first
+// parameter is the closure record itself, and the lambda expression is replaced by a
+// MakeClosure that captures the free variables of its body by value. Top-level functions
+// used as values are wrapped in a definition with the same calling convention.
+
+// name of the hidden closure parameter; it is a reserved word so it can never clash with a user parameter
+const SELF_PARAM: &str = "lambda";
+
+struct Converter<'a> {
+    arities: &'a HashMap<String, usize>,
+    lifted: Vec<Definition>,
+    // the wrapper of every function used as a value
+    wrapped: HashMap<String, String>,
+    // the user's function names and the labels made up so far, which the labels of
+    // lifted definitions must not clash with
+    taken: HashSet<String>,
+    labels: i32,
+}
+
+pub fn convert(p: &Program) -> Program {
+    let mut arities = HashMap::new();
+    for def in &p.defs {
+        let Definition::Fun(name, args, _, _) = def;
+        arities.insert(name.clone(), args.len());
+    }
+
+    let taken = arities.keys().cloned().collect();
+    let mut conv = Converter { arities: &arities, lifted: Vec::new(), wrapped: HashMap::new(), taken, labels: 0 };
+
+    let mut defs = Vec::new();
+    for def in &p.defs {
+        let Definition::Fun(name, args, body, span) = def;
+        let bound = args.iter().cloned().collect();
+        defs.push(Definition::Fun(name.clone(), args.clone(), conv.convert_expr(body, &bound), *span));
+    }
+
+    let main = conv.convert_expr(&p.main, &HashSet::unit(String::from("input")));
+
+    let mut func_list = p.func_list.clone();
+    for def in &conv.lifted {
+        let Definition::Fun(name, _, _, _) = def;
+        func_list.insert(name.clone());
+    }
+    defs.extend(conv.lifted);
+
+    Program { defs, main, func_list }
+}
+
+impl Converter<'_> {
+    // `base` unless a function already has that name, otherwise base_1, base_2 and so on
+    fn fresh(&mut self, base: &str) -> String {
+        let mut label = String::from(base);
+        let mut n = 0;
+        while self.taken.contains(&label) {
+            n += 1;
+            label = format!("{base}_{n}");
+        }
+        self.taken.insert(label.clone());
+        label
+    }
+
+    // `bound` holds the local variables in scope
+    fn convert_expr(&mut self, e: &Expr, bound: &HashSet<String>) -> Expr {
+        let kind = match &e.kind {
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::ClosureVar(_) | ExprKind::MakeClosure(..) | ExprKind::NoMatch => e.kind.clone(),
+            ExprKind::Id(name) => {
+                if !bound.contains(name) && self.arities.contains_key(name) {
+                    let arity = self.arities[name];
+                    ExprKind::MakeClosure(self.wrapper(name, e.span), arity, Vec::new())
+                } else {
+                    e.kind.clone()
+                }
+            },
+            ExprKind::Let(binds, body) => {
+                let mut nbound = bound.clone();
+                let mut nbinds = Vec::new();
+                for (name, val) in binds {
+                    nbinds.push((name.clone(), self.convert_expr(val, &nbound)));
+                    nbound.insert(name.clone());
+                }
+                ExprKind::Let(nbinds, Box::new(self.convert_expr(body, &nbound)))
+            },
+            ExprKind::UnOp(op, e1) => ExprKind::UnOp(op.clone(), Box::new(self.convert_expr(e1, bound))),
+            ExprKind::BinOp(op, e1, e2) =>
+                ExprKind::BinOp(op.clone(), Box::new(self.convert_expr(e1, bound)), Box::new(self.convert_expr(e2, bound))),
+            ExprKind::If(cond, thn, els) =>
+                ExprKind::If(Box::new(self.convert_expr(cond, bound)), Box::new(self.convert_expr(thn, bound)), Box::new(self.convert_expr(els, bound))),
+            ExprKind::Loop(e1) => ExprKind::Loop(Box::new(self.convert_expr(e1, bound))),
+            ExprKind::Break(e1) => ExprKind::Break(Box::new(self.convert_expr(e1, bound))),
+            ExprKind::Set(name, e1) => ExprKind::Set(name.clone(), Box::new(self.convert_expr(e1, bound))),
+            ExprKind::Block(es) => ExprKind::Block(es.iter().map(|item| self.convert_expr(item, bound)).collect()),
+            ExprKind::And(es) => ExprKind::And(es.iter().map(|item| self.convert_expr(item, bound)).collect()),
+            ExprKind::Or(es) => ExprKind::Or(es.iter().map(|item| self.convert_expr(item, bound)).collect()),
+            ExprKind::Tuple(es) => ExprKind::Tuple(es.iter().map(|item| self.convert_expr(item, bound)).collect()),
+            ExprKind::Index(e1, e2) =>
+                ExprKind::Index(Box::new(self.convert_expr(e1, bound)), Box::new(self.convert_expr(e2, bound))),
+            // in call position a top-level function name always refers to the function,
+            // even when a local variable of the same name is in scope
+            ExprKind::Call(name, args) =>
+                ExprKind::Call(name.clone(), args.iter().map(|item| self.convert_expr(item, bound)).collect()),
+            ExprKind::App(f, args) => ExprKind::App(
+                Box::new(self.convert_expr(f, bound)),
+                args.iter().map(|item| self.convert_expr(item, bound)).collect(),
+            ),
+            ExprKind::Lambda(params, body) => {
+                let label = self.fresh(&format!("lambda_{}", self.labels));
+                self.labels += 1;
+
+                // capture the variables of the enclosing scope that the body refers to
+                let mut captured: Vec<String> = Vec::new();
+                for name in free_vars(body, &params.iter().cloned().collect()) {
+                    if bound.contains(&name) && !captured.contains(&name) {
+                        captured.push(name);
+                    }
+                }
+                captured.sort();
+
+                let mut inner_bound: HashSet<String> = params.iter().cloned().collect();
+                inner_bound.extend(captured.iter().cloned());
+                let mut new_body = self.convert_expr(body, &inner_bound);
+
+                // unpack the captured variables at the start of the body
+                if !captured.is_empty() {
+                    let binds = captured.iter().enumerate()
+                        .map(|(i, name)| (name.clone(), Expr::new(ExprKind::ClosureVar(i), body.span)))
+                        .collect();
+                    new_body = Expr::new(ExprKind::Let(binds, Box::new(new_body)), body.span);
+                }
+
+                let mut args = vec![String::from(SELF_PARAM)];
+                args.extend(params.iter().cloned());
+                self.lifted.push(Definition::Fun(label.clone(), args, new_body, e.span));
+
+                ExprKind::MakeClosure(label, params.len(), captured)
+            },
+        };
+        Expr::new(kind, e.span)
+    }
+
+    // definition that lets a top-level function be called through a closure; its nodes
+    // take the span of the first use of the function as a value
+    fn wrapper(&mut self, name: &String, span: Span) -> String {
+        if let Some(label) = self.wrapped.get(name) {
+            return label.clone();
+        }
+        let label = self.fresh(&format!("{name}_closure"));
+        self.wrapped.insert(name.clone(), label.clone());
+        let params: Vec<String> = (0..self.arities[name]).map(|i| format!("x{i}")).collect();
+        let call = Expr::new(
+            ExprKind::Call(name.clone(), params.iter().map(|p| Expr::new(ExprKind::Id(p.clone()), span)).collect()),
+            span,
+        );
+        let mut args = vec![String::from(SELF_PARAM)];
+        args.extend(params);
+        self.lifted.push(Definition::Fun(label.clone(), args, call, span));
+        label
+    }
+}
+
+// variables referenced in e that are not bound within it
+pub fn free_vars(e: &Expr, bound: &HashSet<String>) -> Vec<String> {
+    let mut out = Vec::new();
+    collect_free_vars(e, bound, &mut out);
+    out
+}
+
+fn collect_free_vars(e: &Expr, bound: &HashSet<String>, out: &mut Vec<String>) {
+    match &e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::ClosureVar(_) | ExprKind::NoMatch => (),
+        ExprKind::Id(name) => {
+            if !bound.contains(name) {
+                out.push(name.clone());
+            }
+        },
+        ExprKind::Let(binds, body) => {
+            let mut nbound = bound.clone();
+            for (name, val) in binds {
+                collect_free_vars(val, &nbound, out);
+                nbound.insert(name.clone());
+            }
+            collect_free_vars(body, &nbound, out);
+        },
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) => collect_free_vars(e1, bound, out),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => {
+            collect_free_vars(e1, bound, out);
+            collect_free_vars(e2, bound, out);
+        },
+        ExprKind::If(cond, thn, els) => {
+            collect_free_vars(cond, bound, out);
+            collect_free_vars(thn, bound, out);
+            collect_free_vars(els, bound, out);
+        },
+        ExprKind::Set(name, e1) => {
+            if !bound.contains(name) {
+                out.push(name.clone());
+            }
+            collect_free_vars(e1, bound, out);
+        },
+        ExprKind::Block(es) | ExprKind::Tuple(es) | ExprKind::And(es) | ExprKind::Or(es) => {
+            for item in es {
+                collect_free_vars(item, bound, out);
+            }
+        },
+        ExprKind::Call(_, args) => {
+            for item in args {
+                collect_free_vars(item, bound, out);
+            }
+        },
+        ExprKind::App(f, args) => {
+            collect_free_vars(f, bound, out);
+            for item in args {
+                collect_free_vars(item, bound, out);
+            }
+        },
+        ExprKind::Lambda(params, body) => {
+            let mut nbound = bound.clone();
+            nbound.extend(params.iter().cloned());
+            collect_free_vars(body, &nbound, out);
+        },
+        ExprKind::MakeClosure(_, _, captured) => {
+            for name in captured {
+                if !bound.contains(name) {
+                    out.push(name.clone());
+                }
+            }
+        },
+    }
+}
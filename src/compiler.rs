@@ -1,359 +1,434 @@
 use super::types;
+use super::anf;
+use super::cfg;
+use super::regalloc;
+use super::peephole;
 
-use types::Expr;
+use anf::{AExpr,CExpr,Imm};
+use regalloc::Live;
 use types::Instr;
 use types::Val;
 use types::Op1;
 use types::Op2;
 use types::Reg;
 use types::Program;
-use types::Definition;
+use types::CompileError;
 
-use im::{HashMap,HashSet};
+use im::HashMap;
 
+// assemblers the text output can be written for: nasm, or GNU as in Intel syntax
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Syntax {
+    Nasm,
+    Gas,
+}
+
+// Arguments live at [rbp + 16 + 8*i] and everything else in the frame at [rbp - 8*n]: the
+// number of slots in the frame, the input of main, a slot for each register the function
+// uses, spilled variables and the slots a tail call copies its arguments through. Every
+// other slot is zeroed on entry so the garbage collector can treat each one as a snek value.
+struct Frame {
+    // the register or slot of every variable
+    env: HashMap<String,Val>,
+    // the registers of the function's variables and the slots they are saved to
+    saves: Vec<(Reg,i64)>,
+    // the first slot after those
+    si: i64,
+}
 
-fn compile_to_instrs(e: &Expr, mut si: i64, env: &HashMap<String,i64>, l: &mut i32, brake: &String, func_names: &HashSet<String>, tail:bool) -> Vec<Instr> {
+// `out` holds the variables live after e and `brk` the ones live after the innermost loop;
+// `tail` holds the arity of the enclosing function when e is in tail position.
+fn compile_aexpr(e: &AExpr, f: &Frame, l: &mut i32, brake: &str, out: &Live, brk: &Live, tail: Option<usize>) -> Vec<Instr> {
     let mut instr = Vec::new();
-    match e {
-        Expr::Number(n) => {
-            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(*n)));
-            instr.push(Instr::Shl(Val::Reg(Reg::RAX),Val::Imm(1)));
-        },
-        Expr::Boolean(val) => {
-            if *val { 
-                instr.push(Instr::IMov(Val::Reg(Reg::RAX),Val::Imm(types::TRUE_VAL)));
-            } else {
-                instr.push(Instr::IMov(Val::Reg(Reg::RAX),Val::Imm(types::FALSE_VAL)));
-            }
+    let steps = regalloc::steps(e);
+    let last = steps.len() - 1;
+    let after = regalloc::live_after_steps(&steps, out, brk);
+    for (i, ((name, c), live)) in steps.iter().zip(&after).enumerate() {
+        instr.extend(compile_cexpr(c, f, l, brake, live, brk, if i == last { tail } else { None }));
+        if let Some(name) = name {
+            instr.push(Instr::IMov(f.env[*name].clone(), Val::Reg(Reg::RAX)));
         }
+    }
+    instr
+}
 
-        // Block //
-        Expr::Block(es) => {
-            let es_len = es.len();
-            let mut es_copy = es.clone();
-            let last_item = es[es_len-1].clone();
-            es_copy.pop();
-            for item in es_copy {
-                instr.extend(compile_to_instrs(&item, si, env, l, brake, func_names, false));
-            }
-
-            instr.extend(compile_to_instrs(&last_item, si, env, l, brake, func_names, tail));
-        },
-
-        // Loop //
-        Expr::Loop(e) => {
-            // create labels
-            let startloop = new_label(l, "loop");
-            let endloop = new_label(l, "loopend");
-
-            let e_is = compile_to_instrs(e, si, env, l, &endloop, func_names,false);
-            instr.push(Instr::Label(Val::Label(startloop.clone())));
-            instr.extend(e_is);
-            instr.push(Instr::Jmp(Val::Label(startloop.clone())));
-            instr.push(Instr::Label(Val::Label(endloop.clone())));
+// moves the value of an immediate into a register
+fn load(reg: Reg, imm: &Imm, env: &HashMap<String,Val>) -> Instr {
+    let val = match imm {
+        Imm::Num(n) => Val::Imm((*n as u64) << 1),
+        Imm::Bool(true) => Val::Imm(types::TRUE_VAL),
+        Imm::Bool(false) => Val::Imm(types::FALSE_VAL),
+        Imm::Var(name) => match env.get(name) {
+            Some(loc) => loc.clone(),
+            None => panic!("Error - {name} is unbound, ANF conversion checks every variable."),
         },
+    };
+    Instr::IMov(Val::Reg(reg), val)
+}
 
-        // Break // 
-        Expr::Break(e) => {
-            let e_is = compile_to_instrs(e, si, env, l, brake, func_names,false);
-            instr.extend(e_is);
-            
-            if brake == ""{
-                panic!("Error - break must be within a loop.")
-            }
-            instr.push(Instr::Jmp(Val::Label(brake.clone())));
+// saves the registers holding a variable of `live` to their slots, only the ones the C
+// functions of the runtime may change unless `all` is set; returns the instructions that
+// load them back
+fn save_live(instr: &mut Vec<Instr>, f: &Frame, live: &Live, all: bool) -> Vec<Instr> {
+    let mut restore = Vec::new();
+    for (reg, slot) in &f.saves {
+        let holds_live = live.iter().any(|name| f.env.get(name) == Some(&Val::Reg(*reg)));
+        if holds_live && (all || regalloc::CALLER_SAVED.contains(reg)) {
+            instr.push(Instr::IMov(Val::RegOffset(Reg::RBP, slot * 8), Val::Reg(*reg)));
+            restore.push(Instr::IMov(Val::Reg(*reg), Val::RegOffset(Reg::RBP, slot * 8)));
         }
-        
-        // Set // 
-        Expr::Set(name, val) => {
-            let res = env.get(name);
-            let offset = match res {
-                Some(x) => x,
-                None => panic!("Unbound variable identifier {name}"),
-            };
-            instr.extend(compile_to_instrs(val, si, env, l, brake, func_names,false));
-            instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, *offset), Val::Reg(Reg::RAX)));
-        }
-
-        // If expression //
-        Expr::If(cond, e2, e3) => {
-
-            // evaluate expression of conditional and type check
-            instr.extend(compile_to_instrs(cond, si, env, l, brake, func_names,false));
-
-            // create labels
-            let cond_label = new_label(l, "if");
-            let end_label  = new_label(l, "endif");
-
-            // if conditional false, jump to false branch, otherwise it must be true (number or true bool)
-            instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(types::FALSE_VAL)));
-            instr.push(Instr::JEqual(Val::Label(cond_label.clone())));
-
-            // else branch
-            instr.extend(compile_to_instrs(e2, si, env, l, brake, func_names,tail));
-            instr.push(Instr::Jmp(Val::Label(end_label.clone())));
-
-            // true branch
-            instr.push(Instr::Label(Val::Label(cond_label.clone())));
-            instr.extend(compile_to_instrs(e3, si+1, env, l, brake, func_names,tail));
-            instr.push(Instr::Label(Val::Label(end_label.clone())));
+    }
+    restore
+}
 
-        },
+// leaves the value of c in rax
+fn compile_cexpr(c: &CExpr, f: &Frame, l: &mut i32, brake: &str, out: &Live, brk: &Live, tail: Option<usize>) -> Vec<Instr> {
+    let env = &f.env;
+    let mut instr = Vec::new();
+    match c {
+        CExpr::Imm(imm) => instr.push(load(Reg::RAX, imm, env)),
 
-        // Uniary Operations //
-        Expr::UnOp(op1, subexpr) => {
+        // Unary Operations //
+        CExpr::Prim1(op1, imm) => {
+            instr.push(load(Reg::RAX, imm, env));
             match op1 {
                 Op1::Add1 => {
-                    update_vec_unop(&mut instr, compile_to_instrs(subexpr,si,env, l, brake, func_names,false),
-                    Instr::IAdd(Val::Reg(Reg::RAX), 
-                    Val::Imm(1 << 1)));
-                    instr.push(Instr::OverFlow())
+                    type_number_check(&mut instr);
+                    instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1 << 1)));
+                    instr.push(Instr::OverFlow());
                 },
                 Op1::Sub1 => {
-                    update_vec_unop(&mut instr, compile_to_instrs(subexpr,si,env, l, brake, func_names,false), 
-                    Instr::ISub(Val::Reg(Reg::RAX), 
-                    Val::Imm(1 << 1)));
-                    instr.push(Instr::OverFlow())
+                    type_number_check(&mut instr);
+                    instr.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(1 << 1)));
+                    instr.push(Instr::OverFlow());
+                },
+                Op1::IsBool => check_bool_type_instr(&mut instr, l),
+                Op1::IsNum => check_num_type_instr(&mut instr, l),
+                Op1::Not => {
+                    type_bool_check(&mut instr);
+
+                    // true (0b11) and false (0b01) differ only in bit 1
+                    instr.push(Instr::Xor(Val::Reg(Reg::RAX), Val::Imm(types::TRUE_VAL ^ types::FALSE_VAL)));
                 },
-                Op1::IsBool => {
-                    instr.extend(compile_to_instrs(subexpr, si, env, l, brake, func_names,false));
-                    check_bool_type_instr(&mut instr, l);
-                }
-                Op1::IsNum => {
-                    instr.extend(compile_to_instrs(subexpr,si,env,l, brake, func_names,false));
-                    check_num_type_instr(&mut instr, l);
-                }
                 Op1::Print => {
-                    let e_is = compile_to_instrs(subexpr, si, env, l, brake, func_names,false);
-                    let index:i64 = if si % 2 != 0 { si + 1 } else { si };
-                    let offset = (index * 8) as u64;
-                    instr.extend(e_is);
-                    instr.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(offset)));
-                    instr.push(Instr::Push(Val::Reg(Reg::RDI)));
-                    instr.push(Instr::IMov(Val::Reg(Reg::RDI),Val::Reg(Reg::RAX)));
+                    // the frame keeps rsp 16-byte aligned, so we can call directly
+                    let restore = save_live(&mut instr, f, out, false);
+                    instr.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Reg(Reg::RAX)));
                     instr.push(Instr::Call(Val::Label(String::from("snek_print"))));
-                    instr.push(Instr::Pop(Val::Reg(Reg::RDI)));
-                    instr.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(offset)));
-                }
-          }
-        },
-        
-        // Binary Operations //
-        Expr::BinOp(op2,subexpr1, subexpr2) => {
-            match op2 {
-                Op2::Plus => {
-                    update_vec_binop(
-                        &mut instr, compile_to_instrs(subexpr1,si,env, l, brake, func_names,false), 
-                        compile_to_instrs(subexpr2,si+1,env, l, brake, func_names,false), 
-                        Instr::IAdd(Val::Reg(Reg::RAX),Val::RegOffset(Reg::RSP, si*8)),
-                        si,
-                    );
-                    instr.push(Instr::OverFlow())
-                },
-                Op2::Minus => {
-                    update_vec_binop(
-                        &mut instr, compile_to_instrs(subexpr2,si,env,l, brake, func_names,false), 
-                        compile_to_instrs(subexpr1,si+1,env,l, brake, func_names,false), 
-                        Instr::ISub(Val::Reg(Reg::RAX),Val::RegOffset(Reg::RSP, si*8)),
-                        si,
-                    );
-                    instr.push(Instr::OverFlow())
+                    instr.extend(restore);
                 },
-                Op2::Times => {
-                    let ops = compile_to_instrs(subexpr2,si+1,env,l, brake, func_names,false);
-                    
-                    update_vec_binop(
-                        &mut instr, compile_to_instrs(subexpr1,si,env,l, brake, func_names,false), 
-                        ops, 
-                        Instr::Shr(Val::Reg(Reg::RAX), Val::Imm(1)),
-                        si,
-                    );
-                    instr.push(Instr::IMul(Val::Reg(Reg::RAX),Val::RegOffset(Reg::RSP, si*8)));
-                    instr.push(Instr::OverFlow())
-                },
-                Op2::Equal => {
-                        let op1 = compile_to_instrs(subexpr2,si,env,l, brake, func_names,false);
-                        let op2 = compile_to_instrs(subexpr1,si+1,env,l, brake, func_names,false);
-
-                        let offset = si*8;
-
-                        instr.extend(op1);
-                        instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, offset), Val::Reg(Reg::RAX)));
-                        instr.extend(op2);
-
-                        // check that both expressions are of same type
-                        same_type_expr(&mut instr, offset);
-
-                        // compare values for equivalence
-                        instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, offset)));
-                        instr.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Imm(3)));
-                        instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(1)));
-                        instr.push(Instr::Cmove(Val::Reg(Reg::RAX),Val::Reg(Reg::RDX)));
-                    },
-                Op2::Greater => {
-                    compare_size(&mut instr, 
-                        compile_to_instrs(&subexpr1, si, env, l, brake, func_names,false), 
-                        compile_to_instrs(&subexpr2, si+1, env, l, brake, func_names,false), 
-                        si);
-
-                    // create labels
-                    let cond_label = new_label(l, "if");
-                    let end_label  = new_label(l, "endif");
-
-                    // jump to if condition if greater than
-                    instr.push(Instr::JGreater(Val::Label(cond_label.clone())));
-                    
-                    conditional_jmp_compare(&mut instr, end_label, cond_label);
-
-                },
-                Op2::GreaterEqual => {
-                    compare_size(&mut instr, 
-                        compile_to_instrs(&subexpr1, si, env, l, brake, func_names,false), 
-                        compile_to_instrs(&subexpr2, si+1, env, l, brake, func_names,false), 
-                        si);
+            }
+        },
 
-                    // create labels
-                    let cond_label = new_label(l, "if");
-                    let end_label  = new_label(l, "endif");
+        // Binary Operations //
+        CExpr::Prim2(op2, a, b) => compile_prim2(&mut instr, op2, a, b, env, l),
 
-                    // jump to if condition if greater than or equal
-                    instr.push(Instr::JGreaterEqual(Val::Label(cond_label.clone())));
-                    
-                    conditional_jmp_compare(&mut instr, end_label, cond_label);
+        CExpr::CheckNum(imm) => {
+            instr.push(load(Reg::RAX, imm, env));
+            type_number_check(&mut instr);
+        },
+        CExpr::CheckBool(imm) => {
+            instr.push(load(Reg::RAX, imm, env));
+            type_bool_check(&mut instr);
+        },
 
-                },
-                Op2::Less => {
-                    compare_size(&mut instr, 
-                        compile_to_instrs(&subexpr1, si, env, l, brake, func_names,false), 
-                        compile_to_instrs(&subexpr2, si+1, env, l, brake, func_names,false), 
-                        si);
+        // If expression //
+        CExpr::If(cond, thn, els) => {
+            let else_label = new_label(l, "if");
+            let end_label = new_label(l, "endif");
 
-                    // create labels
-                    let cond_label = new_label(l, "if");
-                    let end_label  = new_label(l, "endif");
+            // anything other than false takes the first branch
+            instr.push(load(Reg::RAX, cond, env));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(types::FALSE_VAL)));
+            instr.push(Instr::JEqual(Val::Label(else_label.clone())));
+            instr.extend(compile_aexpr(thn, f, l, brake, out, brk, tail));
+            instr.push(Instr::Jmp(Val::Label(end_label.clone())));
+            instr.push(Instr::Label(Val::Label(else_label)));
+            instr.extend(compile_aexpr(els, f, l, brake, out, brk, tail));
+            instr.push(Instr::Label(Val::Label(end_label)));
+        },
 
-                    // jump to if condition if less than
-                    instr.push(Instr::JLess(Val::Label(cond_label.clone())));
-                    
-                    conditional_jmp_compare(&mut instr, end_label, cond_label);
+        // Loop //
+        CExpr::Loop(body) => {
+            let startloop = new_label(l, "loop");
+            let endloop = new_label(l, "loopend");
 
-                },
-                Op2::LessEqual => {
-                    compare_size(&mut instr, 
-                        compile_to_instrs(&subexpr1, si, env, l, brake, func_names,false), 
-                        compile_to_instrs(&subexpr2, si+1, env, l, brake, func_names,false), 
-                        si);
+            instr.push(Instr::Label(Val::Label(startloop.clone())));
+            instr.extend(compile_aexpr(body, f, l, &endloop, &regalloc::loop_live(body, out), out, None));
+            instr.push(Instr::Jmp(Val::Label(startloop)));
+            instr.push(Instr::Label(Val::Label(endloop)));
+        },
 
-                    // create labels
-                    let cond_label = new_label(l, "if");
-                    let end_label  = new_label(l, "endif");
+        // Break //
+        CExpr::Break(imm) => {
+            instr.push(load(Reg::RAX, imm, env));
+            instr.push(Instr::Jmp(Val::Label(String::from(brake))));
+        },
 
-                    // jump to if condition if less than or equal
-                    instr.push(Instr::JLessEqual(Val::Label(cond_label.clone())));
-                    
-                    conditional_jmp_compare(&mut instr, end_label, cond_label);
+        // Set //
+        CExpr::Set(name, imm) => {
+            instr.push(load(Reg::RAX, imm, env));
+            instr.push(Instr::IMov(env[name].clone(), Val::Reg(Reg::RAX)));
+        },
 
+        // fall-through of a cond/case without an else clause //
+        CExpr::NoMatch => instr.push(Instr::Jmp(Val::Label(String::from("no_match")))),
+
+        CExpr::Call(name, args) => {
+            match tail {
+                Some(arity) if args.len() <= arity => {
+                    // reuse our own argument slots, tear down the frame and jump to the callee
+                    move_to_args(&mut instr, args, f.si, env);
+                    instr.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+                    instr.push(Instr::Pop(Val::Reg(Reg::RBP)));
+                    instr.push(Instr::Jmp(Val::Label(fun_label(name))));
+                },
+                _ => {
+                    let restore = save_live(&mut instr, f, out, true);
+                    let pushed = push_args(&mut instr, args, env);
+                    instr.push(Instr::Call(Val::Label(fun_label(name))));
+                    instr.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm((pushed * 8) as u64)));
+                    instr.extend(restore);
                 },
             }
         },
 
-        // Let Expression //
-        Expr::Let(vec,body) => {
-            let mut nenv = env.clone();
-            let mut scope_keys:std::collections::HashSet<String> = std::collections::HashSet::new();
-            for item in vec {
-                let key = item.0.clone();
-                if scope_keys.contains(&key) {
-                    panic!("Error - Duplicate binding.")
-                } else {
-                    scope_keys.insert(key.clone());
-                }
-                instr.extend(compile_to_instrs(&item.1, si, &nenv, l, brake, func_names,false));
-                nenv = nenv.update(key, si*8); 
-                instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, si*8), Val::Reg(Reg::RAX)));
-                si = si + 1;
-            }
-            instr.extend(compile_to_instrs(body, si+1, &nenv, l, brake, func_names,tail));
-        },
+        // Tuple allocation //
+        CExpr::Tuple(items) => {
+            let len = items.len() as i64;
 
-        // Variable string //
-        Expr::Id(s) => {if s == "input" {        
-            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDI)));
-            instr.push(Instr::OverFlow())
-        }
-        else {
-            let output = env.get(s);
-            match output {
-                Option::Some(x) => instr.push(Instr::IMov(Val::Reg(Reg::RAX),Val::RegOffset(Reg::RSP, *x))),
-                Option::None => {
-                    let s_out = &s[..];
-                    panic!("Error - Unbound variable identifier {}",s_out);
-                }
-           }
-        }},
-
-        Expr::Call(name, args) => {
-            if ! func_names.contains(name) {
-                panic!("Error - Invalid function call without definition.")
-            }
+            // make sure the heap has room for the GC word, the length and the elements
+            heap_alloc_check(&mut instr, (len + 2) as u64, l, f, &out.clone().union(regalloc::uses(c)));
 
-            let arg_len = args.len() + 1;
-            let mut offset:u64 = (si as u64 * 8) + (arg_len as u64 * 8);
+            // first word is reserved for the garbage collector, second holds the number of elements
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(0)));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, 0), Val::Reg(Reg::RBX)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(len as u64)));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -8), Val::Reg(Reg::RBX)));
 
-            if offset % 16 == 0 {
-                offset += 8;
+            // copy the elements onto the heap; they are read after the collection, which
+            // may have moved them, so they count as live across it
+            for (i, item) in items.iter().enumerate() {
+                instr.push(load(Reg::RBX, item, env));
+                instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -8 * (i as i64 + 2)), Val::Reg(Reg::RBX)));
             }
 
-            let original_offset = offset;
-
-            if tail {
-                let mut iter = -1;
-                let mut iter2 = args.len() as i64;
+            // tag the address and bump the heap pointer
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::R15)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(types::TUPLE_TAG)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::R15), Val::Imm(((len + 2) * 8) as u64)));
+        },
 
-                for expr in args {
-                    instr.extend(compile_to_instrs(expr, si+(iter*-1), env, l, brake, func_names,false));
-                    instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, iter2 * 8), Val::Reg(Reg::RAX)));
-                    iter2 -= 1;
+        // Closure allocation //
+        CExpr::MakeClosure(label, arity, captured) => {
+            let len = captured.len() as i64;
+
+            // GC word, length, arity, code address and the captured values
+            heap_alloc_check(&mut instr, (len + 4) as u64, l, f, &out.clone().union(regalloc::uses(c)));
+
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(0)));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, 0), Val::Reg(Reg::RBX)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm((len + 2) as u64)));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -8), Val::Reg(Reg::RBX)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm((*arity as u64) << 1)));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -16), Val::Reg(Reg::RBX)));
+            instr.push(Instr::Lea(Val::Reg(Reg::RBX), Val::Label(fun_label(label))));
+            instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -24), Val::Reg(Reg::RBX)));
+
+            for (i, name) in captured.iter().enumerate() {
+                instr.push(load(Reg::RAX, &Imm::Var(name.clone()), env));
+                instr.push(Instr::IMov(Val::RegOffset(Reg::R15, -8 * (i as i64 + 4)), Val::Reg(Reg::RAX)));
+            }
 
-                }
+            // tag the address and bump the heap pointer
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::R15)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(types::CLOSURE_TAG)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::R15), Val::Imm(((len + 4) * 8) as u64)));
+        },
 
-                iter2 = args.len() as i64;
+        // Captured variable //
+        CExpr::ClosureVar(i) => {
+            // the closure is always the first argument of a lifted function
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, -16)));
+            let field = 32 + 8 * (*i as i64) - types::CLOSURE_TAG as i64;
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, -field)));
+        },
 
-                for _ in args {
-                    instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP,  8 * iter2)));
-                    instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, iter * 8),Val::Reg(Reg::RBX)));
+        // Closure call //
+        CExpr::App(fun, args) => {
+            // the callee must be a closure expecting this many arguments
+            instr.push(load(Reg::RAX, fun, env));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+            instr.push(Instr::And(Val::Reg(Reg::RBX), Val::Imm(types::TAG_MASK)));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(types::CLOSURE_TAG)));
+            instr.push(Instr::JNotEqual(Val::Label(String::from("not_a_function"))));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RAX, -(16 - types::CLOSURE_TAG as i64))));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm((args.len() as u64) << 1)));
+            instr.push(Instr::JNotEqual(Val::Label(String::from("arity_error"))));
+
+            // the closure goes before the arguments
+            let mut all_args = vec![fun.clone()];
+            all_args.extend(args.iter().cloned());
+            match tail {
+                Some(arity) if args.len() < arity => {
+                    // reuse our own argument slots, tear down the frame and jump to the code address
+                    move_to_args(&mut instr, &all_args, f.si, env);
+                    instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, -(24 - types::CLOSURE_TAG as i64))));
+                    instr.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+                    instr.push(Instr::Pop(Val::Reg(Reg::RBP)));
+                    instr.push(Instr::Jmp(Val::Reg(Reg::RAX)));
+                },
+                _ => {
+                    let restore = save_live(&mut instr, f, out, true);
+                    let pushed = push_args(&mut instr, &all_args, env);
+                    instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, -(24 - types::CLOSURE_TAG as i64))));
+                    instr.push(Instr::Call(Val::Reg(Reg::RAX)));
+                    instr.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm((pushed * 8) as u64)));
+                    instr.extend(restore);
+                },
+            }
+        },
 
-                    offset -= 8;
-                    iter -= 1;
-                    iter2 -= 1;
-                }
-        
-                instr.push(Instr::Jmp(Val::Label(name.clone())));
+        // Tuple indexing //
+        CExpr::Index(tuple, idx) => {
+            // index must be a number
+            instr.push(load(Reg::RAX, idx, env));
+            type_number_check(&mut instr);
+            instr.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(Reg::RAX)));
+
+            // indexed value must be a tuple
+            instr.push(load(Reg::RAX, tuple, env));
+            instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+            instr.push(Instr::And(Val::Reg(Reg::RBX), Val::Imm(types::TAG_MASK)));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(types::TUPLE_TAG)));
+            instr.push(Instr::JNotEqual(Val::Label(String::from("index_error"))));
+            instr.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(types::TUPLE_TAG)));
+
+            // bounds check against the length stored in the second word
+            instr.push(Instr::Shr(Val::Reg(Reg::RDX), Val::Imm(1)));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RDX), Val::Imm(0)));
+            instr.push(Instr::JLess(Val::Label(String::from("index_error"))));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RDX), Val::RegOffset(Reg::RAX, -8)));
+            instr.push(Instr::JGreaterEqual(Val::Label(String::from("index_error"))));
+
+            // load the element, skipping over the GC and length words
+            instr.push(Instr::Shl(Val::Reg(Reg::RDX), Val::Imm(3)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, -16)));
+        },
+    }
+    instr
+}
 
+// Both operands are checked before the operation; ANF conversion has already added a
+// CheckNum where the first operand had to be checked before the second one ran.
+fn compile_prim2(instr: &mut Vec<Instr>, op2: &Op2, a: &Imm, b: &Imm, env: &HashMap<String,Val>, l: &mut i32) {
+    // b goes to rbx and a to rax
+    instr.push(load(Reg::RAX, b, env));
+    if !matches!(op2, Op2::Equal) {
+        type_number_check(instr);
+    }
+    instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+    instr.push(load(Reg::RAX, a, env));
+    match op2 {
+        Op2::Plus => {
+            type_number_check(instr);
+            instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::OverFlow());
+        },
+        Op2::Minus => {
+            type_number_check(instr);
+            instr.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::OverFlow());
+        },
+        Op2::Times => {
+            type_number_check(instr);
+            instr.push(Instr::Shr(Val::Reg(Reg::RAX), Val::Imm(1)));
+            instr.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::OverFlow());
+        },
+        Op2::Divide | Op2::Modulo => {
+            type_number_check(instr);
+            instr.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(0)));
+            instr.push(Instr::JEqual(Val::Label(String::from("divide_by_zero"))));
+
+            // dividing tagged values gives the untagged quotient and the tagged remainder
+            instr.push(Instr::Cqo());
+            instr.push(Instr::IDiv(Val::Reg(Reg::RBX)));
+
+            // idiv truncates towards zero; round towards negative infinity instead when
+            // the remainder is non-zero and its sign differs from the divisor's
+            let end_label = new_label(l, "floor");
+            instr.push(Instr::Cmp(Val::Reg(Reg::RDX), Val::Imm(0)));
+            instr.push(Instr::JEqual(Val::Label(end_label.clone())));
+            instr.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RDX)));
+            instr.push(Instr::Xor(Val::Reg(Reg::RCX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::Cmp(Val::Reg(Reg::RCX), Val::Imm(0)));
+            instr.push(Instr::JGreaterEqual(Val::Label(end_label.clone())));
+            instr.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(1)));
+            instr.push(Instr::IAdd(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::Label(Val::Label(end_label)));
+
+            if let Op2::Divide = op2 {
+                // re-tag the quotient, (/ LEAST_VAL -1) is the only case that overflows
+                instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RAX)));
+                instr.push(Instr::OverFlow());
             } else {
-    
-                let mut iter = 0;
-                // instr.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(offset)));
-                for expr in args {
-                    instr.extend(compile_to_instrs(expr, si+iter, env, l, brake, func_names,false));
-                    instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, offset as i64), Val::Reg(Reg::RAX)));
-                    offset -= 8;
-                    iter += 1;
-                }
-    
-                instr.push(Instr::IMov(Val::RegOffset(Reg::RSP, offset as i64), Val::Reg(Reg::RDI)));
-    
-                instr.push(Instr::ISub(Val::Reg(Reg::RSP),Val::Imm(original_offset)));
-    
-                instr.push(Instr::Call(Val::Label(name.clone())));
-                instr.push(Instr::IAdd(Val::Reg(Reg::RSP),Val::Imm(original_offset)));
+                instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+            }
+        },
+        Op2::Equal => {
+            // only a number compared with a non-number is an error
+            same_type_check(instr);
+            instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Imm(types::TRUE_VAL)));
+            instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(types::FALSE_VAL)));
+            instr.push(Instr::Cmove(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+        },
+        Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+            type_number_check(instr);
+            instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
 
-            }      
-        }
+            let cond_label = new_label(l, "if");
+            let end_label = new_label(l, "endif");
+            let target = Val::Label(cond_label.clone());
+            instr.push(match op2 {
+                Op2::Greater => Instr::JGreater(target),
+                Op2::GreaterEqual => Instr::JGreaterEqual(target),
+                Op2::Less => Instr::JLess(target),
+                _ => Instr::JLessEqual(target),
+            });
+            conditional_jmp_compare(instr, end_label, cond_label);
+        },
     }
-    instr
 }
 
+// pushes the arguments of a call, last one first, as an even number of words so rsp stays
+// 16-byte aligned at the call; returns the number of words pushed
+fn push_args(instr: &mut Vec<Instr>, args: &[Imm], env: &HashMap<String,Val>) -> usize {
+    let padding = args.len() % 2;
+    if padding == 1 {
+        instr.push(Instr::Push(Val::Imm(0)));
+    }
+    for arg in args.iter().rev() {
+        instr.push(load(Reg::RBX, arg, env));
+        instr.push(Instr::Push(Val::Reg(Reg::RBX)));
+    }
+    args.len() + padding
+}
+
+// copies the arguments of a tail call into our own argument slots, going through the
+// slots from si so that an argument read from one of those slots is not overwritten first
+fn move_to_args(instr: &mut Vec<Instr>, args: &[Imm], si: i64, env: &HashMap<String,Val>) {
+    for (i, arg) in args.iter().enumerate() {
+        instr.push(load(Reg::RBX, arg, env));
+        instr.push(Instr::IMov(Val::RegOffset(Reg::RBP, (si + i as i64) * 8), Val::Reg(Reg::RBX)));
+    }
+    for i in 0..args.len() as i64 {
+        instr.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RBP, (si + i) * 8)));
+        instr.push(Instr::IMov(Val::RegOffset(Reg::RBP, -(16 + 8 * i)), Val::Reg(Reg::RBX)));
+    }
+}
 
 fn conditional_jmp_compare(instr: &mut Vec<Instr>, end_label: String, cond_label: String){
     // condition not met, set RAX to false
@@ -366,50 +441,18 @@ fn conditional_jmp_compare(instr: &mut Vec<Instr>, end_label: String, cond_label
     instr.push(Instr::Label(Val::Label(end_label.clone())));
 }
 
-fn compare_size(vec: &mut Vec<Instr>, e1: Vec<Instr>, e2: Vec<Instr>, si: i64){
-    // compute expression1, save to memory
-    vec.extend(e1);
-    vec.push(Instr::IMov(Val::RegOffset(Reg::RSP, si*8), Val::Reg(Reg::RAX)));
-
-    // compute expression2 
-    vec.extend(e2);
-
-    // confirm that they are the same type 
-    same_type_expr(vec, si *8);
-
-    // confirm that of type number
-    type_number_check(vec);
-
-    // compare values
-    vec.push(Instr::Cmp(Val::RegOffset(Reg::RSP, si*8), Val::Reg(Reg::RAX)));    
-
-}
-
 fn type_number_check(vec: &mut Vec<Instr>){
     vec.push(Instr::Test(Val::Reg(Reg::RAX),Val::Imm(1)));
     vec.push(Instr::JNotEqual(Val::Label(String::from("invalid_arg"))));
 }
 
 
-fn update_vec_binop(vec: &mut Vec<Instr>, append1: Vec<Instr>, append2: Vec<Instr>, append3: Instr, si: i64) {
-    vec.extend(append1);
-    let stack_offset = si * 8;
-
-    // confirm that value is a number 
-    type_number_check(vec);
-
-    vec.push(Instr::IMov(Val::RegOffset(Reg::RSP, stack_offset),Val::Reg(Reg::RAX)));
-    vec.extend(append2);
-
-    // confirm that value is a number 
-    type_number_check(vec);
-    vec.push(append3);
-}
-
-fn update_vec_unop(vec: &mut Vec<Instr>, append1: Vec<Instr>, append2: Instr) {
-    vec.extend(append1);
-    type_number_check(vec);
-    vec.push(append2);
+fn type_bool_check(vec: &mut Vec<Instr>){
+    // booleans are 0b01 and 0b11, so masking with 0b101 leaves 1 only for them
+    vec.push(Instr::IMov(Val::Reg(Reg::RBX),Val::Reg(Reg::RAX)));
+    vec.push(Instr::And(Val::Reg(Reg::RBX),Val::Imm(5)));
+    vec.push(Instr::Cmp(Val::Reg(Reg::RBX),Val::Imm(1)));
+    vec.push(Instr::JNotEqual(Val::Label(String::from("invalid_arg"))));
 }
 
 fn new_label(l: &mut i32, s: &str) -> String {
@@ -418,16 +461,33 @@ fn new_label(l: &mut i32, s: &str) -> String {
     format!("{s}_{current}")
 }
 
+// Label of a snek function. The prefix keeps it apart from every label made up here and
+// from the runtime's symbols, and anything but letters and digits becomes _xx_ with its hex
+// code, so any identifier makes a valid label and distinct names stay distinct.
+pub fn fun_label(name: &str) -> String {
+    let mut out = String::from("fun_");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("_{:x}_", c as u32));
+        }
+    }
+    out
+}
+
 
 fn check_bool_type_instr(instr: &mut Vec<Instr>, l: &mut i32){
     // Create labels
     let cond_label = new_label(l, "if");
     let end_label  = new_label(l, "endif");
 
-    // bitwise and with RAX register and 1 to determine if LSB is 0
-    // LSB of 0 denotes that the value is a number -> jmp if not zero...i.e. true if bool
-    instr.push(Instr::Test(Val::Reg(Reg::RAX),Val::Imm(1)));
-    instr.push(Instr::JNotEqual(Val::Label(cond_label.clone())));
+    // booleans are the only values with a low bit of 1 and a clear tag bit 2
+    // (numbers have a LSB of 0, heap values carry the tag 0b101)
+    instr.push(Instr::IMov(Val::Reg(Reg::RDX),Val::Reg(Reg::RAX)));
+    instr.push(Instr::And(Val::Reg(Reg::RDX),Val::Imm(5)));
+    instr.push(Instr::Cmp(Val::Reg(Reg::RDX),Val::Imm(1)));
+    instr.push(Instr::JEqual(Val::Label(cond_label.clone())));
 
     // else branch --> value is not a bool
     instr.push(Instr::IMov(Val::Reg(Reg::RAX),Val::Imm(types::FALSE_VAL)));
@@ -459,9 +519,9 @@ fn check_num_type_instr(instr: &mut Vec<Instr>, l: &mut i32){
     instr.push(Instr::Label(Val::Label(end_label.clone())))
 }
 
-fn same_type_expr(instr: &mut Vec<Instr>, offset:i64){
+fn same_type_check(instr: &mut Vec<Instr>){
     instr.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(Reg::RAX)));
-    instr.push(Instr::Xor(Val::Reg(Reg::RDX),Val::RegOffset(Reg::RSP, offset)));
+    instr.push(Instr::Xor(Val::Reg(Reg::RDX),Val::Reg(Reg::RBX)));
 
     instr.push(Instr::Test(Val::Reg(Reg::RDX), Val::Imm(1)));
 
@@ -469,124 +529,294 @@ fn same_type_expr(instr: &mut Vec<Instr>, offset:i64){
     instr.push(Instr::JNotEqual(Val::Label(String::from("invalid_arg"))));
 }
 
-fn instr_to_str(i: &Instr) -> String {
+fn instr_to_str(i: &Instr, syntax: Syntax) -> String {
     match i {
-        Instr::IMov(val_a, val_b) => format!("\nmov {}, {}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::IAdd(val_a, val_b) => format!("\nadd {}, {}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::ISub(val_a, val_b) => format!("\nsub {}, {}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::IMul(val_a, val_b) => format!("\nimul {}, {}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::Shr(val_a, val_b) => format!("\nsar {},{}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::Shl(val_a,val_b) => format!("\nshl {},{}",val_to_str(val_a),val_to_str(val_b)),
-        Instr::Cmp(val_a, val_b) => format!("\ncmp {},{}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::JEqual(val_a) => format!("\nje {}", val_to_str(val_a)),
-        Instr::Jmp(val_a) => format!("\njmp {}", val_to_str(val_a)),
-        Instr::JNotEqual(val_a) => format!("\njne {}", val_to_str(val_a)),
-        Instr::JGreater(val_a) => format!("\njg {}", val_to_str(val_a)),
-        Instr::JGreaterEqual(val_a) => format!("\njge {}", val_to_str(val_a)),
-        Instr::JLess(val_a) => format!("\njl {}", val_to_str(val_a)),
-        Instr::JLessEqual(val_a) => format!("\njle {}", val_to_str(val_a)),
-        Instr::Test(val_a, val_b) => format!("\ntest {},{}", val_to_str(val_a), val_to_str(val_b)),
-        Instr::Label(val_a) => format!("\n{}:",val_to_str(val_a)),
-        Instr::Xor(val_a,val_b) => format!("\nxor {},{}",val_to_str(val_a),val_to_str(val_b)),
-        Instr::Cmove(val_a,val_b) => format!("\ncmove {},{}",val_to_str(val_a),val_to_str(val_b)),
-        Instr::OverFlow() => format!("\njo overflow"),
-        Instr::Call(val_a) => format!("\ncall {}", val_to_str(val_a)),
-        Instr::Push(val_a) => format!("\npush {}",val_to_str(val_a)),
-        Instr::Pop(val_a) => format!("\npop {}",val_to_str(val_a)),
-        Instr::Ret() => format!("\nret"),
+        Instr::IMov(val_a, val_b) => format!("\nmov {}, {}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::IAdd(val_a, val_b) => format!("\nadd {}, {}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::ISub(val_a, val_b) => format!("\nsub {}, {}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::IMul(val_a, val_b) => format!("\nimul {}, {}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::IDiv(val_a) => format!("\nidiv {}", val_to_str(val_a, syntax)),
+        Instr::Cqo() => String::from("\ncqo"),
+        Instr::Shr(val_a, val_b) => format!("\nsar {},{}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::Shl(val_a,val_b) => format!("\nshl {},{}",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+        Instr::Cmp(val_a, val_b) => format!("\ncmp {},{}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::JEqual(val_a) => format!("\nje {}", val_to_str(val_a, syntax)),
+        Instr::Jmp(val_a) => format!("\njmp {}", val_to_str(val_a, syntax)),
+        Instr::JNotEqual(val_a) => format!("\njne {}", val_to_str(val_a, syntax)),
+        Instr::JGreater(val_a) => format!("\njg {}", val_to_str(val_a, syntax)),
+        Instr::JGreaterEqual(val_a) => format!("\njge {}", val_to_str(val_a, syntax)),
+        Instr::JLess(val_a) => format!("\njl {}", val_to_str(val_a, syntax)),
+        Instr::JLessEqual(val_a) => format!("\njle {}", val_to_str(val_a, syntax)),
+        Instr::Test(val_a, val_b) => format!("\ntest {},{}", val_to_str(val_a, syntax), val_to_str(val_b, syntax)),
+        Instr::Label(val_a) => format!("\n{}:",val_to_str(val_a, syntax)),
+        Instr::Xor(val_a,val_b) => format!("\nxor {},{}",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+        Instr::And(val_a,val_b) => format!("\nand {},{}",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+        Instr::Lea(val_a,val_b) => match syntax {
+            Syntax::Nasm => format!("\nlea {}, [rel {}]",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+            Syntax::Gas => format!("\nlea {}, [rip + {}]",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+        },
+        Instr::Cmove(val_a,val_b) => format!("\ncmove {},{}",val_to_str(val_a, syntax),val_to_str(val_b, syntax)),
+        Instr::OverFlow() => String::from("\njo overflow"),
+        Instr::Call(val_a) => format!("\ncall {}", val_to_str(val_a, syntax)),
+        Instr::Push(val_a) => format!("\npush {}",val_to_str(val_a, syntax)),
+        Instr::Pop(val_a) => format!("\npop {}",val_to_str(val_a, syntax)),
+        Instr::Ret() => String::from("\nret"),
     }
 }
 
-fn val_to_str(v: &Val) -> String {
+fn val_to_str(v: &Val, syntax: Syntax) -> String {
     match v {
-        Val::Reg(Reg::RAX) => String::from("rax"),
-        Val::Reg(Reg::RBX) => String::from("rbx"),
-        Val::Reg(Reg::RSP) => String::from("rsp"),
-        Val::Reg(Reg::RDI) => String::from("rdi"),
-        Val::Reg(Reg::RDX) => String::from("rdx"),
-        Val::Imm(n) => n.to_string(),
-        Val::RegOffset(Reg::RSP,n) => {
+        Val::Reg(reg) => reg_to_str(reg),
+        Val::Imm(n) => match syntax {
+            Syntax::Nasm => n.to_string(),
+            // as takes immediates as signed values
+            Syntax::Gas => (*n as i64).to_string(),
+        },
+        Val::RegOffset(reg,n) => {
+            // as needs the operand size whenever the other operand does not give it
+            let size = match syntax {
+                Syntax::Nasm => "",
+                Syntax::Gas => "QWORD PTR ",
+            };
             if *n < 0 {
-                format!("[rsp+{}]",-1 * n)}
+                format!("{size}[{}+{}]",reg_to_str(reg),-n)}
+            else if *n == 0 {
+                format!("{size}[{}]",reg_to_str(reg))}
             else {
-                format!("[rsp-{}]",n)}
+                format!("{size}[{}-{}]",reg_to_str(reg),n)}
             },
-        Val::Label(str_val) => format!("{}",str_val),
-        _ => panic!("TODO val_to_str")
+        Val::Label(str_val) => str_val.to_string(),
+    }
+}
+
+fn reg_to_str(r: &Reg) -> String {
+    match r {
+        Reg::RAX => String::from("rax"),
+        Reg::RBX => String::from("rbx"),
+        Reg::RSP => String::from("rsp"),
+        Reg::RDI => String::from("rdi"),
+        Reg::RDX => String::from("rdx"),
+        Reg::RBP => String::from("rbp"),
+        Reg::RSI => String::from("rsi"),
+        Reg::RCX => String::from("rcx"),
+        Reg::R8 => String::from("r8"),
+        Reg::R9 => String::from("r9"),
+        Reg::R10 => String::from("r10"),
+        Reg::R11 => String::from("r11"),
+        Reg::R12 => String::from("r12"),
+        Reg::R13 => String::from("r13"),
+        Reg::R14 => String::from("r14"),
+        Reg::R15 => String::from("r15"),
     }
 }
 
-fn compile_definition_instrs(d: &Definition, labels: &mut i32, func_names: &HashSet<String>) -> Vec<Instr> {
-    let (env, body, name) = match d {
-        Definition::Fun(name, args, body) => {
-            let mut body_env:HashMap<String,i64> = HashMap::new();
-            let mut mem_addr = -8;
-            for item in args {
-                if body_env.contains_key(item){
-                    panic!("Error - invalid function declaration; parameter is declared twice")
-                }
-                body_env.insert(String::from(item),mem_addr);
-                mem_addr -= 8;
+// highest [rbp - 8*n] slot referenced by a function body
+fn frame_slots(instrs: &[Instr]) -> i64 {
+    let mut slots = 0;
+    for i in instrs {
+        let vals = match i {
+            Instr::IMov(a, b) | Instr::IAdd(a, b) | Instr::ISub(a, b) | Instr::IMul(a, b) |
+            Instr::Shr(a, b) | Instr::Shl(a, b) | Instr::Cmp(a, b) | Instr::Test(a, b) |
+            Instr::Xor(a, b) | Instr::And(a, b) | Instr::Lea(a, b) | Instr::Cmove(a, b) => vec![a, b],
+            Instr::Jmp(a) | Instr::JEqual(a) | Instr::JNotEqual(a) | Instr::JGreater(a) |
+            Instr::JGreaterEqual(a) | Instr::JLess(a) | Instr::JLessEqual(a) | Instr::Label(a) |
+            Instr::Call(a) | Instr::Push(a) | Instr::Pop(a) | Instr::IDiv(a) => vec![a],
+            Instr::OverFlow() | Instr::Cqo() | Instr::Ret() => vec![],
+        };
+        for v in vals {
+            if let Val::RegOffset(Reg::RBP, n) = v {
+                slots = slots.max(n / 8);
             }
-            (body_env, body, name)
         }
-    };
-    let mut out_instrs = Vec::new();
+    }
+    slots
+}
 
-    // add label for function name
-    out_instrs.push(Instr::Label(Val::Label(name.clone())));
+// points rbp at the current frame and reserves (and zeroes) enough slots for the body,
+// rounding up so rsp stays 16-byte aligned; the first slot holds the number of slots,
+// which the garbage collector needs to find the others
+fn frame_setup(instr: &mut Vec<Instr>, body: &[Instr], min_slots: i64) {
+    let mut slots = frame_slots(body).max(min_slots).max(1);
+    if slots % 2 != 0 {
+        slots += 1;
+    }
 
-    // compile instructions for function body
-    out_instrs.extend(compile_to_instrs(body, 2, &env, labels, &String::from(""), func_names, true));
-    out_instrs.push(Instr::Ret());
-    out_instrs
+    instr.push(Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)));
+    instr.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm((slots * 8) as u64)));
+    instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(0)));
+    for i in 2..=slots {
+        instr.push(Instr::IMov(Val::RegOffset(Reg::RBP, i * 8), Val::Reg(Reg::RAX)));
+    }
+    instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(slots as u64)));
+    instr.push(Instr::IMov(Val::RegOffset(Reg::RBP, 8), Val::Reg(Reg::RAX)));
+}
 
+// calls into the runtime to collect garbage when fewer than `words` words are left on the
+// heap; the collector only finds the variables of `live` that are in registers, and
+// updates them, once they are saved to the frame
+fn heap_alloc_check(instr: &mut Vec<Instr>, words: u64, l: &mut i32, f: &Frame, live: &Live) {
+    let ok_label = new_label(l, "alloc");
+
+    instr.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::R15)));
+    instr.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(words * 8)));
+    instr.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Reg(Reg::R14)));
+    instr.push(Instr::JLessEqual(Val::Label(ok_label.clone())));
+
+    // snek_try_gc(words, heap pointer, rbp) returns the new heap pointer
+    let restore = save_live(instr, f, live, true);
+    instr.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(words)));
+    instr.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(Reg::R15)));
+    instr.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(Reg::RBP)));
+    instr.push(Instr::Call(Val::Label(String::from("snek_try_gc"))));
+    instr.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RAX)));
+    instr.extend(restore);
+
+    instr.push(Instr::Label(Val::Label(ok_label)));
 }
 
-// this function incorporates aspects of the compile_program and compile_definition functions in the lecture code
-pub fn compile(p: &Program) -> (String,String) {
-    // create empty environment 
-    let env:&HashMap<String,i64> = &HashMap::new();
+// places the let-bound variables of a body whose slots start at `base`
+fn frame(body: &AExpr, base: i64, mut env: HashMap<String,Val>) -> Frame {
+    let alloc = regalloc::allocate(body, base);
+    env.extend(alloc.locs);
+    let saves = alloc.regs.iter().enumerate().map(|(i, reg)| (*reg, base + i as i64)).collect();
+    Frame { env, saves, si: alloc.next_slot }
+}
 
-    // initialize stack index, brake string, and label index
-    let si = 2;
-    let mut labels  = 0;
-    let brake = String::from("");
+fn compile_definition_instrs(d: &anf::Function, labels: &mut i32) -> Vec<Instr> {
+    let mut env = HashMap::new();
+    for (i, param) in d.params.iter().enumerate() {
+        env.insert(param.clone(), Val::RegOffset(Reg::RBP, -16 - 8 * i as i64));
+    }
+    let f = frame(&d.body, 2, env);
+    let mut out_instrs = Vec::new();
 
-    // create instructions for function defintions
-    let mut def_instrs:Vec<Instr> = Vec::new();
+    // compile instructions for function body
+    let body_instrs = compile_aexpr(&d.body, &f, labels, "", &Live::new(), &Live::new(), Some(d.params.len()));
 
-    // create hashset to insure each function declaration is unique 
-    let mut func_names = HashSet::new();
+    // add label for function name and set up the frame
+    out_instrs.push(Instr::Label(Val::Label(fun_label(&d.name))));
+    out_instrs.push(Instr::Push(Val::Reg(Reg::RBP)));
+    frame_setup(&mut out_instrs, &body_instrs, 0);
 
-    for def in &p.defs[..] {
-        let name = match def {
-            Definition::Fun(name,_, _) => name,
-           
-        };
-        if func_names.contains(name) { 
-            panic!("Error - invalid function declaration, function {name} declare multiple times.")
-        }
-        func_names.insert(name.clone());
+    out_instrs.extend(body_instrs);
 
-        def_instrs.extend(compile_definition_instrs(&def, &mut labels, &p.func_list));
-      }
-    
-    // create instructions for main body
-    let main_instrs = compile_to_instrs(&p.main,si,env, &mut labels, &brake, &p.func_list,true);
+    out_instrs.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
+    out_instrs.push(Instr::Ret());
+    out_instrs
+}
+
+fn compile_main_instrs(body_instrs: Vec<Instr>) -> Vec<Instr> {
+    // save the registers we use that the caller expects preserved
+    let mut out_instrs = vec![
+        Instr::Label(Val::Label(String::from("our_code_starts_here"))),
+        Instr::Push(Val::Reg(Reg::RBX)),
+        Instr::Push(Val::Reg(Reg::R12)),
+        Instr::Push(Val::Reg(Reg::R13)),
+        Instr::Push(Val::Reg(Reg::R14)),
+        Instr::Push(Val::Reg(Reg::R15)),
+        Instr::Push(Val::Reg(Reg::RBP)),
+    ];
+
+    // a saved rbp of 0 marks the bottom of the snek stack for the garbage collector
+    out_instrs.push(Instr::Push(Val::Imm(0)));
+    frame_setup(&mut out_instrs, &body_instrs, 2);
+
+    // input goes to the slot after the slot count, heap start and end are kept in r15 and r14
+    out_instrs.push(Instr::IMov(Val::RegOffset(Reg::RBP, 16), Val::Reg(Reg::RDI)));
+    out_instrs.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RSI)));
+    out_instrs.push(Instr::IMov(Val::Reg(Reg::R14), Val::Reg(Reg::RDX)));
+
+    out_instrs.extend(body_instrs);
+
+    out_instrs.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+    out_instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(8)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::R15)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::R14)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::R13)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::R12)));
+    out_instrs.push(Instr::Pop(Val::Reg(Reg::RBX)));
+    out_instrs.push(Instr::Ret());
+    out_instrs
+}
+
+// The control-flow graphs of every function definition and then of our_code_starts_here,
+// for a program that has passed check::check_program.
+pub fn compile_cfg(p: &Program) -> Result<Vec<cfg::Function>, CompileError> {
+    // lift lambdas into definitions of their own and name every intermediate value
+    let p = anf::convert(p)?;
+    let mut labels = 0;
 
-    let mut def_output = String::new();
-    let mut main_output = String::new();
+    let mut functions = Vec::new();
+    for def in &p.defs {
+        functions.push(cfg::Function::new(&fun_label(&def.name), compile_definition_instrs(def, &mut labels)));
+    }
+
+    // the input is kept in the slot of main after the slot count
+    let f = frame(&p.main, 3, HashMap::unit(String::from("input"), Val::RegOffset(Reg::RBP, 16)));
+    let main = compile_main_instrs(compile_aexpr(&p.main, &f, &mut labels, "", &Live::new(), &Live::new(), None));
+    functions.push(cfg::Function::new("our_code_starts_here", main));
+    Ok(functions)
+}
+
+// The whole program: the error handlers, the function definitions and our_code_starts_here.
+pub fn compile(p: &Program) -> Result<Vec<Instr>, CompileError> {
+    Ok(compile_with_stats(p)?.0)
+}
 
-    // convert def instr vector to assembly str
-    for entry in &def_instrs {
-        def_output = [def_output, instr_to_str(entry)].join("")
+// the same, along with what each peephole rule removed
+pub fn compile_with_stats(p: &Program) -> Result<(Vec<Instr>, peephole::Stats), CompileError> {
+    let functions = compile_cfg(p)?;
+
+    // every error handler passes its code to snek_error, which does not return
+    let mut instrs = vec![
+        Instr::Label(Val::Label(String::from("throw_error"))),
+        Instr::Call(Val::Label(String::from("snek_error"))),
+    ];
+    for f in &functions {
+        instrs.extend(f.linearize());
     }
 
-    // convert main instr vector to assembly str
-    for entry in &main_instrs {
-        main_output = [main_output, instr_to_str(entry)].join("")
+    let handlers = [
+        ("overflow", types::OVERFLOW_ERROR_CODE),
+        ("invalid_arg", types::INVALID_ARGUMENT_ERROR_CODE),
+        ("index_error", types::INDEX_ERROR_CODE),
+        ("not_a_function", types::NOT_A_FUNCTION_ERROR_CODE),
+        ("arity_error", types::ARITY_ERROR_CODE),
+        ("divide_by_zero", types::DIVIDE_BY_ZERO_ERROR_CODE),
+        ("no_match", types::NO_MATCH_ERROR_CODE),
+    ];
+    for (label, code) in handlers {
+        instrs.push(Instr::Label(Val::Label(String::from(label))));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(code)));
+        instrs.push(Instr::Jmp(Val::Label(String::from("throw_error"))));
     }
-    (def_output,main_output)
+    Ok(peephole::optimize(instrs))
+}
+
+// convert an instr vector to assembly str
+pub fn instrs_to_str(instrs: &[Instr], syntax: Syntax) -> String {
+    instrs.iter().map(|i| instr_to_str(i, syntax)).collect()
+}
 
-}
\ No newline at end of file
+// the whole assembly file for the instructions from compile, with the section and symbol
+// directives around them
+pub fn program_to_str(instrs: &[Instr], syntax: Syntax) -> String {
+    let prologue = match syntax {
+        Syntax::Nasm => "
+section .text
+extern snek_error
+extern snek_print
+extern snek_try_gc
+global our_code_starts_here",
+        Syntax::Gas => "
+.intel_syntax noprefix
+.text
+.extern snek_error
+.extern snek_print
+.extern snek_try_gc
+.globl our_code_starts_here",
+    };
+    format!("{prologue}{}\n", instrs_to_str(instrs, syntax))
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::compiler::Syntax;
+
+// Turns the assembly produced by `compile` into an executable: nasm or as assembles it (or
+// elf::object encodes the instructions directly), ar puts the object in the static library
+// the runtime links against, and rustc builds the runtime with it. The runtime source is
+// built into the compiler, so this works from any directory. Programs from the C backend
+// only need a C compiler.
+
+const RUNTIME: &str = include_str!("../runtime/start.rs");
+const RUNTIME_GC: &str = include_str!("../runtime/gc.rs");
+
+#[cfg(target_os = "macos")]
+const OBJECT_FORMAT: &str = "macho64";
+#[cfg(not(target_os = "macos"))]
+const OBJECT_FORMAT: &str = "elf64";
+
+#[derive(Debug)]
+pub enum DriverError {
+    // an external tool is not installed or not on the PATH
+    MissingTool(&'static str),
+    // an external tool exited with an error, with what it printed
+    ToolFailed(&'static str, String),
+    Io(io::Error),
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverError::MissingTool(tool) => write!(f, "could not find `{tool}`, make sure it is installed and on the PATH"),
+            DriverError::ToolFailed(tool, stderr) => write!(f, "`{tool}` failed:\n{stderr}"),
+            DriverError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for DriverError {
+    fn from(err: io::Error) -> DriverError {
+        DriverError::Io(err)
+    }
+}
+
+// a new directory under the system temp directory, removed along with its contents when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> io::Result<TempDir> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("diamondback-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// assembles and links `asm`, written for nasm or as, into an executable at `out`, keeping
+// intermediate files in a temp dir
+pub fn build_executable(asm: &str, syntax: Syntax, out: &Path) -> Result<(), DriverError> {
+    let dir = TempDir::new()?;
+    let asm_path = dir.path().join("our_code.s");
+    let obj_path = dir.path().join("our_code.o");
+    std::fs::write(&asm_path, asm)?;
+
+    match syntax {
+        Syntax::Nasm => run_tool("nasm", Command::new("nasm").arg("-f").arg(OBJECT_FORMAT).arg(&asm_path).arg("-o").arg(&obj_path))?,
+        Syntax::Gas => run_tool("as", Command::new("as").arg(&asm_path).arg("-o").arg(&obj_path))?,
+    }
+    link(&dir, &obj_path, out)
+}
+
+// links an object file, e.g. from elf::object, into an executable at `out`
+pub fn link_object(obj: &[u8], out: &Path) -> Result<(), DriverError> {
+    let dir = TempDir::new()?;
+    let obj_path = dir.path().join("our_code.o");
+    std::fs::write(&obj_path, obj)?;
+    link(&dir, &obj_path, out)
+}
+
+// compiles the output of the C backend (c::compile) into an executable at `out`; the
+// runtime is already part of the C file
+pub fn build_c(src: &str, out: &Path) -> Result<(), DriverError> {
+    let dir = TempDir::new()?;
+    let src_path = dir.path().join("our_code.c");
+    std::fs::write(&src_path, src)?;
+    run_tool("cc", Command::new("cc").arg("-O2").arg(&src_path).arg("-o").arg(out))
+}
+
+// puts the object in the static library the runtime links against and builds the runtime
+fn link(dir: &TempDir, obj_path: &Path, out: &Path) -> Result<(), DriverError> {
+    let lib_path = dir.path().join("libour_code.a");
+    let runtime_path = dir.path().join("start.rs");
+    std::fs::write(&runtime_path, RUNTIME)?;
+    std::fs::write(dir.path().join("gc.rs"), RUNTIME_GC)?;
+
+    run_tool("ar", Command::new("ar").arg("rcs").arg(&lib_path).arg(obj_path))?;
+    run_tool("rustc", Command::new("rustc").arg("-L").arg(dir.path()).arg(&runtime_path).arg("-o").arg(out))?;
+    Ok(())
+}
+
+fn run_tool(tool: &'static str, cmd: &mut Command) -> Result<(), DriverError> {
+    match cmd.output() {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(DriverError::MissingTool(tool)),
+        Err(err) => Err(DriverError::Io(err)),
+        Ok(output) if !output.status.success() =>
+            Err(DriverError::ToolFailed(tool, String::from_utf8_lossy(&output.stderr).trim().to_string())),
+        Ok(_) => Ok(()),
+    }
+}
@@ -0,0 +1,170 @@
+use super::types;
+use super::x86;
+
+use types::Instr;
+
+// Relocatable ELF64 objects for the code from `compiler::compile`, in place of running nasm
+// on the assembly text. The object has the same shape as nasm's: a .text section with
+// our_code_starts_here as its only global, and every label the code uses but does not
+// define (snek_print, snek_error, snek_try_gc) as an undefined symbol with a pc-relative
+// relocation, which the runtime provides when it is linked.
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u64 = 2;
+
+// section header indexes, in the order they are written (.rela.text is 2)
+const TEXT: u16 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u16 = 5;
+const SECTION_COUNT: u16 = 7;
+
+const ENTRY: &str = "our_code_starts_here";
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+// names joined by NUL bytes, starting with the empty name
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+pub fn object(instrs: &[Instr]) -> Result<Vec<u8>, x86::EncodeError> {
+    let code = x86::encode(instrs)?;
+    let entry = *code.labels.get(ENTRY).ok_or_else(|| x86::EncodeError(format!("{ENTRY} is not defined")))?;
+
+    // the null symbol and .text, then the globals: the entry point and every undefined symbol
+    let mut strtab = StringTable::new();
+    let mut symtab = Vec::new();
+    symbol(&mut symtab, 0, STB_LOCAL, STT_NOTYPE, 0, 0);
+    symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT, 0);
+    let first_global = 2;
+    let name = strtab.add(ENTRY);
+    symbol(&mut symtab, name, STB_GLOBAL, STT_FUNC, TEXT, entry as u64);
+
+    let mut undefined: Vec<&str> = Vec::new();
+    let mut rela = Vec::new();
+    for reloc in &code.relocs {
+        let index = match undefined.iter().position(|s| *s == reloc.symbol) {
+            Some(i) => i,
+            None => {
+                let name = strtab.add(&reloc.symbol);
+                symbol(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, 0, 0);
+                undefined.push(&reloc.symbol);
+                undefined.len() - 1
+            }
+        };
+        // the field holds the symbol relative to the end of the field
+        let sym = (first_global + 1 + index as u32) as u64;
+        rela.extend((reloc.offset as u64).to_le_bytes());
+        rela.extend(((sym << 32) | R_X86_64_PC32).to_le_bytes());
+        rela.extend((-4i64).to_le_bytes());
+    }
+
+    let sections = [
+        Section { name: ".text", kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, data: code.bytes, link: 0, info: 0, align: 16, entsize: 0 },
+        Section { name: ".rela.text", kind: SHT_RELA, flags: SHF_INFO_LINK, data: rela, link: SYMTAB, info: TEXT as u32, align: 8, entsize: 24 },
+        Section { name: ".symtab", kind: SHT_SYMTAB, flags: 0, data: symtab, link: STRTAB, info: first_global, align: 8, entsize: 24 },
+        Section { name: ".strtab", kind: SHT_STRTAB, flags: 0, data: strtab.bytes, link: 0, info: 0, align: 1, entsize: 0 },
+        Section { name: ".shstrtab", kind: SHT_STRTAB, flags: 0, data: Vec::new(), link: 0, info: 0, align: 1, entsize: 0 },
+        // marks the stack as not executable, like nasm does
+        Section { name: ".note.GNU-stack", kind: SHT_PROGBITS, flags: 0, data: Vec::new(), link: 0, info: 0, align: 1, entsize: 0 },
+    ];
+    Ok(write(sections))
+}
+
+fn symbol(symtab: &mut Vec<u8>, name: u32, bind: u8, kind: u8, section: u16, value: u64) {
+    symtab.extend(name.to_le_bytes());
+    symtab.push((bind << 4) | kind);
+    symtab.push(0);
+    symtab.extend(section.to_le_bytes());
+    symtab.extend(value.to_le_bytes());
+    symtab.extend(0u64.to_le_bytes());
+}
+
+// the file header, the contents of every section and then the section headers
+fn write(mut sections: [Section; SECTION_COUNT as usize - 1]) -> Vec<u8> {
+    let mut shstrtab = StringTable::new();
+    let names: Vec<u32> = sections.iter().map(|s| shstrtab.add(s.name)).collect();
+    sections[SHSTRTAB as usize - 1].data = shstrtab.bytes;
+
+    let mut out = vec![0; 64];
+    let mut offsets = Vec::new();
+    for s in &sections {
+        while out.len() as u64 % s.align != 0 {
+            out.push(0);
+        }
+        offsets.push(out.len() as u64);
+        out.extend(&s.data);
+    }
+    while out.len() % 8 != 0 {
+        out.push(0);
+    }
+    let shoff = out.len() as u64;
+
+    out.extend([0; 64]);
+    for ((s, name), offset) in sections.iter().zip(names).zip(offsets) {
+        out.extend(name.to_le_bytes());
+        out.extend(s.kind.to_le_bytes());
+        out.extend(s.flags.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend((s.data.len() as u64).to_le_bytes());
+        out.extend(s.link.to_le_bytes());
+        out.extend(s.info.to_le_bytes());
+        out.extend(s.align.to_le_bytes());
+        out.extend(s.entsize.to_le_bytes());
+    }
+
+    let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    header.extend(1u16.to_le_bytes()); // relocatable
+    header.extend(62u16.to_le_bytes()); // x86-64
+    header.extend(1u32.to_le_bytes());
+    header.extend(0u64.to_le_bytes()); // entry
+    header.extend(0u64.to_le_bytes()); // program headers
+    header.extend(shoff.to_le_bytes());
+    header.extend(0u32.to_le_bytes()); // flags
+    header.extend(64u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(64u16.to_le_bytes());
+    header.extend(SECTION_COUNT.to_le_bytes());
+    header.extend(SHSTRTAB.to_le_bytes());
+    out[..64].copy_from_slice(&header);
+    out
+}
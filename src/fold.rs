@@ -0,0 +1,157 @@
+use super::types;
+use super::interp;
+
+use types::Expr;
+use types::ExprKind;
+use types::Op1;
+use types::Op2;
+use types::Program;
+use types::Definition;
+
+use im::HashMap;
+
+// Constant folding and propagation on a checked program, before closure conversion.
+// Operations whose operands are constants become their result, an if on a constant
+// condition becomes the branch it takes, and a let-bound variable whose value is a
+// constant and which is never set! is replaced by that constant. Anything that fails at
+// run time, an overflow, an operand of the wrong type or a division by zero, is left for
+// the compiled code so the program still fails the same way.
+
+pub fn program(p: &Program) -> Program {
+    let defs = p.defs.iter().map(|def| {
+        let Definition::Fun(name, params, body, span) = def;
+        Definition::Fun(name.clone(), params.clone(), fold(body, &HashMap::new()), *span)
+    }).collect();
+    Program { defs, main: fold(&p.main, &HashMap::new()), func_list: p.func_list.clone() }
+}
+
+fn is_constant(e: &Expr) -> bool {
+    matches!(e.kind, ExprKind::Number(_) | ExprKind::Boolean(_))
+}
+
+fn number(n: i64) -> Option<ExprKind> {
+    if (types::LEAST_VAL..=types::GREATEST_VAL).contains(&n) {
+        Some(ExprKind::Number(n as u64))
+    } else {
+        None
+    }
+}
+
+// whether e can set! `name`; lambdas and inner lets that shadow it count too, which
+// only keeps a constant that could have been propagated
+fn assigns(e: &Expr, name: &str) -> bool {
+    match &e.kind {
+        ExprKind::Set(var, val) => var == name || assigns(val, name),
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => false,
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) | ExprKind::Lambda(_, e1) => assigns(e1, name),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => assigns(e1, name) || assigns(e2, name),
+        ExprKind::If(cond, thn, els) => assigns(cond, name) || assigns(thn, name) || assigns(els, name),
+        ExprKind::Let(binds, body) => binds.iter().any(|(_, val)| assigns(val, name)) || assigns(body, name),
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es) | ExprKind::Call(_, es) =>
+            es.iter().any(|e| assigns(e, name)),
+        ExprKind::App(f, args) => assigns(f, name) || args.iter().any(|e| assigns(e, name)),
+    }
+}
+
+// the result of an operator applied to constants, or None when it has to run
+fn unop(op: &Op1, v: &ExprKind) -> Option<ExprKind> {
+    match (op, v) {
+        (Op1::Add1, ExprKind::Number(n)) => number(*n as i64 + 1),
+        (Op1::Sub1, ExprKind::Number(n)) => number(*n as i64 - 1),
+        (Op1::IsNum, ExprKind::Number(_) | ExprKind::Boolean(_)) => Some(ExprKind::Boolean(matches!(v, ExprKind::Number(_)))),
+        (Op1::IsBool, ExprKind::Number(_) | ExprKind::Boolean(_)) => Some(ExprKind::Boolean(matches!(v, ExprKind::Boolean(_)))),
+        (Op1::Not, ExprKind::Boolean(b)) => Some(ExprKind::Boolean(!b)),
+        _ => None,
+    }
+}
+
+fn binop(op: &Op2, v1: &ExprKind, v2: &ExprKind) -> Option<ExprKind> {
+    match (v1, v2) {
+        (ExprKind::Number(a), ExprKind::Number(b)) => {
+            let (a, b) = (*a as i64, *b as i64);
+            match op {
+                Op2::Plus => number(a + b),
+                Op2::Minus => number(a - b),
+                Op2::Times => a.checked_mul(b).and_then(number),
+                Op2::Divide | Op2::Modulo if b == 0 => None,
+                Op2::Divide => number(interp::floor_div(a, b).0),
+                Op2::Modulo => number(interp::floor_div(a, b).1),
+                Op2::Equal => Some(ExprKind::Boolean(a == b)),
+                Op2::Greater => Some(ExprKind::Boolean(a > b)),
+                Op2::GreaterEqual => Some(ExprKind::Boolean(a >= b)),
+                Op2::Less => Some(ExprKind::Boolean(a < b)),
+                Op2::LessEqual => Some(ExprKind::Boolean(a <= b)),
+            }
+        },
+        (ExprKind::Boolean(a), ExprKind::Boolean(b)) if matches!(op, Op2::Equal) => Some(ExprKind::Boolean(a == b)),
+        _ => None,
+    }
+}
+
+// `env` holds the variables in scope that are bound to a constant
+fn fold(e: &Expr, env: &HashMap<String, ExprKind>) -> Expr {
+    let all = |es: &[Expr]| es.iter().map(|e| fold(e, env)).collect();
+    let kind = match &e.kind {
+        ExprKind::Id(name) => env.get(name).cloned().unwrap_or_else(|| e.kind.clone()),
+        ExprKind::Let(binds, body) => {
+            let mut env = env.clone();
+            let mut kept = Vec::new();
+            for (i, (name, val)) in binds.iter().enumerate() {
+                let val = fold(val, &env);
+                let set_later = binds[i + 1..].iter().any(|(_, later)| assigns(later, name)) || assigns(body, name);
+                if is_constant(&val) && !set_later {
+                    env.insert(name.clone(), val.kind);
+                } else {
+                    env.remove(name);
+                    kept.push((name.clone(), val));
+                }
+            }
+            let body = fold(body, &env);
+            if kept.is_empty() {
+                return body;
+            }
+            ExprKind::Let(kept, Box::new(body))
+        },
+        ExprKind::UnOp(op, e1) => {
+            let e1 = fold(e1, env);
+            match unop(op, &e1.kind) {
+                Some(v) => v,
+                None => ExprKind::UnOp(op.clone(), Box::new(e1)),
+            }
+        },
+        ExprKind::BinOp(op, e1, e2) => {
+            let (e1, e2) = (fold(e1, env), fold(e2, env));
+            match binop(op, &e1.kind, &e2.kind) {
+                Some(v) => v,
+                None => ExprKind::BinOp(op.clone(), Box::new(e1), Box::new(e2)),
+            }
+        },
+        ExprKind::If(cond, thn, els) => {
+            // anything other than false takes the first branch
+            let cond = fold(cond, env);
+            match cond.kind {
+                ExprKind::Boolean(false) => return fold(els, env),
+                ExprKind::Boolean(true) | ExprKind::Number(_) => return fold(thn, env),
+                _ => ExprKind::If(Box::new(cond), Box::new(fold(thn, env)), Box::new(fold(els, env))),
+            }
+        },
+        ExprKind::Loop(body) => ExprKind::Loop(Box::new(fold(body, env))),
+        ExprKind::Break(e1) => ExprKind::Break(Box::new(fold(e1, env))),
+        ExprKind::Set(name, val) => ExprKind::Set(name.clone(), Box::new(fold(val, env))),
+        ExprKind::Block(es) => ExprKind::Block(all(es)),
+        ExprKind::And(es) => ExprKind::And(all(es)),
+        ExprKind::Or(es) => ExprKind::Or(all(es)),
+        ExprKind::Call(name, args) => ExprKind::Call(name.clone(), all(args)),
+        ExprKind::Tuple(es) => ExprKind::Tuple(all(es)),
+        ExprKind::Index(tuple, idx) => ExprKind::Index(Box::new(fold(tuple, env)), Box::new(fold(idx, env))),
+        ExprKind::Lambda(params, body) => {
+            let env = params.iter().fold(env.clone(), |env, param| env.without(param));
+            ExprKind::Lambda(params.clone(), Box::new(fold(body, &env)))
+        },
+        ExprKind::App(f, args) => ExprKind::App(Box::new(fold(f, env)), all(args)),
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => e.kind.clone(),
+    };
+    Expr::new(kind, e.span)
+}
@@ -0,0 +1,508 @@
+use super::types;
+use super::check;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use types::Op1;
+use types::Op2;
+use types::Span;
+
+use im::HashSet;
+
+// Random well-formed programs for differential testing of the compiler against the
+// interpreter (see tests/fuzz.rs), and a shrinker that reduces a failing program.
+//
+// Programs follow a simple type discipline so that most of them run to the end instead of
+// stopping at the first invalid argument; a few operands are given the wrong type on
+// purpose to exercise the error paths. Every program terminates: loops count down a
+// variable that nothing else assigns, and a function only calls itself after checking a
+// fuel parameter that the recursive call decrements.
+
+// xorshift64*, reproducible from a seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next_u64() % 100 < percent
+    }
+
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + self.below((hi - lo + 1) as usize) as i64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ty {
+    Num,
+    Bool,
+    // tuple of numbers with this many elements
+    Tuple(usize),
+    // function from this many numbers to a number
+    Fun(usize),
+}
+
+struct Var {
+    name: String,
+    ty: Ty,
+    // loop counters and fuel parameters are never assigned or shadowed
+    settable: bool,
+}
+
+struct Fun {
+    name: String,
+    arity: usize,
+    // takes fuel as its first argument, so it is only called directly with a small literal
+    recursive: bool,
+}
+
+struct Gen<'r> {
+    rng: &'r mut Rng,
+    // functions defined so far
+    funs: Vec<Fun>,
+    // (name, arity, fuel parameter) of the recursive function whose body is being generated
+    recursion: Option<(String, usize, String)>,
+    vars: Vec<Var>,
+    in_loop: bool,
+    // calls left in the current body, which keeps the running time of nested calls down
+    calls: usize,
+    names: usize,
+}
+
+// `size` bounds the depth of every expression
+pub fn gen_program(rng: &mut Rng, size: usize) -> Program {
+    let mut gen = Gen { rng, funs: Vec::new(), recursion: None, vars: Vec::new(), in_loop: false, calls: 0, names: 0 };
+
+    let mut defs = Vec::new();
+    let mut func_list = HashSet::new();
+    for i in 0..gen.rng.below(4) {
+        let name = format!("f{i}");
+        func_list.insert(name.clone());
+        defs.push(gen.definition(name, size));
+    }
+
+    gen.vars = vec![Var { name: String::from("input"), ty: Ty::Num, settable: false }];
+    gen.in_loop = false;
+    gen.calls = 3;
+    let ty = gen.any_ty();
+    let main = gen.expr(ty, size);
+
+    Program { defs, main, func_list }
+}
+
+fn mk(kind: ExprKind) -> Expr {
+    Expr::new(kind, Span::default())
+}
+
+fn num(n: i64) -> Expr {
+    mk(ExprKind::Number(n as u64))
+}
+
+fn id(name: &str) -> Expr {
+    mk(ExprKind::Id(String::from(name)))
+}
+
+impl Gen<'_> {
+    fn fresh(&mut self) -> String {
+        self.names += 1;
+        format!("x{}", self.names)
+    }
+
+    fn any_ty(&mut self) -> Ty {
+        match self.rng.below(20) {
+            0..=7 => Ty::Num,
+            8..=12 => Ty::Bool,
+            13..=16 => Ty::Tuple(1 + self.rng.below(3)),
+            _ => Ty::Fun(1 + self.rng.below(2)),
+        }
+    }
+
+    // indices of the variables in scope that are not shadowed and satisfy `pred`
+    fn visible(&self, pred: impl Fn(&Var) -> bool) -> Vec<usize> {
+        let mut seen = Vec::new();
+        let mut out = Vec::new();
+        for (i, var) in self.vars.iter().enumerate().rev() {
+            if seen.contains(&&var.name) {
+                continue;
+            }
+            seen.push(&var.name);
+            if pred(var) {
+                out.push(i);
+            }
+        }
+        out
+    }
+
+    fn pick_var(&mut self, pred: impl Fn(&Var) -> bool) -> Option<String> {
+        let vars = self.visible(pred);
+        if vars.is_empty() {
+            return None;
+        }
+        let i = vars[self.rng.below(vars.len())];
+        Some(self.vars[i].name.clone())
+    }
+
+    fn definition(&mut self, name: String, depth: usize) -> Definition {
+        let arity = 1 + self.rng.below(3);
+        let recursive = self.rng.chance(40);
+        let params: Vec<String> = (0..arity).map(|_| self.fresh()).collect();
+        self.vars = params.iter().enumerate()
+            .map(|(i, p)| Var { name: p.clone(), ty: Ty::Num, settable: !(recursive && i == 0) })
+            .collect();
+        self.in_loop = false;
+        self.calls = 3;
+
+        let body = if recursive {
+            // (if (< fuel 1) base step), only step calls the function again
+            let test = mk(ExprKind::BinOp(Op2::Less, Box::new(id(&params[0])), Box::new(num(1))));
+            let base = self.expr(Ty::Num, depth / 2);
+            self.recursion = Some((name.clone(), arity, params[0].clone()));
+            let step = self.expr(Ty::Num, depth);
+            self.recursion = None;
+            mk(ExprKind::If(Box::new(test), Box::new(base), Box::new(step)))
+        } else {
+            self.expr(Ty::Num, depth)
+        };
+
+        self.funs.push(Fun { name: name.clone(), arity, recursive });
+        Definition::Fun(name, params, body, Span::default())
+    }
+
+    fn expr(&mut self, ty: Ty, depth: usize) -> Expr {
+        // now and then produce a value of the wrong type
+        let ty = if self.rng.chance(3) { self.any_ty() } else { ty };
+        if depth == 0 || self.rng.chance(15) {
+            return self.leaf(ty);
+        }
+        let d = depth - 1;
+        let kind = match self.rng.below(12) {
+            0 => ExprKind::If(Box::new(self.expr(Ty::Bool, d)), Box::new(self.expr(ty, d)), Box::new(self.expr(ty, d))),
+            1 => self.let_expr(ty, d),
+            2 => {
+                let mut items: Vec<Expr> = (0..self.rng.below(3)).map(|_| { let t = self.any_ty(); self.expr(t, d) }).collect();
+                items.push(self.expr(ty, d));
+                ExprKind::Block(items)
+            },
+            3 => ExprKind::UnOp(Op1::Print, Box::new(self.expr(ty, d))),
+            4 if self.in_loop && self.rng.chance(40) => ExprKind::Break(Box::new(self.expr(Ty::Num, d))),
+            5 => match self.pick_var(|v| v.settable && v.ty == ty) {
+                Some(name) => ExprKind::Set(name, Box::new(self.expr(ty, d))),
+                None => return self.typed(ty, d),
+            },
+            _ => return self.typed(ty, d),
+        };
+        mk(kind)
+    }
+
+    fn typed(&mut self, ty: Ty, d: usize) -> Expr {
+        let kind = match ty {
+            Ty::Num => match self.rng.below(10) {
+                0 => {
+                    let op = if self.rng.chance(50) { Op1::Add1 } else { Op1::Sub1 };
+                    ExprKind::UnOp(op, Box::new(self.expr(Ty::Num, d)))
+                },
+                1..=3 => {
+                    let ops = [Op2::Plus, Op2::Minus, Op2::Times, Op2::Divide, Op2::Modulo];
+                    let op = ops[self.rng.below(ops.len())].clone();
+                    ExprKind::BinOp(op, Box::new(self.expr(Ty::Num, d)), Box::new(self.expr(Ty::Num, d)))
+                },
+                4 => self.loop_expr(d),
+                5 => {
+                    // mostly in bounds
+                    let len = 1 + self.rng.below(3);
+                    let idx = if self.rng.chance(85) { self.rng.range(0, len as i64 - 1) } else if self.rng.chance(50) { -1 } else { len as i64 };
+                    ExprKind::Index(Box::new(self.expr(Ty::Tuple(len), d)), Box::new(num(idx)))
+                },
+                6 | 7 if self.calls > 0 => {
+                    self.calls -= 1;
+                    match self.call(d) {
+                        Some(kind) => kind,
+                        None => {
+                            let arity = 1 + self.rng.below(2);
+                            self.app(arity, d)
+                        },
+                    }
+                },
+                8 if self.calls > 0 => {
+                    self.calls -= 1;
+                    let arity = 1 + self.rng.below(2);
+                    self.app(arity, d)
+                },
+                _ => return self.leaf(ty),
+            },
+            Ty::Bool => match self.rng.below(8) {
+                0 => ExprKind::UnOp(Op1::Not, Box::new(self.expr(Ty::Bool, d))),
+                1 | 2 => {
+                    let items = (0..self.rng.below(4)).map(|_| self.expr(Ty::Bool, d)).collect();
+                    if self.rng.chance(50) { ExprKind::And(items) } else { ExprKind::Or(items) }
+                },
+                3 | 4 => {
+                    let ops = [Op2::Greater, Op2::GreaterEqual, Op2::Less, Op2::LessEqual, Op2::Equal];
+                    let op = ops[self.rng.below(ops.len())].clone();
+                    ExprKind::BinOp(op, Box::new(self.expr(Ty::Num, d)), Box::new(self.expr(Ty::Num, d)))
+                },
+                5 => {
+                    // equality on booleans and heap values
+                    let t = self.any_ty();
+                    let t = if t == Ty::Num { Ty::Bool } else { t };
+                    ExprKind::BinOp(Op2::Equal, Box::new(self.expr(t, d)), Box::new(self.expr(t, d)))
+                },
+                6 => {
+                    let op = if self.rng.chance(50) { Op1::IsNum } else { Op1::IsBool };
+                    let t = self.any_ty();
+                    ExprKind::UnOp(op, Box::new(self.expr(t, d)))
+                },
+                _ => return self.leaf(ty),
+            },
+            Ty::Tuple(len) => ExprKind::Tuple((0..len).map(|_| self.expr(Ty::Num, d)).collect()),
+            Ty::Fun(arity) => self.lambda(arity, d),
+        };
+        mk(kind)
+    }
+
+    fn leaf(&mut self, ty: Ty) -> Expr {
+        if self.rng.chance(50) {
+            if let Some(name) = self.pick_var(|v| v.ty == ty) {
+                return id(&name);
+            }
+        }
+        match ty {
+            Ty::Num => {
+                // occasionally close to the bounds, to overflow
+                if self.rng.chance(5) {
+                    num(if self.rng.chance(50) { types::GREATEST_VAL - self.rng.range(0, 2) } else { types::LEAST_VAL + self.rng.range(0, 2) })
+                } else {
+                    num(self.rng.range(-10, 10))
+                }
+            },
+            Ty::Bool => mk(ExprKind::Boolean(self.rng.chance(50))),
+            Ty::Tuple(len) => mk(ExprKind::Tuple((0..len).map(|_| num(self.rng.range(-10, 10))).collect())),
+            Ty::Fun(arity) => {
+                let funs: Vec<String> = self.funs.iter().filter(|f| !f.recursive && f.arity == arity).map(|f| f.name.clone()).collect();
+                if !funs.is_empty() && self.rng.chance(50) {
+                    id(&funs[self.rng.below(funs.len())])
+                } else {
+                    mk(self.lambda(arity, 0))
+                }
+            },
+        }
+    }
+
+    fn let_expr(&mut self, ty: Ty, d: usize) -> ExprKind {
+        let scope = self.vars.len();
+        let mut binds: Vec<(String, Expr)> = Vec::new();
+        for _ in 0..1 + self.rng.below(2) {
+            let bty = self.any_ty();
+            let val = self.expr(bty, d);
+            // reuse the name of a variable in scope now and then to test shadowing
+            let shadow = self.pick_var(|v| v.settable).filter(|name| !binds.iter().any(|(n, _)| n == name));
+            let name = match shadow {
+                Some(name) if self.rng.chance(15) => name,
+                _ => self.fresh(),
+            };
+            self.vars.push(Var { name: name.clone(), ty: bty, settable: true });
+            binds.push((name, val));
+        }
+        let body = self.expr(ty, d);
+        self.vars.truncate(scope);
+        ExprKind::Let(binds, Box::new(body))
+    }
+
+    // (let ((i n)) (loop (if (< i 1) (break e) (block ... (set! i (sub1 i))))))
+    fn loop_expr(&mut self, d: usize) -> ExprKind {
+        let counter = self.fresh();
+        let start = num(self.rng.range(0, 4));
+        let in_loop = std::mem::replace(&mut self.in_loop, true);
+        self.vars.push(Var { name: counter.clone(), ty: Ty::Num, settable: false });
+
+        let done = mk(ExprKind::Break(Box::new(self.expr(Ty::Num, d))));
+        let mut items: Vec<Expr> = (0..1 + self.rng.below(2)).map(|_| { let t = self.any_ty(); self.expr(t, d) }).collect();
+        let step = mk(ExprKind::UnOp(Op1::Sub1, Box::new(id(&counter))));
+        items.push(mk(ExprKind::Set(counter.clone(), Box::new(step))));
+        let test = mk(ExprKind::BinOp(Op2::Less, Box::new(id(&counter)), Box::new(num(1))));
+        let body = mk(ExprKind::If(Box::new(test), Box::new(done), Box::new(mk(ExprKind::Block(items)))));
+
+        self.vars.pop();
+        self.in_loop = in_loop;
+        ExprKind::Let(vec![(counter, start)], Box::new(mk(ExprKind::Loop(Box::new(body)))))
+    }
+
+    fn lambda(&mut self, arity: usize, d: usize) -> ExprKind {
+        let params: Vec<String> = (0..arity).map(|_| self.fresh()).collect();
+        let scope = self.vars.len();
+        let in_loop = std::mem::replace(&mut self.in_loop, false);
+        for p in &params {
+            self.vars.push(Var { name: p.clone(), ty: Ty::Num, settable: true });
+        }
+        let body = self.expr(Ty::Num, d);
+        self.vars.truncate(scope);
+        self.in_loop = in_loop;
+        ExprKind::Lambda(params, Box::new(body))
+    }
+
+    // direct call to a top-level function, if there is one to call
+    fn call(&mut self, d: usize) -> Option<ExprKind> {
+        let mut targets: Vec<(String, usize, Option<Expr>)> = self.funs.iter().map(|f| {
+            let fuel = if f.recursive { Some(num(self.rng.range(0, 3))) } else { None };
+            (f.name.clone(), f.arity, fuel)
+        }).collect();
+        if let Some((name, arity, fuel)) = &self.recursion {
+            let step = mk(ExprKind::UnOp(Op1::Sub1, Box::new(id(fuel))));
+            targets.push((name.clone(), *arity, Some(step)));
+        }
+        if targets.is_empty() {
+            return None;
+        }
+        let (name, arity, fuel) = targets.swap_remove(self.rng.below(targets.len()));
+        let mut args: Vec<Expr> = fuel.into_iter().collect();
+        while args.len() < arity {
+            args.push(self.expr(Ty::Num, d));
+        }
+        Some(ExprKind::Call(name, args))
+    }
+
+    // call through a closure
+    fn app(&mut self, arity: usize, d: usize) -> ExprKind {
+        let mut f = self.expr(Ty::Fun(arity), d);
+        // (f0 ...) would be read back as a direct call, and (input ...) does not parse
+        if let ExprKind::Id(name) = &f.kind {
+            if name == "input" || self.funs.iter().any(|fun| &fun.name == name) {
+                f = mk(ExprKind::Block(vec![f]));
+            }
+        }
+        let args = (0..arity).map(|_| self.expr(Ty::Num, d)).collect();
+        ExprKind::App(Box::new(f), args)
+    }
+}
+
+// Repeatedly replaces the program with a smaller variant for which `fails` still holds,
+// until no single step keeps it failing. Variants that do not pass check_program are skipped.
+pub fn shrink(p: &Program, mut fails: impl FnMut(&Program) -> bool) -> Program {
+    let mut best = p.clone();
+    'search: loop {
+        for candidate in candidates(&best) {
+            if check::check_program(&candidate).is_empty() && fails(&candidate) {
+                best = candidate;
+                continue 'search;
+            }
+        }
+        return best;
+    }
+}
+
+// programs one step smaller than p, roughly largest steps first
+fn candidates(p: &Program) -> Vec<Program> {
+    let mut out = Vec::new();
+    for (i, def) in p.defs.iter().enumerate() {
+        let Definition::Fun(name, ..) = def;
+        let mut smaller = p.clone();
+        smaller.defs.remove(i);
+        smaller.func_list.remove(name);
+        out.push(smaller);
+    }
+    for main in variants(&p.main) {
+        out.push(Program { main, ..p.clone() });
+    }
+    for (i, def) in p.defs.iter().enumerate() {
+        let Definition::Fun(name, params, body, span) = def;
+        for body in variants(body) {
+            let mut smaller = p.clone();
+            smaller.defs[i] = Definition::Fun(name.clone(), params.clone(), body, *span);
+            out.push(smaller);
+        }
+    }
+    out
+}
+
+// expressions one step smaller than e: e replaced by 0 or by one of its children, one item
+// dropped from a list, or the same change made somewhere inside
+fn variants(e: &Expr) -> Vec<Expr> {
+    let mut out = Vec::new();
+    if !matches!(e.kind, ExprKind::Number(_) | ExprKind::Boolean(_)) {
+        out.push(Expr::new(ExprKind::Number(0), e.span));
+    }
+    for child in children(e) {
+        out.push(child.clone());
+    }
+
+    let dropped = |kind: &dyn Fn(Vec<Expr>) -> ExprKind, items: &[Expr], min: usize| -> Vec<Expr> {
+        if items.len() <= min {
+            return Vec::new();
+        }
+        (0..items.len()).map(|i| {
+            let mut rest = items.to_vec();
+            rest.remove(i);
+            Expr::new(kind(rest), e.span)
+        }).collect()
+    };
+    match &e.kind {
+        ExprKind::Block(es) => out.extend(dropped(&ExprKind::Block, es, 1)),
+        ExprKind::Tuple(es) => out.extend(dropped(&ExprKind::Tuple, es, 1)),
+        ExprKind::And(es) => out.extend(dropped(&ExprKind::And, es, 0)),
+        ExprKind::Or(es) => out.extend(dropped(&ExprKind::Or, es, 0)),
+        ExprKind::Let(binds, body) if binds.len() > 1 => {
+            for i in 0..binds.len() {
+                let mut rest = binds.clone();
+                rest.remove(i);
+                out.push(Expr::new(ExprKind::Let(rest, body.clone()), e.span));
+            }
+        },
+        _ => (),
+    }
+
+    for (i, child) in children(e).into_iter().enumerate() {
+        for smaller in variants(child) {
+            let mut copy = e.clone();
+            *children_mut(&mut copy).swap_remove(i) = smaller;
+            out.push(copy);
+        }
+    }
+    out
+}
+
+fn children(e: &Expr) -> Vec<&Expr> {
+    match &e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch
+        | ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => Vec::new(),
+        ExprKind::Let(binds, body) => binds.iter().map(|(_, val)| val).chain([body.as_ref()]).collect(),
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) | ExprKind::Set(_, e1)
+        | ExprKind::Lambda(_, e1) => vec![e1],
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => vec![e1, e2],
+        ExprKind::If(cond, thn, els) => vec![cond, thn, els],
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es)
+        | ExprKind::Call(_, es) => es.iter().collect(),
+        ExprKind::App(f, args) => [f.as_ref()].into_iter().chain(args).collect(),
+    }
+}
+
+fn children_mut(e: &mut Expr) -> Vec<&mut Expr> {
+    match &mut e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch
+        | ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => Vec::new(),
+        ExprKind::Let(binds, body) => binds.iter_mut().map(|(_, val)| val).chain([body.as_mut()]).collect(),
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) | ExprKind::Set(_, e1)
+        | ExprKind::Lambda(_, e1) => vec![e1],
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => vec![e1, e2],
+        ExprKind::If(cond, thn, els) => vec![cond, thn, els],
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es)
+        | ExprKind::Call(_, es) => es.iter_mut().collect(),
+        ExprKind::App(f, args) => [f.as_mut()].into_iter().chain(args).collect(),
+    }
+}
@@ -0,0 +1,222 @@
+use super::types;
+use super::callgraph;
+use super::closure;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use callgraph::CallGraph;
+
+use im::{HashMap,HashSet,OrdSet};
+
+// Inlining of small functions at their call sites, on a checked program before constant
+// folding, so constant arguments fold through the inlined bodies. A call (f a1 .. an)
+// becomes (let ((x1' a1) .. (xn' an)) body), where the parameters are renamed to names
+// used nowhere in the program: the arguments are still evaluated once and in order, and
+// the body cannot see the caller's variables. Functions on a cycle of the call graph are
+// never inlined. The calls in a body are inlined before the body itself is, so the size
+// compared with the threshold is that of what actually gets copied.
+
+// the largest body, in expression nodes, that is inlined at each optimization level
+pub fn threshold(level: u32) -> usize {
+    match level {
+        0 => 0,
+        1 => 12,
+        _ => 40,
+    }
+}
+
+struct Inliner<'a> {
+    defs: HashMap<String, (&'a Vec<String>, &'a Expr)>,
+    recursive: OrdSet<String>,
+    threshold: usize,
+    // the bodies of the functions with their own calls inlined, as they are needed
+    inlined: HashMap<String, Expr>,
+    // every variable name in the program, and the ones made up here
+    used: HashSet<String>,
+}
+
+pub fn program(p: &Program, threshold: usize) -> Program {
+    if threshold == 0 {
+        return p.clone();
+    }
+    let mut used = HashSet::new();
+    let mut defs = HashMap::new();
+    for def in &p.defs {
+        let Definition::Fun(name, params, body, _) = def;
+        used.extend(params.iter().cloned());
+        names(body, &mut used);
+        defs.insert(name.clone(), (params, body));
+    }
+    names(&p.main, &mut used);
+    let recursive = CallGraph::new(p).recursive();
+    let mut inl = Inliner { defs, recursive, threshold, inlined: HashMap::new(), used };
+
+    let defs = p.defs.iter().map(|def| {
+        let Definition::Fun(name, params, _, span) = def;
+        Definition::Fun(name.clone(), params.clone(), inl.body(name), *span)
+    }).collect();
+    let main = inl.expr(&p.main, &HashSet::unit(String::from("input")));
+    Program { defs, main, func_list: p.func_list.clone() }
+}
+
+// adds every variable e binds or refers to
+fn names(e: &Expr, out: &mut HashSet<String>) {
+    match &e.kind {
+        ExprKind::Id(name) => {
+            out.insert(name.clone());
+        },
+        ExprKind::Let(binds, _) => out.extend(binds.iter().map(|(name, _)| name.clone())),
+        ExprKind::Lambda(params, _) => out.extend(params.iter().cloned()),
+        _ => (),
+    }
+    children(e).into_iter().for_each(|e| names(e, out));
+}
+
+fn children(e: &Expr) -> Vec<&Expr> {
+    match &e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => Vec::new(),
+        ExprKind::UnOp(_, e1) | ExprKind::Loop(e1) | ExprKind::Break(e1) | ExprKind::Set(_, e1) |
+        ExprKind::Lambda(_, e1) => vec![e1],
+        ExprKind::BinOp(_, e1, e2) | ExprKind::Index(e1, e2) => vec![e1, e2],
+        ExprKind::If(cond, thn, els) => vec![cond, thn, els],
+        ExprKind::Let(binds, body) => binds.iter().map(|(_, val)| val).chain([&**body]).collect(),
+        ExprKind::Block(es) | ExprKind::And(es) | ExprKind::Or(es) | ExprKind::Tuple(es) | ExprKind::Call(_, es) =>
+            es.iter().collect(),
+        ExprKind::App(f, args) => [&**f].into_iter().chain(args).collect(),
+    }
+}
+
+fn size(e: &Expr) -> usize {
+    1 + children(e).into_iter().map(size).sum::<usize>()
+}
+
+// e with the free variables in `renames` renamed; function names in call position are
+// not variables
+fn rename(e: &Expr, renames: &HashMap<String, String>) -> Expr {
+    let all = |es: &[Expr]| es.iter().map(|e| rename(e, renames)).collect();
+    let kind = match &e.kind {
+        ExprKind::Id(name) => ExprKind::Id(renames.get(name).unwrap_or(name).clone()),
+        ExprKind::Set(name, val) => ExprKind::Set(renames.get(name).unwrap_or(name).clone(), Box::new(rename(val, renames))),
+        ExprKind::Let(binds, body) => {
+            let mut renames = renames.clone();
+            let mut nbinds = Vec::new();
+            for (name, val) in binds {
+                nbinds.push((name.clone(), rename(val, &renames)));
+                renames.remove(name);
+            }
+            ExprKind::Let(nbinds, Box::new(rename(body, &renames)))
+        },
+        ExprKind::Lambda(params, body) => {
+            let renames = params.iter().fold(renames.clone(), |renames, param| renames.without(param));
+            ExprKind::Lambda(params.clone(), Box::new(rename(body, &renames)))
+        },
+        ExprKind::UnOp(op, e1) => ExprKind::UnOp(op.clone(), Box::new(rename(e1, renames))),
+        ExprKind::BinOp(op, e1, e2) => ExprKind::BinOp(op.clone(), Box::new(rename(e1, renames)), Box::new(rename(e2, renames))),
+        ExprKind::If(cond, thn, els) =>
+            ExprKind::If(Box::new(rename(cond, renames)), Box::new(rename(thn, renames)), Box::new(rename(els, renames))),
+        ExprKind::Loop(body) => ExprKind::Loop(Box::new(rename(body, renames))),
+        ExprKind::Break(e1) => ExprKind::Break(Box::new(rename(e1, renames))),
+        ExprKind::Block(es) => ExprKind::Block(all(es)),
+        ExprKind::And(es) => ExprKind::And(all(es)),
+        ExprKind::Or(es) => ExprKind::Or(all(es)),
+        ExprKind::Tuple(es) => ExprKind::Tuple(all(es)),
+        ExprKind::Call(name, args) => ExprKind::Call(name.clone(), all(args)),
+        ExprKind::Index(e1, e2) => ExprKind::Index(Box::new(rename(e1, renames)), Box::new(rename(e2, renames))),
+        ExprKind::App(f, args) => ExprKind::App(Box::new(rename(f, renames)), all(args)),
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::NoMatch |
+        ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => e.kind.clone(),
+    };
+    Expr::new(kind, e.span)
+}
+
+impl Inliner<'_> {
+    // `base` followed by the first .1, .2 ... not in use
+    fn fresh(&mut self, base: &str) -> String {
+        let mut n = 1;
+        while self.used.contains(&format!("{base}.{n}")) {
+            n += 1;
+        }
+        let name = format!("{base}.{n}");
+        self.used.insert(name.clone());
+        name
+    }
+
+    fn body(&mut self, name: &str) -> Expr {
+        if let Some(body) = self.inlined.get(name) {
+            return body.clone();
+        }
+        let (params, body) = self.defs[name];
+        let body = self.expr(body, &params.iter().cloned().collect());
+        self.inlined.insert(String::from(name), body.clone());
+        body
+    }
+
+    // the body to put in place of a call to `name` where the variables in `bound` are in
+    // scope, if there is one
+    fn inline(&mut self, name: &str, bound: &HashSet<String>) -> Option<(Vec<String>, Expr)> {
+        if self.recursive.contains(name) {
+            return None;
+        }
+        let (params, _) = self.defs[name];
+        let body = self.body(name);
+        if size(&body) > self.threshold {
+            return None;
+        }
+        // the body can still refer to other functions as values, which a local variable
+        // of the caller would shadow
+        let free = closure::free_vars(&body, &params.iter().cloned().collect());
+        if free.iter().any(|name| bound.contains(name)) {
+            return None;
+        }
+        let fresh: Vec<String> = params.iter().map(|param| self.fresh(param)).collect();
+        let renames = params.iter().cloned().zip(fresh.iter().cloned()).collect();
+        Some((fresh, rename(&body, &renames)))
+    }
+
+    // `bound` holds the local variables in scope
+    fn expr(&mut self, e: &Expr, bound: &HashSet<String>) -> Expr {
+        let kind = match &e.kind {
+            ExprKind::Call(name, args) => {
+                let args: Vec<Expr> = args.iter().map(|arg| self.expr(arg, bound)).collect();
+                match self.inline(name, bound) {
+                    Some((_, body)) if args.is_empty() => return body,
+                    Some((params, body)) => ExprKind::Let(params.into_iter().zip(args).collect(), Box::new(body)),
+                    None => ExprKind::Call(name.clone(), args),
+                }
+            },
+            ExprKind::Let(binds, body) => {
+                let mut nbound = bound.clone();
+                let mut nbinds = Vec::new();
+                for (name, val) in binds {
+                    nbinds.push((name.clone(), self.expr(val, &nbound)));
+                    nbound.insert(name.clone());
+                }
+                ExprKind::Let(nbinds, Box::new(self.expr(body, &nbound)))
+            },
+            ExprKind::Lambda(params, body) => {
+                let nbound = params.iter().cloned().collect::<HashSet<String>>().union(bound.clone());
+                ExprKind::Lambda(params.clone(), Box::new(self.expr(body, &nbound)))
+            },
+            ExprKind::UnOp(op, e1) => ExprKind::UnOp(op.clone(), Box::new(self.expr(e1, bound))),
+            ExprKind::BinOp(op, e1, e2) => ExprKind::BinOp(op.clone(), Box::new(self.expr(e1, bound)), Box::new(self.expr(e2, bound))),
+            ExprKind::If(cond, thn, els) =>
+                ExprKind::If(Box::new(self.expr(cond, bound)), Box::new(self.expr(thn, bound)), Box::new(self.expr(els, bound))),
+            ExprKind::Loop(body) => ExprKind::Loop(Box::new(self.expr(body, bound))),
+            ExprKind::Break(e1) => ExprKind::Break(Box::new(self.expr(e1, bound))),
+            ExprKind::Set(name, val) => ExprKind::Set(name.clone(), Box::new(self.expr(val, bound))),
+            ExprKind::Block(es) => ExprKind::Block(es.iter().map(|e| self.expr(e, bound)).collect()),
+            ExprKind::And(es) => ExprKind::And(es.iter().map(|e| self.expr(e, bound)).collect()),
+            ExprKind::Or(es) => ExprKind::Or(es.iter().map(|e| self.expr(e, bound)).collect()),
+            ExprKind::Tuple(es) => ExprKind::Tuple(es.iter().map(|e| self.expr(e, bound)).collect()),
+            ExprKind::Index(e1, e2) => ExprKind::Index(Box::new(self.expr(e1, bound)), Box::new(self.expr(e2, bound))),
+            ExprKind::App(f, args) =>
+                ExprKind::App(Box::new(self.expr(f, bound)), args.iter().map(|e| self.expr(e, bound)).collect()),
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Id(_) | ExprKind::NoMatch |
+            ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) => e.kind.clone(),
+        };
+        Expr::new(kind, e.span)
+    }
+}
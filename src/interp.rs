@@ -0,0 +1,382 @@
+use super::types;
+
+use types::Expr;
+use types::ExprKind;
+use types::Program;
+use types::Definition;
+use types::Op1;
+use types::Op2;
+
+use im::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+// Reference interpreter. It walks the checked program (before closure conversion) and
+// follows the generated code step for step: the order operands are evaluated in, when
+// each one is type checked, 63-bit overflow and the error every failure jumps to. Values
+// print the same way as snek_str in runtime/gc.rs. The heap has no limit, so running
+// out of memory is never reported.
+
+// every snek call that is not a tail call recurses on the host stack
+const STACK_SIZE: usize = 256 << 20;
+
+#[derive(Clone)]
+enum Value<'p> {
+    Num(i64),
+    Bool(bool),
+    Tuple(Rc<Vec<Value<'p>>>),
+    Closure(Rc<Closure<'p>>),
+}
+
+// Lambdas capture the variables in scope by value, like MakeClosure does. A top-level
+// function used as a value is a closure with nothing captured.
+#[derive(Clone)]
+struct Closure<'p> {
+    params: &'p [String],
+    body: &'p Expr,
+    env: Vec<(&'p str, Value<'p>)>,
+}
+
+// a runtime error code, see the *_ERROR_CODE constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeError(pub u64);
+
+enum Flow<'p> {
+    Error(RuntimeError),
+    Break(Value<'p>),
+    // a call in tail position, made by the enclosing call_closure so the host stack stays flat
+    TailCall(Rc<Closure<'p>>, Vec<Value<'p>>),
+}
+
+type Eval<'p> = Result<Value<'p>, Flow<'p>>;
+
+struct Interp<'p, 'w> {
+    defs: HashMap<&'p str, Rc<Closure<'p>>>,
+    out: &'w mut dyn Write,
+}
+
+// same text as snek_error in runtime/start.rs
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self.0 {
+            types::OVERFLOW_ERROR_CODE => "overflow",
+            types::INVALID_ARGUMENT_ERROR_CODE => "invalid argument",
+            types::INDEX_ERROR_CODE => "index out of bounds or not a tuple",
+            types::OUT_OF_MEMORY_ERROR_CODE => "out of memory",
+            types::NOT_A_FUNCTION_ERROR_CODE => "called a value that is not a function",
+            types::ARITY_ERROR_CODE => "wrong number of arguments",
+            types::DIVIDE_BY_ZERO_ERROR_CODE => "division by zero",
+            types::NO_MATCH_ERROR_CODE => "no matching clause",
+            _ => "",
+        };
+        write!(f, "an error ocurred - {msg}")
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Tuple(items) => {
+                write!(f, "(tuple")?;
+                for item in items.iter() {
+                    write!(f, " {item}")?;
+                }
+                write!(f, ")")
+            },
+            Value::Closure(_) => write!(f, "<function>"),
+        }
+    }
+}
+
+// parses the input argument the same way as the runtime, returning the tagged word
+pub fn parse_input(s: &str) -> Option<u64> {
+    match s {
+        "true" => Some(types::TRUE_VAL),
+        "false" => Some(types::FALSE_VAL),
+        _ => s.parse::<i64>().ok().map(|val| (val as u64) << 1),
+    }
+}
+
+// Runs a program that passed check_program with the given tagged input. Everything the
+// compiled program would print to stdout, including the final value, goes to `out`.
+pub fn run(p: &Program, input: u64, out: &mut (dyn Write + Send)) -> Result<(), RuntimeError> {
+    std::thread::scope(|s| {
+        let handle = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(s, move || run_main(p, input, out))
+            .expect("could not start the interpreter thread");
+        handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err))
+    })
+}
+
+fn run_main(p: &Program, input: u64, out: &mut dyn Write) -> Result<(), RuntimeError> {
+    let mut defs = HashMap::new();
+    for def in &p.defs {
+        let Definition::Fun(name, params, body, _) = def;
+        defs.insert(name.as_str(), Rc::new(Closure { params, body, env: Vec::new() }));
+    }
+    let mut interp = Interp { defs, out };
+
+    let input = if input & 1 == 0 { Value::Num(input as i64 >> 1) } else { Value::Bool(input == types::TRUE_VAL) };
+    match interp.eval(&p.main, &mut vec![("input", input)], false) {
+        Ok(v) => {
+            interp.print(&v);
+            Ok(())
+        },
+        Err(Flow::Error(err)) => Err(err),
+        Err(_) => panic!("Error - break or tail call escaped the main expression."),
+    }
+}
+
+fn error<'p>(code: u64) -> Flow<'p> {
+    Flow::Error(RuntimeError(code))
+}
+
+fn num(v: Value) -> Result<i64, Flow> {
+    match v {
+        Value::Num(n) => Ok(n),
+        _ => Err(error(types::INVALID_ARGUMENT_ERROR_CODE)),
+    }
+}
+
+fn boolean(v: Value) -> Result<bool, Flow> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        _ => Err(error(types::INVALID_ARGUMENT_ERROR_CODE)),
+    }
+}
+
+// numbers are 63 bits once tagged
+fn checked<'p>(n: i64) -> Eval<'p> {
+    if (types::LEAST_VAL..=types::GREATEST_VAL).contains(&n) {
+        Ok(Value::Num(n))
+    } else {
+        Err(error(types::OVERFLOW_ERROR_CODE))
+    }
+}
+
+// floored quotient and remainder, so the remainder takes the sign of the divisor
+pub fn floor_div(a: i64, b: i64) -> (i64, i64) {
+    let (mut q, mut r) = (a / b, a % b);
+    if r != 0 && (r ^ b) < 0 {
+        q -= 1;
+        r += b;
+    }
+    (q, r)
+}
+
+impl<'p> Interp<'p, '_> {
+    fn print(&mut self, v: &Value) {
+        writeln!(self.out, "{v}").expect("could not write the program output");
+    }
+
+    fn def(&self, name: &str) -> &Rc<Closure<'p>> {
+        match self.defs.get(name) {
+            Some(def) => def,
+            None => panic!("Error - {name} is unbound, programs must be checked before they are interpreted."),
+        }
+    }
+
+    // `tail` mirrors the compiler: calls in tail position do not grow the stack
+    fn eval(&mut self, e: &'p Expr, env: &mut Vec<(&'p str, Value<'p>)>, tail: bool) -> Eval<'p> {
+        match &e.kind {
+            ExprKind::Number(n) => Ok(Value::Num(*n as i64)),
+            ExprKind::Boolean(b) => Ok(Value::Bool(*b)),
+            ExprKind::Id(name) => match env.iter().rev().find(|(n, _)| *n == name.as_str()) {
+                Some((_, v)) => Ok(v.clone()),
+                // every use of a function as a value allocates a new closure
+                None => Ok(Value::Closure(Rc::new(Closure::clone(self.def(name))))),
+            },
+            ExprKind::Let(binds, body) => {
+                // a break or error leaving the body must still drop its bindings
+                let depth = env.len();
+                let result = self.eval_let(binds, body, env, tail);
+                env.truncate(depth);
+                result
+            },
+            ExprKind::Set(name, val) => {
+                let v = self.eval(val, env, false)?;
+                match env.iter_mut().rev().find(|(n, _)| *n == name.as_str()) {
+                    Some(slot) => slot.1 = v.clone(),
+                    None => panic!("Error - {name} is unbound, programs must be checked before they are interpreted."),
+                }
+                Ok(v)
+            },
+            ExprKind::UnOp(op, e1) => {
+                let v = self.eval(e1, env, false)?;
+                match op {
+                    Op1::Add1 => checked(num(v)? + 1),
+                    Op1::Sub1 => checked(num(v)? - 1),
+                    Op1::IsNum => Ok(Value::Bool(matches!(v, Value::Num(_)))),
+                    Op1::IsBool => Ok(Value::Bool(matches!(v, Value::Bool(_)))),
+                    Op1::Not => Ok(Value::Bool(!boolean(v)?)),
+                    Op1::Print => {
+                        self.print(&v);
+                        Ok(v)
+                    },
+                }
+            },
+            ExprKind::BinOp(op, e1, e2) => self.eval_binop(op, e1, e2, env),
+            ExprKind::If(cond, thn, els) => {
+                // anything other than false takes the first branch
+                if let Value::Bool(false) = self.eval(cond, env, false)? {
+                    self.eval(els, env, tail)
+                } else {
+                    self.eval(thn, env, tail)
+                }
+            },
+            ExprKind::Loop(body) => loop {
+                match self.eval(body, env, false) {
+                    Ok(_) => (),
+                    Err(Flow::Break(v)) => return Ok(v),
+                    Err(flow) => return Err(flow),
+                }
+            },
+            ExprKind::Break(e1) => Err(Flow::Break(self.eval(e1, env, false)?)),
+            ExprKind::Block(es) => {
+                let (last, init) = es.split_last().expect("Error - empty block");
+                for item in init {
+                    self.eval(item, env, false)?;
+                }
+                self.eval(last, env, tail)
+            },
+            ExprKind::And(es) | ExprKind::Or(es) => {
+                let short = matches!(e.kind, ExprKind::Or(_));
+                for item in es {
+                    if boolean(self.eval(item, env, false)?)? == short {
+                        return Ok(Value::Bool(short));
+                    }
+                }
+                Ok(Value::Bool(!short))
+            },
+            ExprKind::NoMatch => Err(error(types::NO_MATCH_ERROR_CODE)),
+            ExprKind::Call(name, args) => {
+                let callee = self.def(name).clone();
+                let args = self.eval_all(args, env)?;
+                self.call(callee, args, tail)
+            },
+            ExprKind::App(f, args) => {
+                let f = self.eval(f, env, false)?;
+                let args = self.eval_all(args, env)?;
+                let callee = match f {
+                    Value::Closure(c) => c,
+                    _ => return Err(error(types::NOT_A_FUNCTION_ERROR_CODE)),
+                };
+                if callee.params.len() != args.len() {
+                    return Err(error(types::ARITY_ERROR_CODE));
+                }
+                self.call(callee, args, tail)
+            },
+            ExprKind::Lambda(params, body) => Ok(Value::Closure(Rc::new(Closure { params, body, env: env.clone() }))),
+            ExprKind::Tuple(es) => Ok(Value::Tuple(Rc::new(self.eval_all(es, env)?))),
+            ExprKind::Index(tuple, idx) => {
+                let tuple = self.eval(tuple, env, false)?;
+                let idx = num(self.eval(idx, env, false)?)?;
+                match tuple {
+                    Value::Tuple(items) if idx >= 0 && (idx as usize) < items.len() => Ok(items[idx as usize].clone()),
+                    _ => Err(error(types::INDEX_ERROR_CODE)),
+                }
+            },
+            ExprKind::MakeClosure(..) | ExprKind::ClosureVar(_) =>
+                panic!("Error - the interpreter runs on programs before closure conversion."),
+        }
+    }
+
+    fn eval_let(&mut self, binds: &'p [(String, Expr)], body: &'p Expr, env: &mut Vec<(&'p str, Value<'p>)>, tail: bool) -> Eval<'p> {
+        for (name, val) in binds {
+            let v = self.eval(val, env, false)?;
+            env.push((name, v));
+        }
+        self.eval(body, env, tail)
+    }
+
+    fn eval_all(&mut self, es: &'p [Expr], env: &mut Vec<(&'p str, Value<'p>)>) -> Result<Vec<Value<'p>>, Flow<'p>> {
+        es.iter().map(|item| self.eval(item, env, false)).collect()
+    }
+
+    fn eval_binop(&mut self, op: &Op2, e1: &'p Expr, e2: &'p Expr, env: &mut Vec<(&'p str, Value<'p>)>) -> Eval<'p> {
+        match op {
+            // each operand is checked right after it is evaluated; minus starts with e2
+            Op2::Plus => {
+                let a = num(self.eval(e1, env, false)?)?;
+                let b = num(self.eval(e2, env, false)?)?;
+                checked(a + b)
+            },
+            Op2::Minus => {
+                let b = num(self.eval(e2, env, false)?)?;
+                let a = num(self.eval(e1, env, false)?)?;
+                checked(a - b)
+            },
+            Op2::Times => {
+                let a = num(self.eval(e1, env, false)?)?;
+                let b = num(self.eval(e2, env, false)?)?;
+                a.checked_mul(b).map_or(Err(error(types::OVERFLOW_ERROR_CODE)), checked)
+            },
+            Op2::Divide | Op2::Modulo => {
+                let a = num(self.eval(e1, env, false)?)?;
+                let b = num(self.eval(e2, env, false)?)?;
+                if b == 0 {
+                    return Err(error(types::DIVIDE_BY_ZERO_ERROR_CODE));
+                }
+                let (q, r) = floor_div(a, b);
+                if let Op2::Divide = op { checked(q) } else { Ok(Value::Num(r)) }
+            },
+            // only a number compared with a non-number is an error; heap values are
+            // equal when they are the same object
+            Op2::Equal => {
+                let v2 = self.eval(e2, env, false)?;
+                let v1 = self.eval(e1, env, false)?;
+                let eq = match (&v1, &v2) {
+                    (Value::Num(a), Value::Num(b)) => a == b,
+                    (Value::Num(_), _) | (_, Value::Num(_)) => return Err(error(types::INVALID_ARGUMENT_ERROR_CODE)),
+                    (Value::Bool(a), Value::Bool(b)) => a == b,
+                    (Value::Tuple(a), Value::Tuple(b)) => Rc::ptr_eq(a, b),
+                    (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+                    _ => false,
+                };
+                Ok(Value::Bool(eq))
+            },
+            // comparisons check both operands once they have been evaluated
+            Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+                let v1 = self.eval(e1, env, false)?;
+                let v2 = self.eval(e2, env, false)?;
+                let (a, b) = match (v1, v2) {
+                    (Value::Num(a), Value::Num(b)) => (a, b),
+                    _ => return Err(error(types::INVALID_ARGUMENT_ERROR_CODE)),
+                };
+                Ok(Value::Bool(match op {
+                    Op2::Greater => a > b,
+                    Op2::GreaterEqual => a >= b,
+                    Op2::Less => a < b,
+                    _ => a <= b,
+                }))
+            },
+        }
+    }
+
+    fn call(&mut self, callee: Rc<Closure<'p>>, args: Vec<Value<'p>>, tail: bool) -> Eval<'p> {
+        if tail {
+            Err(Flow::TailCall(callee, args))
+        } else {
+            self.call_closure(callee, args)
+        }
+    }
+
+    fn call_closure(&mut self, mut callee: Rc<Closure<'p>>, mut args: Vec<Value<'p>>) -> Eval<'p> {
+        loop {
+            // captured variables are copied into each call, so set! on them stays local to it
+            let mut env = callee.env.clone();
+            env.extend(callee.params.iter().map(String::as_str).zip(args));
+            match self.eval(callee.body, &mut env, true) {
+                Err(Flow::TailCall(next, next_args)) => {
+                    callee = next;
+                    args = next_args;
+                },
+                result => return result,
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use super::types;
+use super::compiler;
+use super::x86;
+use super::interp::RuntimeError;
+
+use types::Instr;
+use types::Val;
+use types::Reg;
+use types::Program;
+use types::CompileError;
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
+
+#[path = "../runtime/gc.rs"]
+mod gc;
+
+// Runs compiled programs in process, without nasm or a linker. The instructions from
+// `compiler::compile` are encoded by x86.rs into executable memory and called directly.
+// A few stubs are added around them:
+//
+//   jit_entry      saves the callee-saved registers and rsp, then calls our_code_starts_here
+//   snek_error     stores the error code and returns from jit_entry with the saved rsp
+//   snek_print     jumps to jit_print
+//   snek_try_gc    calls jit_try_gc, raising out of memory when it returns null
+//
+// The Rust functions and the words the stubs share with the host live on a writable page
+// after the code, the only symbols left for x86.rs to relocate.
+
+// same as the runtime
+pub const DEFAULT_HEAP_SIZE: usize = 100000;
+
+const STACK_SIZE: usize = 256 << 20;
+
+const PAGE_SIZE: usize = 4096;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+#[cfg(target_os = "macos")]
+const MAP_ANONYMOUS: i32 = 0x1000;
+#[cfg(not(target_os = "macos"))]
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+// the words on the data page, in order
+const DATA_SLOTS: [&str; 4] = ["jit_saved_rsp", "jit_error", "jit_print", "jit_try_gc"];
+
+#[derive(Debug)]
+pub enum JitError {
+    Compile(CompileError),
+    Encode(x86::EncodeError),
+    Io(io::Error),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::Compile(err) => write!(f, "{err}"),
+            JitError::Encode(err) => write!(f, "{err}"),
+            JitError::Io(err) => write!(f, "{err}"),
+            JitError::Runtime(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+thread_local! {
+    // set by run_main for the callbacks from our code
+    static HEAP: Cell<gc::Heap> = const { Cell::new(gc::Heap { start: std::ptr::null_mut(), end: std::ptr::null_mut() }) };
+    static OUT: Cell<Option<*mut (dyn Write + 'static)>> = const { Cell::new(None) };
+}
+
+extern "C" fn jit_print(val: u64) -> u64 {
+    if let Some(out) = OUT.with(Cell::get) {
+        let _ = writeln!(unsafe { &mut *out }, "{}", gc::snek_str(val));
+    }
+    val
+}
+
+unsafe extern "C" fn jit_try_gc(count: u64, heap_ptr: *const u64, curr_rbp: *const u64) -> *mut u64 {
+    let heap = HEAP.with(Cell::get);
+    gc::collect(heap, count, heap_ptr, curr_rbp).unwrap_or(std::ptr::null_mut())
+}
+
+// code and data pages, unmapped when dropped
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    // offset of the data page
+    data: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+// Compiles p and runs it with `input` (a tagged value, see interp::parse_input) and a heap
+// of `heap_size` words, writing what it prints and its final value to `out`.
+pub fn run(p: &Program, input: u64, heap_size: usize, out: &mut (dyn Write + Send)) -> Result<(), JitError> {
+    let mut instrs = compiler::compile(p).map_err(JitError::Compile)?;
+    instrs.extend(stubs());
+    let code = x86::encode(&instrs).map_err(JitError::Encode)?;
+    let mapping = load(&code)?;
+
+    // raw pointers are not Send, so the other thread gets addresses
+    let entry = mapping.ptr as usize + code.labels["jit_entry"];
+    let data = mapping.ptr as usize + mapping.data;
+    std::thread::scope(|s| {
+        let handle = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(s, move || run_main(entry, data, input, heap_size, out))
+            .map_err(JitError::Io)?;
+        handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err))
+    })
+}
+
+fn run_main(entry: usize, data: usize, input: u64, heap_size: usize, out: &mut dyn Write) -> Result<(), JitError> {
+    let mut heap = vec![0u64; heap_size];
+    let start = heap.as_mut_ptr();
+    let end = unsafe { start.add(heap_size) };
+    HEAP.with(|h| h.set(gc::Heap { start, end }));
+    // the callbacks only use `out` while our code runs, before this function returns
+    let out_ptr: *mut (dyn Write + '_) = &mut *out;
+    OUT.with(|o| o.set(Some(unsafe { std::mem::transmute::<*mut (dyn Write + '_), *mut (dyn Write + 'static)>(out_ptr) })));
+
+    let entry: extern "C" fn(u64, *mut u64, *mut u64) -> u64 = unsafe { std::mem::transmute(entry) };
+    let result = entry(input, start, end);
+    OUT.with(|o| o.set(None));
+
+    let error = unsafe { *(data as *const u64).add(slot("jit_error")) };
+    if error != 0 {
+        return Err(JitError::Runtime(RuntimeError(error)));
+    }
+    writeln!(out, "{}", gc::snek_str(result)).map_err(JitError::Io)
+}
+
+fn slot(name: &str) -> usize {
+    DATA_SLOTS.iter().position(|s| *s == name).expect("not a data slot")
+}
+
+// copies the code into fresh pages followed by the data page, fills in the references to
+// the data slots and makes the code executable
+fn load(code: &x86::Code) -> Result<Mapping, JitError> {
+    let data = (code.bytes.len() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let len = data + PAGE_SIZE;
+    let ptr = unsafe { mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+    if ptr as isize == -1 {
+        return Err(JitError::Io(io::Error::last_os_error()));
+    }
+    let mapping = Mapping { ptr, len, data };
+
+    let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+    bytes[..code.bytes.len()].copy_from_slice(&code.bytes);
+    let print: extern "C" fn(u64) -> u64 = jit_print;
+    let try_gc: unsafe extern "C" fn(u64, *const u64, *const u64) -> *mut u64 = jit_try_gc;
+    bytes[data + 8 * slot("jit_print")..][..8].copy_from_slice(&(print as usize as u64).to_le_bytes());
+    bytes[data + 8 * slot("jit_try_gc")..][..8].copy_from_slice(&(try_gc as usize as u64).to_le_bytes());
+
+    for reloc in &code.relocs {
+        let index = DATA_SLOTS.iter().position(|s| *s == reloc.symbol).ok_or_else(||
+            JitError::Encode(x86::EncodeError(format!("undefined symbol {}", reloc.symbol))))?;
+        let rel = (data + 8 * index) as i64 - (reloc.offset as i64 + 4);
+        bytes[reloc.offset..reloc.offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    if unsafe { mprotect(ptr, data, PROT_READ | PROT_EXEC) } != 0 {
+        return Err(JitError::Io(io::Error::last_os_error()));
+    }
+    Ok(mapping)
+}
+
+fn label(name: &str) -> Val {
+    Val::Label(String::from(name))
+}
+
+// the stubs described at the top of the file
+fn stubs() -> Vec<Instr> {
+    let rax = || Val::Reg(Reg::RAX);
+    let rsp = || Val::Reg(Reg::RSP);
+    vec![
+        // called as extern "C" fn(input, heap start, heap end); the extra push keeps the
+        // stack 16-byte aligned at the call
+        Instr::Label(label("jit_entry")),
+        Instr::Push(Val::Reg(Reg::RBX)),
+        Instr::Push(Val::Reg(Reg::RBP)),
+        Instr::Push(Val::Reg(Reg::R14)),
+        Instr::Push(Val::Reg(Reg::R15)),
+        Instr::Push(Val::Imm(0)),
+        Instr::Lea(rax(), label("jit_saved_rsp")),
+        Instr::IMov(Val::RegOffset(Reg::RAX, 0), rsp()),
+        Instr::Call(label("our_code_starts_here")),
+        Instr::Label(label("jit_return")),
+        Instr::IAdd(rsp(), Val::Imm(8)),
+        Instr::Pop(Val::Reg(Reg::R15)),
+        Instr::Pop(Val::Reg(Reg::R14)),
+        Instr::Pop(Val::Reg(Reg::RBP)),
+        Instr::Pop(Val::Reg(Reg::RBX)),
+        Instr::Ret(),
+
+        Instr::Label(label("snek_error")),
+        Instr::Lea(rax(), label("jit_error")),
+        Instr::IMov(Val::RegOffset(Reg::RAX, 0), Val::Reg(Reg::RDI)),
+        Instr::Lea(rax(), label("jit_saved_rsp")),
+        Instr::IMov(rsp(), Val::RegOffset(Reg::RAX, 0)),
+        Instr::Jmp(label("jit_return")),
+
+        Instr::Label(label("snek_print")),
+        Instr::Lea(rax(), label("jit_print")),
+        Instr::Jmp(Val::RegOffset(Reg::RAX, 0)),
+
+        // entered like a call from aligned code, so the stack needs 8 more bytes for the
+        // next call
+        Instr::Label(label("snek_try_gc")),
+        Instr::ISub(rsp(), Val::Imm(8)),
+        Instr::Lea(rax(), label("jit_try_gc")),
+        Instr::Call(Val::RegOffset(Reg::RAX, 0)),
+        Instr::IAdd(rsp(), Val::Imm(8)),
+        Instr::Cmp(rax(), Val::Imm(0)),
+        Instr::JEqual(label("jit_out_of_memory")),
+        Instr::Ret(),
+        Instr::Label(label("jit_out_of_memory")),
+        Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(types::OUT_OF_MEMORY_ERROR_CODE)),
+        Instr::Jmp(label("snek_error")),
+    ]
+}
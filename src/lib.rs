@@ -0,0 +1,56 @@
+pub mod types;
+pub mod reader;
+pub mod parser;
+pub mod compiler;
+pub mod closure;
+pub mod fold;
+pub mod callgraph;
+pub mod inline;
+pub mod anf;
+pub mod cfg;
+pub mod regalloc;
+pub mod peephole;
+pub mod check;
+pub mod interp;
+pub mod fuzz;
+pub mod driver;
+pub mod repl;
+pub mod x86;
+pub mod jit;
+pub mod elf;
+pub mod c;
+
+pub use types::CompileError;
+pub use types::CompileWarning;
+pub use types::ErrorKind;
+pub use types::Program;
+pub use check::check_program;
+pub use compiler::Syntax;
+
+// reads and parses a whole source file; parsing stops at the first syntax error
+pub fn parse_program(src: &str) -> Result<Program, CompileError> {
+    let expr_inp = reader::parse(src)?;
+    parser::parse_program(&expr_inp)
+}
+
+// compiles a parsed program to the full NASM text, including the error handlers that the
+// generated code jumps to
+pub fn compile(p: &Program) -> Result<String, CompileError> {
+    compile_with(p, Syntax::Nasm)
+}
+
+// the same for any assembler
+pub fn compile_with(p: &Program, syntax: Syntax) -> Result<String, CompileError> {
+    let instrs = compiler::compile(p)?;
+    Ok(compiler::program_to_str(&instrs, syntax))
+}
+
+// source text to assembly text, returning the first error found. Use parse_program and
+// check_program directly to get every well-formedness error.
+pub fn compile_source(src: &str) -> Result<String, CompileError> {
+    let p = parse_program(src)?;
+    if let Some(err) = check_program(&p).into_iter().next() {
+        return Err(err);
+    }
+    compile(&p)
+}
@@ -1,53 +1,364 @@
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-mod types;
-mod parser;
-mod compiler;
+use diamondback::CompileError;
+use diamondback::Program;
+use diamondback::Syntax;
+use diamondback::types::Instr;
+use diamondback::driver;
+use diamondback::jit::JitError;
+
+const USAGE: &str = "usage: diamondback [--emit asm|obj|anf|cfg-dot|callgraph] [--syntax nasm|gas] [--opt-level 0|1|2] [--peephole-stats] <in.snek> <out>
+       diamondback --target c [--opt-level 0|1|2] <in.snek> <out.c>
+       diamondback build [--syntax nasm|gas | --assembler builtin | --target c] [--opt-level 0|1|2] [--peephole-stats] <in.snek> [-o <out>]
+       diamondback run [--syntax nasm|gas | --assembler builtin | --target c] [--opt-level 0|1|2] <in.snek> [args...]
+       diamondback --interp <in.snek> [input]
+       diamondback --jit <in.snek> [input [heap_size]]
+       diamondback repl [input]";
+
+// how build and run turn the program into an executable: by writing assembly for nasm or
+// as, by encoding the instructions into an ELF object directly, or by writing C for cc
+enum Backend {
+    Text(Syntax),
+    Builtin,
+    C,
+}
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let in_name = &args[1];
-    let out_name = &args[2];
+    match args.get(1).map(String::as_str) {
+        Some("build") => build_file(&args[2..]),
+        Some("run") => run_file(&args[2..]),
+        Some("repl") if args.len() <= 3 => repl(args.get(2).map_or("false", String::as_str)),
+        Some("--jit") if (3..=5).contains(&args.len()) => jit_file(&args[2], &args[3..]),
+        Some("--interp") if args.len() >= 3 => interp_file(&args[2], args.get(3).map_or("false", String::as_str)),
+        Some(_) => compile_file(&args[1..]),
+        _ => usage(),
+    }
+}
 
-    let mut in_file = File::open(in_name)?;
-    let mut in_contents = String::new();
-    in_file.read_to_string(&mut in_contents)?;
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
 
-    let prog = "(".to_owned() + &in_contents + ")";
-    let parse_res = sexp::parse(&prog);
-    let expr_inp = match parse_res {
-        Ok(val) => val,
-        Err(_) => panic!("Invalid S-Expression.")
-    };
-    let p = parser::parse_program(&expr_inp);
-    let (func_defs, main) = compiler::compile(&p);
-    let asm_program = format!(
-        "
-section .text
-extern snek_error
-extern snek_print
-global our_code_starts_here
-throw_error:
-    push rsp
-    call snek_error
-{func_defs}
-our_code_starts_here:
-    {main}
-    ret
-overflow:
-    mov rdi, {}
-    jmp throw_error
-invalid_arg:
-    mov rdi, {}
-    jmp throw_error
-",
-        types::OVERFLOW_ERROR_CODE, types::INVALID_ARGUMENT_ERROR_CODE
-    );
+// removes `<flag> <value>` from args, where the flag is any of `names`
+fn take_option(args: &mut Vec<String>, names: &[&str]) -> Option<String> {
+    let i = args.iter().position(|arg| names.contains(&arg.as_str()))?;
+    if i + 1 == args.len() {
+        usage();
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+// removes a flag that takes no value from args, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+fn parse_syntax(name: Option<String>) -> Syntax {
+    match name.as_deref() {
+        None | Some("nasm") => Syntax::Nasm,
+        Some("gas") => Syntax::Gas,
+        Some(_) => usage(),
+    }
+}
+
+// the inlining threshold for --opt-level; 0, the default, inlines nothing
+fn take_inline_threshold(args: &mut Vec<String>) -> usize {
+    match take_option(args, &["--opt-level"]).as_deref() {
+        None => 0,
+        Some(level @ ("0" | "1" | "2")) => diamondback::inline::threshold(level.parse().unwrap()),
+        Some(_) => usage(),
+    }
+}
 
+// --assembler nasm is the same as --syntax nasm; the assembler options only go with the
+// default target, x86-64
+fn take_backend(args: &mut Vec<String>) -> Backend {
+    let target = take_option(args, &["--target"]);
+    let assembler = take_option(args, &["--assembler"]);
+    let syntax = take_option(args, &["--syntax"]);
+    match (target.as_deref(), assembler.as_deref(), syntax) {
+        (Some("c"), None, None) => Backend::C,
+        (None | Some("x86-64"), None, syntax) => Backend::Text(parse_syntax(syntax)),
+        (None | Some("x86-64"), Some("nasm"), None) => Backend::Text(Syntax::Nasm),
+        (None | Some("x86-64"), Some("builtin"), None) => Backend::Builtin,
+        _ => usage(),
+    }
+}
+
+// writes the assembly (or with --emit obj, an object file, and with --target c, a C file)
+// for a program, for use with the Makefile; --emit anf writes the intermediate form instead
+// and --emit cfg-dot the control-flow graph of each function for Graphviz, --emit callgraph
+// the call graph. --peephole-stats prints what each peephole rule removed from the x86-64
+// code to stderr
+fn compile_file(args: &[String]) -> std::io::Result<()> {
+    let mut args = args.to_vec();
+    let emit = take_option(&mut args, &["--emit"]);
+    let target = take_option(&mut args, &["--target"]);
+    let syntax = take_option(&mut args, &["--syntax"]);
+    let inline = take_inline_threshold(&mut args);
+    let stats = take_flag(&mut args, "--peephole-stats");
+    let [in_name, out_name] = &args[..] else { usage() };
+    let output = match (target.as_deref(), emit.as_deref()) {
+        (None | Some("x86-64"), None | Some("asm")) => compile_or_exit(in_name, parse_syntax(syntax), inline, stats)?.into_bytes(),
+        (None | Some("x86-64"), Some("obj")) if syntax.is_none() => object_or_exit(in_name, inline, stats)?,
+        _ if stats => usage(),
+        (Some("c"), None) if syntax.is_none() => c_or_exit(in_name, inline)?.into_bytes(),
+        (None, Some("anf")) if syntax.is_none() => anf_or_exit(in_name, inline)?.into_bytes(),
+        (None, Some("cfg-dot")) if syntax.is_none() => cfg_or_exit(in_name, inline)?.into_bytes(),
+        (None, Some("callgraph")) if syntax.is_none() => callgraph_or_exit(in_name, inline)?.into_bytes(),
+        _ => usage(),
+    };
     let mut out_file = File::create(out_name)?;
-    out_file.write_all(asm_program.as_bytes())?;
+    out_file.write_all(&output)?;
+    Ok(())
+}
+
+// builds an executable next to the source file (prog.snek -> prog.run) unless -o is given
+fn build_file(args: &[String]) -> std::io::Result<()> {
+    let mut args = args.to_vec();
+    let out_name = take_option(&mut args, &["-o", "--output"]);
+    let backend = take_backend(&mut args);
+    let inline = take_inline_threshold(&mut args);
+    let stats = take_flag(&mut args, "--peephole-stats");
+    if stats && matches!(backend, Backend::C) {
+        usage();
+    }
+    let [in_name] = &args[..] else { usage() };
+    let out_name = out_name.map_or_else(|| Path::new(in_name).with_extension("run"), PathBuf::from);
+    if let Err(err) = link(in_name, backend, inline, stats, &out_name)? {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// builds the program in a temp dir and runs it with the remaining arguments (the input and
+// heap size), exiting with its status
+fn run_file(args: &[String]) -> std::io::Result<()> {
+    // options go before the input, everything after it is for the program
+    let mut options = Vec::new();
+    let mut args = args;
+    while let [flag, value, rest @ ..] = args {
+        if !flag.starts_with("--") {
+            break;
+        }
+        options.extend([flag.clone(), value.clone()]);
+        args = rest;
+    }
+    let backend = take_backend(&mut options);
+    let inline = take_inline_threshold(&mut options);
+    if !options.is_empty() {
+        usage();
+    }
+    let [in_name, program_args @ ..] = args else { usage() };
+    let dir = driver::TempDir::new()?;
+    let exe = dir.path().join("program");
+    let status = match link(in_name, backend, inline, false, &exe)? {
+        Ok(()) => Command::new(&exe).args(program_args).status()?.code().unwrap_or(1),
+        Err(err) => {
+            eprintln!("error: {err}");
+            1
+        }
+    };
+    // exit skips destructors, so remove the temp dir first
+    drop(dir);
+    std::process::exit(status);
+}
+
+// runs the program with the reference interpreter instead of compiling it, printing
+// output and runtime errors the same way as the compiled program
+fn interp_file(in_name: &str, input: &str) -> std::io::Result<()> {
+    let in_contents = read_file(in_name)?;
+    let p = match check_all(&in_contents) {
+        Ok(p) => p,
+        Err(errors) => report(in_name, errors),
+    };
+    let input = match diamondback::interp::parse_input(input) {
+        Some(input) => input,
+        None => {
+            eprintln!("Invalid input {input}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = diamondback::interp::run(&p, input, &mut std::io::stdout()) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
+// reads entries from stdin until EOF, waiting for more lines while a form is still open
+fn repl(input: &str) -> std::io::Result<()> {
+    let mut session = diamondback::repl::Session::new(input)?;
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut entry = String::new();
+    loop {
+        print!("{}", if entry.is_empty() { "> " } else { ". " });
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            break;
+        }
+        entry.push_str(&line);
+        if !diamondback::reader::is_incomplete(&entry) {
+            session.eval(&entry, &mut stdout)?;
+            entry.clear();
+        }
+    }
+    if !entry.trim().is_empty() {
+        session.eval(&entry, &mut stdout)?;
+    }
+    println!();
     Ok(())
 }
+
+// runs the compiled program in process, taking the same arguments as the executable
+fn jit_file(in_name: &str, program_args: &[String]) -> std::io::Result<()> {
+    let in_contents = read_file(in_name)?;
+    let p = match check_all(&in_contents) {
+        Ok(p) => p,
+        Err(errors) => report(in_name, errors),
+    };
+    let input = program_args.first().map_or("false", String::as_str);
+    let input = match diamondback::interp::parse_input(input) {
+        Some(input) => input,
+        None => {
+            eprintln!("Invalid input {input}");
+            std::process::exit(1);
+        }
+    };
+    let heap_size = match program_args.get(1).map(|size| size.parse::<usize>()) {
+        None => diamondback::jit::DEFAULT_HEAP_SIZE,
+        Some(Ok(size)) => size,
+        Some(Err(_)) => {
+            eprintln!("Invalid heap size");
+            std::process::exit(1);
+        }
+    };
+    match diamondback::jit::run(&p, input, heap_size, &mut std::io::stdout()) {
+        Ok(()) => Ok(()),
+        Err(JitError::Compile(err)) => report(in_name, vec![err]),
+        Err(JitError::Runtime(err)) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_file(name: &str) -> std::io::Result<String> {
+    let mut in_file = File::open(name)?;
+    let mut in_contents = String::new();
+    in_file.read_to_string(&mut in_contents)?;
+    Ok(in_contents)
+}
+
+// the x86-64 instructions, printing the peephole statistics with `stats`
+fn instrs_or_exit(in_name: &str, inline: usize, stats: bool) -> std::io::Result<Vec<Instr>> {
+    let in_contents = read_file(in_name)?;
+    match check_for_compile(in_name, &in_contents, inline).and_then(|p| diamondback::compiler::compile_with_stats(&p).map_err(|err| vec![err])) {
+        Ok((instrs, rules)) => {
+            if stats {
+                eprint!("{rules}");
+            }
+            Ok(instrs)
+        },
+        Err(errors) => report(in_name, errors),
+    }
+}
+
+fn compile_or_exit(in_name: &str, syntax: Syntax, inline: usize, stats: bool) -> std::io::Result<String> {
+    Ok(diamondback::compiler::program_to_str(&instrs_or_exit(in_name, inline, stats)?, syntax))
+}
+
+fn c_or_exit(in_name: &str, inline: usize) -> std::io::Result<String> {
+    let in_contents = read_file(in_name)?;
+    match check_for_compile(in_name, &in_contents, inline).and_then(|p| diamondback::c::compile(&p).map_err(|err| vec![err])) {
+        Ok(output) => Ok(output),
+        Err(errors) => report(in_name, errors),
+    }
+}
+
+fn anf_or_exit(in_name: &str, inline: usize) -> std::io::Result<String> {
+    let in_contents = read_file(in_name)?;
+    match check_for_compile(in_name, &in_contents, inline).and_then(|p| diamondback::anf::convert(&p).map_err(|err| vec![err])) {
+        Ok(p) => Ok(p.to_string()),
+        Err(errors) => report(in_name, errors),
+    }
+}
+
+fn cfg_or_exit(in_name: &str, inline: usize) -> std::io::Result<String> {
+    let in_contents = read_file(in_name)?;
+    match check_for_compile(in_name, &in_contents, inline).and_then(|p| diamondback::compiler::compile_cfg(&p).map_err(|err| vec![err])) {
+        Ok(functions) => Ok(diamondback::cfg::to_dot(&functions)),
+        Err(errors) => report(in_name, errors),
+    }
+}
+
+fn callgraph_or_exit(in_name: &str, inline: usize) -> std::io::Result<String> {
+    let in_contents = read_file(in_name)?;
+    match check_for_compile(in_name, &in_contents, inline) {
+        Ok(p) => Ok(diamondback::callgraph::CallGraph::new(&p).to_dot()),
+        Err(errors) => report(in_name, errors),
+    }
+}
+
+fn object_or_exit(in_name: &str, inline: usize, stats: bool) -> std::io::Result<Vec<u8>> {
+    match diamondback::elf::object(&instrs_or_exit(in_name, inline, stats)?) {
+        Ok(obj) => Ok(obj),
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// compiles, assembles and links the program into an executable at `out`
+fn link(in_name: &str, backend: Backend, inline: usize, stats: bool, out: &Path) -> std::io::Result<Result<(), driver::DriverError>> {
+    Ok(match backend {
+        Backend::Text(syntax) => driver::build_executable(&compile_or_exit(in_name, syntax, inline, stats)?, syntax, out),
+        Backend::Builtin => driver::link_object(&object_or_exit(in_name, inline, stats)?, out),
+        Backend::C => driver::build_c(&c_or_exit(in_name, inline)?, out),
+    })
+}
+
+// syntax errors stop at the first one found, the well-formedness check reports every error
+fn check_all(src: &str) -> Result<Program, Vec<CompileError>> {
+    let p = diamondback::parse_program(src).map_err(|err| vec![err])?;
+    let errors = diamondback::check_program(&p);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(p)
+}
+
+// the same for a program about to be compiled, printing its warnings (the interpreter
+// and --jit leave them out so they print exactly what the executable would), then inlining
+// the functions no larger than `inline`
+fn check_for_compile(in_name: &str, src: &str, inline: usize) -> Result<Program, Vec<CompileError>> {
+    let p = check_all(src)?;
+    for warning in diamondback::callgraph::unused_warnings(&p) {
+        eprintln!("{in_name}:{warning}");
+    }
+    Ok(diamondback::inline::program(&p, inline))
+}
+
+fn report(in_name: &str, errors: Vec<CompileError>) -> ! {
+    for err in errors {
+        eprintln!("{in_name}:{err}");
+    }
+    std::process::exit(1);
+}
@@ -1,276 +1,406 @@
 use super::types;
+use super::reader::Sexp;
+use super::reader::Atom::*;
 use im::HashSet;
 use im::HashMap;
-use sexp::*;
-use sexp::Atom::*;
 
 use types::Expr;
+use types::ExprKind;
 use types::Op1;
 use types::Op2;
 use types::Program;
 use types::Definition;
+use types::Span;
+use types::CompileError;
+use types::ErrorKind;
 
 
-pub fn parse_expr(s: &Sexp, is_def: bool, defs: &HashMap<String,u64>) -> types::Expr {
-    match s {
-        Sexp::Atom(I(n)) => { 
+// state shared by every parse function: the arity of each top-level function
+pub struct ParseCtx {
+    defs: HashMap<String,u64>,
+}
+
+pub fn parse_expr(s: &Sexp, is_def: bool, ctx: &ParseCtx) -> Result<types::Expr, CompileError> {
+    let span = s.span();
+    let kind = match s {
+        Sexp::Atom(I(n), _) => {
             if *n < types::LEAST_VAL || *n > types::GREATEST_VAL {
-                panic!("Invalid - Number too large")
+                return Err(CompileError::new(ErrorKind::NumberBounds, span, "Invalid - Number too large"))
             } else {
-            Expr::Number(*n as u64)}
+            ExprKind::Number(*n as u64)}
         },
-        Sexp::Atom(S(var)) => {
+        Sexp::Atom(S(var), _) => {
             if var == "true" {
-                Expr::Boolean(true)
+                ExprKind::Boolean(true)
             } else if var == "false" {
-                Expr::Boolean(false)
-            } else {            
+                ExprKind::Boolean(false)
+            } else {
                 if var == "input" && is_def {
-                    panic!("Invalid - input keyword cannot be in function definition.")
+                    return Err(CompileError::new(ErrorKind::UnboundVariable, span, "Invalid - input keyword cannot be in function definition."))
                 }
-                Expr::Id(String::from(var))
+                ExprKind::Id(String::from(var))
             }
         },
-        Sexp::List(vec) => {
+        Sexp::List(vec, _) => {
             match &vec[..] {
                 // add1 operator //
-                [Sexp::Atom(S(op)), e] if op == "add1"   => Expr::UnOp(Op1::Add1, Box::new(parse_expr(e, is_def,defs))),
+                [Sexp::Atom(S(op), _), e] if op == "add1"   => ExprKind::UnOp(Op1::Add1, Box::new(parse_expr(e, is_def,ctx)?)),
 
                 // sub1 operator //
-                [Sexp::Atom(S(op)), e] if op == "sub1"   => Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e, is_def,defs))),
+                [Sexp::Atom(S(op), _), e] if op == "sub1"   => ExprKind::UnOp(Op1::Sub1, Box::new(parse_expr(e, is_def,ctx)?)),
 
                 // isnum operator //
-                [Sexp::Atom(S(op)), e] if op == "isnum"  => Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e, is_def,defs))),
+                [Sexp::Atom(S(op), _), e] if op == "isnum"  => ExprKind::UnOp(Op1::IsNum, Box::new(parse_expr(e, is_def,ctx)?)),
 
                 // isbool operator //
-                [Sexp::Atom(S(op)), e] if op == "isbool" => Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e, is_def,defs))),
+                [Sexp::Atom(S(op), _), e] if op == "isbool" => ExprKind::UnOp(Op1::IsBool, Box::new(parse_expr(e, is_def,ctx)?)),
+
+                // not operator //
+                [Sexp::Atom(S(op), _), e] if op == "not"    => ExprKind::UnOp(Op1::Not, Box::new(parse_expr(e, is_def,ctx)?)),
 
                 // print statement //
-                [Sexp::Atom(S(op)), e] if op == "print"  => {
-                    Expr::UnOp(Op1::Print, Box::new(parse_expr(e, is_def,defs))) 
+                [Sexp::Atom(S(op), _), e] if op == "print"  => {
+                    ExprKind::UnOp(Op1::Print, Box::new(parse_expr(e, is_def,ctx)?))
                 },
 
                 // addition operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "+" => 
-                    Expr::BinOp(Op2::Plus, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == "+" =>
+                    ExprKind::BinOp(Op2::Plus, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // subtraction operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "-" => 
-                    Expr::BinOp(Op2::Minus, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == "-" =>
+                    ExprKind::BinOp(Op2::Minus, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // multiplication operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "*" => 
-                    Expr::BinOp(Op2::Times, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == "*" =>
+                    ExprKind::BinOp(Op2::Times, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
+
+                // division operator //
+                [Sexp::Atom(S(op), _), e1, e2] if op == "/" =>
+                    ExprKind::BinOp(Op2::Divide, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
+
+                // modulo operator //
+                [Sexp::Atom(S(op), _), e1, e2] if op == "%" =>
+                    ExprKind::BinOp(Op2::Modulo, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // Equal operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "=" => 
-                    Expr::BinOp(Op2::Equal, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == "=" =>
+                    ExprKind::BinOp(Op2::Equal, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // Greater than or equal operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == ">=" => 
-                    Expr::BinOp(Op2::GreaterEqual, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == ">=" =>
+                    ExprKind::BinOp(Op2::GreaterEqual, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // Less than or equal operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "<=" => {
-                    Expr::BinOp(Op2::LessEqual, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs)))},
+                [Sexp::Atom(S(op), _), e1, e2] if op == "<=" => {
+                    ExprKind::BinOp(Op2::LessEqual, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?))},
 
                 // Greater than operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == ">" => 
-                    Expr::BinOp(Op2::Greater, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2] if op == ">" =>
+                    ExprKind::BinOp(Op2::Greater, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
 
                 // Less than operator //
-                [Sexp::Atom(S(op)), e1, e2] if op == "<" => 
-                    Expr::BinOp(Op2::Less, Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs))),
-                
+                [Sexp::Atom(S(op), _), e1, e2] if op == "<" =>
+                    ExprKind::BinOp(Op2::Less, Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
+
                 // if statement //
-                [Sexp::Atom(S(op)), e1, e2, e3] if op == "if" => 
-                    Expr::If(Box::new(parse_expr(e1, is_def,defs)),Box::new(parse_expr(e2, is_def,defs)), Box::new(parse_expr(e3, is_def,defs))),
+                [Sexp::Atom(S(op), _), e1, e2, e3] if op == "if" =>
+                    ExprKind::If(Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?), Box::new(parse_expr(e3, is_def,ctx)?)),
+
+                // cond expression //
+                [Sexp::Atom(S(op), _), clauses @ ..] if op == "cond" => parse_cond(clauses, span, is_def, ctx)?.kind,
+
+                // case expression: the scrutinee is evaluated once into a temporary //
+                [Sexp::Atom(S(op), _), e, clauses @ ..] if op == "case" => {
+                    let scrutinee = parse_expr(e, is_def, ctx)?;
+                    ExprKind::Let(vec![(String::from(CASE_VAR), scrutinee)], Box::new(parse_case(clauses, span, is_def, ctx)?))
+                },
 
                 // loop statment //
-                [Sexp::Atom(S(op)), e] if op == "loop" =>  
-                    Expr::Loop(Box::new(parse_expr(e, is_def,defs))),
+                [Sexp::Atom(S(op), _), e] if op == "loop" =>
+                    ExprKind::Loop(Box::new(parse_expr(e, is_def,ctx)?)),
 
-                // Let statement // 
-                [Sexp::Atom(S(op)), Sexp::List(list_vec), e] if op == "let" => {
+                // Let statement //
+                [Sexp::Atom(S(op), _), Sexp::List(list_vec, _), e] if op == "let" => {
                     let mut bind_vec = Vec::new();
                     for item in list_vec{
-                        bind_vec.push(parse_bind(item,defs))
+                        bind_vec.push(parse_bind(item,ctx)?)
                     }
-                    if bind_vec.len() == 0 {
-                        panic!("Invalid S-Expression, missing binding for let.")
+                    if bind_vec.is_empty() {
+                        return Err(CompileError::new(ErrorKind::Syntax, span, "Invalid S-Expression, missing binding for let."))
                     }
-                    Expr::Let(bind_vec, Box::new(parse_expr(e, is_def,defs)))
+                    ExprKind::Let(bind_vec, Box::new(parse_expr(e, is_def,ctx)?))
                 },
 
                 // Block statement //
-                [Sexp::Atom(S(op)), exprs @ ..] if op == "block" => {
+                [Sexp::Atom(S(op), _), exprs @ ..] if op == "block" => {
                     let mut coll:Vec<Expr> = Vec::new();
                     for item in exprs {
-                        coll.push(parse_expr(item, is_def,defs));
+                        coll.push(parse_expr(item, is_def,ctx)?);
+                    }
+
+                    if coll.is_empty() {
+                        return Err(CompileError::new(ErrorKind::Syntax, span, "Invalid S-Expression, empty block."))
                     }
+                    ExprKind::Block(coll)
+                },
 
-                    if coll.len() == 0 {
-                        panic!("Invalid S-Expression")
+                // tuple allocation //
+                [Sexp::Atom(S(op), _), exprs @ ..] if op == "tuple" => {
+                    if exprs.is_empty() {
+                        return Err(CompileError::new(ErrorKind::Syntax, span, "Invalid S-Expression, tuple must have at least one element."))
                     }
-                    Expr::Block(coll)
+                    ExprKind::Tuple(exprs.iter().map(|item| parse_expr(item, is_def, ctx)).collect::<Result<_, _>>()?)
                 },
 
+                // tuple indexing //
+                [Sexp::Atom(S(op), _), e1, e2] if op == "index" =>
+                    ExprKind::Index(Box::new(parse_expr(e1, is_def,ctx)?),Box::new(parse_expr(e2, is_def,ctx)?)),
+
+                // and operator //
+                [Sexp::Atom(S(op), _), exprs @ ..] if op == "and" =>
+                    ExprKind::And(exprs.iter().map(|item| parse_expr(item, is_def, ctx)).collect::<Result<_, _>>()?),
+
+                // or operator //
+                [Sexp::Atom(S(op), _), exprs @ ..] if op == "or" =>
+                    ExprKind::Or(exprs.iter().map(|item| parse_expr(item, is_def, ctx)).collect::<Result<_, _>>()?),
+
                 // set! statement //
-                [Sexp::Atom(S(op)), Sexp::Atom(S(name)), e] if op == "set!" => {
-                    Expr::Set(name.to_string(), Box::new(parse_expr(e, is_def,defs)))
+                [Sexp::Atom(S(op), _), Sexp::Atom(S(name), _), e] if op == "set!" => {
+                    ExprKind::Set(name.to_string(), Box::new(parse_expr(e, is_def,ctx)?))
                 },
 
                 // Break statement //
-                [Sexp::Atom(S(op)), e] if op == "break" => {
-                    Expr::Break(Box::new(parse_expr(e, is_def,defs)))
+                [Sexp::Atom(S(op), _), e] if op == "break" => {
+                    ExprKind::Break(Box::new(parse_expr(e, is_def,ctx)?))
                 },
 
-                // Function Call//
-                [Sexp::Atom(S(funname)), args @ ..] => {
-                    if check_reserved_words(funname.clone()) { panic!("Invalid")} 
-                    let mut exprs = Vec::new();               
+                // lambda expression //
+                [Sexp::Atom(S(op), _), Sexp::List(params, _), body] if op == "lambda" => {
+                    let mut arg_vec = Vec::new();
+                    for item in params {
+                        match item {
+                            Sexp::Atom(S(str_val), _) => {
+                                if check_reserved_words(str_val.clone()) {
+                                    return Err(CompileError::new(ErrorKind::ReservedWord, item.span(), "Error - Invalid keyword used in lambda parameters."))
+                                }
+                                arg_vec.push(str_val.clone());
+                            },
+                            _ => return Err(CompileError::new(ErrorKind::Syntax, item.span(), "Invalid - Bad lambda")),
+                        }
+                    }
+                    ExprKind::Lambda(arg_vec, Box::new(parse_expr(body, is_def, ctx)?))
+                },
 
-                    println!("funname {funname}");
-                    if args.len() as u64 != *(defs.get(funname)).unwrap() {
-                        panic!("Invalid, function call must match the number of arguments in declared function.")
+                // Function Call//
+                [head @ Sexp::Atom(S(funname), _), args @ ..] => {
+                    // a keyword here means a built-in form with the wrong shape, e.g. (add1 1 2)
+                    if check_reserved_words(funname.clone()) {
+                        return Err(CompileError::new(ErrorKind::Syntax, span, format!("Invalid {funname} expression.")))
                     }
+                    let mut exprs = Vec::new();
+
                     for item in args {
-                        exprs.push(parse_expr(item, is_def,defs))
+                        exprs.push(parse_expr(item, is_def,ctx)?)
+                    }
+
+                    // calls to top-level functions are direct (their arity is checked in
+                    // check.rs), anything else is called through a closure
+                    if ctx.defs.contains_key(funname) {
+                        ExprKind::Call(funname.clone(), exprs)
+                    } else {
+                        ExprKind::App(Box::new(Expr::new(ExprKind::Id(funname.clone()), head.span())), exprs)
                     }
+                },
 
-                    Expr::Call(funname.clone(), exprs)
+                // Closure Call //
+                [f @ Sexp::List(..), args @ ..] => {
+                    let exprs = args.iter().map(|item| parse_expr(item, is_def, ctx)).collect::<Result<_, _>>()?;
+                    ExprKind::App(Box::new(parse_expr(f, is_def, ctx)?), exprs)
                 },
                 _ => {
-                    panic!("Invalid S-Expression.")
+                    return Err(CompileError::new(ErrorKind::Syntax, span, "Invalid S-Expression."))
                 },
             }
         },
-        
-        
-        _ => panic!("Invalid S-Expression.")
-        }
+    };
+    Ok(Expr::new(kind, span))
 }
 
 // PROVIDED LECTURE CODE (https://github.com/ucsd-compilers-s23/lecture1/blob/diamondback/src/main.rs#L334)
-fn parse_definition(s: &Sexp, defs:&HashMap<String,u64>) -> (Definition, String) {
+fn parse_definition(s: &Sexp, ctx: &ParseCtx) -> Result<(Definition, String), CompileError> {
     match s {
-        Sexp::List(def_vec) => match &def_vec[..] {
-            [Sexp::Atom(S(keyword)), Sexp::List(in_name_vec), body] if keyword == "fun" =>  {
+        Sexp::List(def_vec, _) => match &def_vec[..] {
+            [Sexp::Atom(S(keyword), _), Sexp::List(in_name_vec, name_span), body] if keyword == "fun" =>  {
                 let mut name_vec = in_name_vec.clone();
                 let mut arg_vec = Vec::new();
-                if name_vec.len() == 0 {
-                   panic!("Invalid - Bad fundef")
+                if name_vec.is_empty() {
+                   return Err(CompileError::new(ErrorKind::Syntax, *name_span, "Invalid - Bad fundef"))
                 }
-                let funname = name_vec[0].clone();
-                name_vec.remove(0);
+                let funname = match name_vec.remove(0) {
+                    Sexp::Atom(S(name), name_span) => {
+                        if check_reserved_words(name.clone()) {
+                            return Err(CompileError::new(ErrorKind::ReservedWord, name_span, "Error - Invalid keyword used as function name."))
+                        }
+                        name
+                    },
+                    other => return Err(CompileError::new(ErrorKind::Syntax, other.span(), "Invalid - Bad fundef")),
+                };
 
                 for item in name_vec {
-                    match item {
-                        Sexp::Atom(S(str_val)) => {
-                            if check_reserved_words(str_val.clone()) || check_reserved_words(str_val.clone()) || str_val == "input"
-                            { 
-                                panic!("Error - Invalid keyword used in function defintion.")
+                    match &item {
+                        Sexp::Atom(S(str_val), _) => {
+                            if check_reserved_words(str_val.clone()) || str_val == "input"
+                            {
+                                return Err(CompileError::new(ErrorKind::ReservedWord, item.span(), "Error - Invalid keyword used in function defintion."))
                             }
                             arg_vec.push(str_val.clone());
                         },
-                        _ => panic!("Invalid - Bad fundef"),
+                        _ => return Err(CompileError::new(ErrorKind::Syntax, item.span(), "Invalid - Bad fundef")),
                     }
-                  
+
                 }
-                (Definition::Fun(funname.to_string(), arg_vec, parse_expr(body, true,defs)), funname.to_string())
+                Ok((Definition::Fun(funname.clone(), arg_vec, parse_expr(body, true,ctx)?, s.span()), funname))
 
             },
-            _ => panic!("Invalid - Bad fundef"),
+            _ => Err(CompileError::new(ErrorKind::Syntax, s.span(), "Invalid - Bad fundef")),
         },
-        _ => panic!("Invalid - Bad fundef"),
+        _ => Err(CompileError::new(ErrorKind::Syntax, s.span(), "Invalid - Bad fundef")),
     }
 }
 
 pub fn find_arg_num(def_arg_num:&mut HashMap<String,u64>,s:&Sexp) {
-    match s {
-        Sexp::List(def_vec) => match &def_vec[..] {
-            [Sexp::Atom(S(keyword)), Sexp::List(in_name_vec), _] if keyword == "fun" =>  {
-                let name_vec = in_name_vec.clone();
-                if name_vec.len() == 0 {
-                   panic!("Invalid - Bad fundef")
+    if let Sexp::List(def_vec, _) = s {
+        if let [Sexp::Atom(S(keyword), _), Sexp::List(in_name_vec, _), _] = &def_vec[..] {
+            if keyword == "fun" {
+                if let Some(Sexp::Atom(S(name), _)) = in_name_vec.first() {
+                    def_arg_num.insert(name.clone(), (in_name_vec.len() - 1) as u64);
                 }
-                
-                def_arg_num.insert(name_vec[0].clone().to_string(), (name_vec.len() - 1) as u64);
-
-            },
-            _ => ()
-        },
-        _ => (),
-    
-}
+            }
+        }
+    }
 }
 
 // PROVIDED LECTURE CODE (https://github.com/ucsd-compilers-s23/lecture1/blob/diamondback/src/main.rs#L334)
-pub fn parse_program(s: &Sexp) -> Program {
+pub fn parse_program(s: &Sexp) -> Result<Program, CompileError> {
+    let mut ctx = ParseCtx { defs: HashMap::new() };
     match s {
-        Sexp::List(vec) => {
-            let mut def_arg_num = HashMap::new();
+        Sexp::List(vec, _) => {
             for def_or_exp in vec {
-                find_arg_num(&mut def_arg_num, def_or_exp);
+                find_arg_num(&mut ctx.defs, def_or_exp);
             }
             let mut defs: Vec<Definition> = vec![];
             let mut func_list = HashSet::new();
             for def_or_exp in vec {
                 if is_def(def_or_exp) {
-                    let (instr, name) = parse_definition(def_or_exp,&def_arg_num);
+                    let (instr, name) = parse_definition(def_or_exp,&ctx)?;
                     defs.push(instr);
                     func_list.insert(name);
                 } else {
                     if defs.len() + 1 != vec.len() {
-                        panic!("Invalid function use")
+                        return Err(CompileError::new(ErrorKind::Syntax, def_or_exp.span(), "Invalid function use"))
                     }
-                    return Program {
-                        defs: defs,
-                        main: parse_expr(def_or_exp,false, &def_arg_num),
-                        func_list: func_list,
-                    };
+                    return Ok(Program {
+                        defs,
+                        main: parse_expr(def_or_exp,false, &ctx)?,
+                        func_list,
+                    });
                 }
             }
-            panic!("Only found definitions");
+            Err(CompileError::new(ErrorKind::Syntax, s.span(), "Invalid - only found definitions"))
         }
-        _ => panic!("Program should be a list")
+        _ => Err(CompileError::new(ErrorKind::Syntax, s.span(), "Program should be a list"))
     }
 }
 
 // PROVIDED IN LECTURE CODE (https://github.com/ucsd-compilers-s23/lecture1/blob/diamondback/src/main.rs#L334)
 fn is_def(s: &Sexp) -> bool {
     match s {
-        Sexp::List(def_vec) => match &def_vec[..] {
-            [Sexp::Atom(S(keyword)), Sexp::List(_), _] if keyword == "fun" => true,
-            _ => false
-        }
+        Sexp::List(def_vec, _) => matches!(&def_vec[..], [Sexp::Atom(S(keyword), _), Sexp::List(..), _] if keyword == "fun"),
         _ => false,
     }
 }
 
+// name of the temporary holding a case scrutinee; it is a reserved word so it cannot capture a user variable
+const CASE_VAR: &str = "case";
+
+// (cond (test e) ... (else e)) becomes a chain of ifs ending in a NoMatch when there is no else
+fn parse_cond(clauses: &[Sexp], span: Span, is_def: bool, ctx: &ParseCtx) -> Result<Expr, CompileError> {
+    match clauses {
+        [] => Ok(Expr::new(ExprKind::NoMatch, span)),
+        [Sexp::List(clause, clause_span), rest @ ..] => match &clause[..] {
+            [Sexp::Atom(S(word), _), e] if word == "else" => {
+                if !rest.is_empty() {
+                    return Err(CompileError::new(ErrorKind::Syntax, *clause_span, "Invalid - else must be the last clause."))
+                }
+                parse_expr(e, is_def, ctx)
+            },
+            [test, e] => Ok(Expr::new(ExprKind::If(
+                Box::new(parse_expr(test, is_def, ctx)?),
+                Box::new(parse_expr(e, is_def, ctx)?),
+                Box::new(parse_cond(rest, span, is_def, ctx)?),
+            ), *clause_span)),
+            _ => Err(CompileError::new(ErrorKind::Syntax, *clause_span, "Invalid cond clause.")),
+        },
+        [other, ..] => Err(CompileError::new(ErrorKind::Syntax, other.span(), "Invalid cond clause.")),
+    }
+}
+
+// (case e (v r) ... (else d)) compares the temporary against each literal label in turn
+fn parse_case(clauses: &[Sexp], span: Span, is_def: bool, ctx: &ParseCtx) -> Result<Expr, CompileError> {
+    match clauses {
+        [] => Ok(Expr::new(ExprKind::NoMatch, span)),
+        [Sexp::List(clause, clause_span), rest @ ..] => match &clause[..] {
+            [Sexp::Atom(S(word), _), e] if word == "else" => {
+                if !rest.is_empty() {
+                    return Err(CompileError::new(ErrorKind::Syntax, *clause_span, "Invalid - else must be the last clause."))
+                }
+                parse_expr(e, is_def, ctx)
+            },
+            [label, e] => {
+                // guard on the type first, since = rejects a number compared with a boolean
+                let value = parse_expr(label, is_def, ctx)?;
+                let type_check = match value.kind {
+                    ExprKind::Number(_) => Op1::IsNum,
+                    ExprKind::Boolean(_) => Op1::IsBool,
+                    _ => return Err(CompileError::new(ErrorKind::Syntax, value.span, "Invalid - case labels must be number or boolean literals.")),
+                };
+                let at = |kind| Expr::new(kind, *clause_span);
+                let tmp = at(ExprKind::Id(String::from(CASE_VAR)));
+                let test = at(ExprKind::And(vec![
+                    at(ExprKind::UnOp(type_check, Box::new(tmp.clone()))),
+                    at(ExprKind::BinOp(Op2::Equal, Box::new(tmp), Box::new(value))),
+                ]));
+                Ok(at(ExprKind::If(Box::new(test), Box::new(parse_expr(e, is_def, ctx)?), Box::new(parse_case(rest, span, is_def, ctx)?))))
+            },
+            _ => Err(CompileError::new(ErrorKind::Syntax, *clause_span, "Invalid case clause.")),
+        },
+        [other, ..] => Err(CompileError::new(ErrorKind::Syntax, other.span(), "Invalid case clause.")),
+    }
+}
+
 // This was inspired by the code from compiler 31 and 17
 fn check_reserved_words(name: String) -> bool {
-    match &name[..] {
+    matches!(&name[..],
         "let" | "block" | "set!" | "loop" | "break" | "if"   | "input" | "+" |
         "-"   | "*"     | "="    | "true" | "false" | ">"    | "<"     | ">="|
-        "<="  | "fun"   | "print"| "sub1" | "add1"  | "isnum"| "isbool" => true,
-        _ => false
-    }
+        "<="  | "fun"   | "print"| "sub1" | "add1"  | "isnum"| "isbool"| "tuple" |
+        "index" | "lambda" | "/" | "%" | "and" | "or" | "not" | "cond" | "case" | "else")
 }
 
-fn parse_bind(s: &Sexp, defs: &HashMap<String,u64>) -> (String, Expr) {
+fn parse_bind(s: &Sexp, ctx: &ParseCtx) -> Result<(String, Expr), CompileError> {
 
 
     match s {
-        Sexp::List(vec) =>
+        Sexp::List(vec, _) =>
             match &vec[..] {
-                [Sexp::Atom(S(var)), e] => {   
+                [Sexp::Atom(S(var), var_span), e] => {
                     if check_reserved_words(var.clone()){
-                        panic!("Error - keyword used.")
+                        return Err(CompileError::new(ErrorKind::ReservedWord, *var_span, "Error - keyword used."))
                     }
-                    (String::from(var),parse_expr(e, false,defs)) },
-                _ => panic!("Invalid S-Expression.")
+                    Ok((String::from(var),parse_expr(e, false,ctx)?)) },
+                _ => Err(CompileError::new(ErrorKind::Syntax, s.span(), "Invalid S-Expression."))
             },
-        _ => panic!("Invalid S-Expression.")
+        _ => Err(CompileError::new(ErrorKind::Syntax, s.span(), "Invalid S-Expression."))
     }
-}
\ No newline at end of file
+}
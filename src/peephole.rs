@@ -0,0 +1,233 @@
+use super::types;
+
+use types::Instr;
+use types::Reg;
+use types::Val;
+
+use im::HashMap;
+use std::fmt;
+
+// Peephole optimization of the instructions from codegen, before they are printed or
+// encoded. Every rule in RULES looks at the instructions from one position on and may
+// replace the first few with something shorter; the passes repeat until no rule applies.
+//
+// Rules may also use what is known about the registers at that position, which is
+// tracked forward from the last label: a register holding a constant, or one that has
+// passed a `test r,1` / `jne` check and so holds a number. Anything reached by a jump
+// starts at a label, so nothing is known there, and nothing survives a call.
+//
+// Dropping an instruction that sets the flags is only safe when the next instruction
+// that uses them sets them again, which `flags_dead` checks.
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Fact {
+    Const(u64),
+    Number,
+}
+
+type Facts = HashMap<Reg, Fact>;
+
+// what a rule does at a position: how many instructions it replaces, and with what
+type Rewrite = Option<(usize, Vec<Instr>)>;
+
+pub struct Rule {
+    pub name: &'static str,
+    apply: fn(&[Instr], &Facts) -> Rewrite,
+}
+
+pub const RULES: [Rule; 6] = [
+    Rule { name: "jump-to-next", apply: jump_to_next },
+    Rule { name: "reload-after-store", apply: reload_after_store },
+    Rule { name: "known-number-check", apply: known_number_check },
+    Rule { name: "pre-shifted-immediate", apply: pre_shifted_immediate },
+    Rule { name: "constant-copy", apply: constant_copy },
+    Rule { name: "dead-move", apply: dead_move },
+];
+
+// for every rule, how many times it applied and how many instructions that removed
+pub struct Stats {
+    pub rules: Vec<(&'static str, usize, usize)>,
+}
+
+pub fn optimize(instrs: Vec<Instr>) -> (Vec<Instr>, Stats) {
+    let mut stats = Stats { rules: RULES.iter().map(|rule| (rule.name, 0, 0)).collect() };
+    let mut instrs = instrs;
+    loop {
+        let (next, changed) = pass(&instrs, &mut stats);
+        instrs = next;
+        if !changed {
+            return (instrs, stats);
+        }
+    }
+}
+
+fn pass(instrs: &[Instr], stats: &mut Stats) -> (Vec<Instr>, bool) {
+    let mut out: Vec<Instr> = Vec::new();
+    let mut facts = Facts::new();
+    let mut changed = false;
+    let mut i = 0;
+    while i < instrs.len() {
+        let rewrite = RULES.iter().enumerate()
+            .find_map(|(r, rule)| (rule.apply)(&instrs[i..], &facts).map(|rewrite| (r, rewrite)));
+        let (replaced, with) = match rewrite {
+            Some((r, (n, with))) => {
+                stats.rules[r].1 += 1;
+                stats.rules[r].2 += n - with.len();
+                changed = true;
+                (n, with)
+            },
+            None => (1, vec![instrs[i].clone()]),
+        };
+        for instr in with {
+            learn(&mut facts, out.last(), &instr);
+            out.push(instr);
+        }
+        i += replaced;
+    }
+    (out, changed)
+}
+
+// updates what is known about the registers after `instr`, which follows `prev`
+fn learn(facts: &mut Facts, prev: Option<&Instr>, instr: &Instr) {
+    match instr {
+        Instr::Label(_) | Instr::Call(_) | Instr::Jmp(_) | Instr::Ret() => facts.clear(),
+        Instr::IMov(Val::Reg(r), Val::Imm(n)) => {
+            facts.insert(*r, Fact::Const(*n));
+        },
+        Instr::IMov(Val::Reg(r), Val::Reg(s)) => match facts.get(s).copied() {
+            Some(fact) => {
+                facts.insert(*r, fact);
+            },
+            None => {
+                facts.remove(r);
+            },
+        },
+        // the check falls through only for a number
+        Instr::JNotEqual(_) => {
+            if let Some(Instr::Test(Val::Reg(r), Val::Imm(1))) = prev {
+                facts.entry(*r).or_insert(Fact::Number);
+            }
+        },
+        Instr::IDiv(_) => {
+            facts.remove(&Reg::RAX);
+            facts.remove(&Reg::RDX);
+        },
+        Instr::Cqo() => {
+            facts.remove(&Reg::RDX);
+        },
+        Instr::IMov(Val::Reg(r), _) | Instr::IAdd(Val::Reg(r), _) | Instr::ISub(Val::Reg(r), _) |
+        Instr::IMul(Val::Reg(r), _) | Instr::Shr(Val::Reg(r), _) | Instr::Shl(Val::Reg(r), _) |
+        Instr::Xor(Val::Reg(r), _) | Instr::And(Val::Reg(r), _) | Instr::Lea(Val::Reg(r), _) |
+        Instr::Cmove(Val::Reg(r), _) | Instr::Pop(Val::Reg(r)) => {
+            facts.remove(r);
+        },
+        _ => (),
+    }
+}
+
+fn is_number(facts: &Facts, r: &Reg) -> bool {
+    match facts.get(r) {
+        Some(Fact::Number) => true,
+        Some(Fact::Const(n)) => n & 1 == 0,
+        None => false,
+    }
+}
+
+// whether the flags at the start of `rest` are set again before anything reads them
+fn flags_dead(rest: &[Instr]) -> bool {
+    for instr in rest {
+        match instr {
+            Instr::IMov(..) | Instr::Push(_) | Instr::Pop(_) | Instr::Lea(..) | Instr::Cqo() => (),
+            Instr::Shr(_, Val::Imm(n)) | Instr::Shl(_, Val::Imm(n)) => return *n != 0,
+            Instr::Test(..) | Instr::Cmp(..) | Instr::IAdd(..) | Instr::ISub(..) | Instr::IMul(..) |
+            Instr::Xor(..) | Instr::And(..) | Instr::IDiv(_) => return true,
+            // a jump or label may lead anywhere, a call to code that reads them
+            _ => return false,
+        }
+    }
+    false
+}
+
+fn mentions(v: &Val, r: &Reg) -> bool {
+    matches!(v, Val::Reg(s) | Val::RegOffset(s, _) if s == r)
+}
+
+// jmp l; l:
+fn jump_to_next(instrs: &[Instr], _: &Facts) -> Rewrite {
+    match instrs {
+        [Instr::Jmp(Val::Label(target)), label @ Instr::Label(Val::Label(name)), ..] if target == name =>
+            Some((2, vec![label.clone()])),
+        _ => None,
+    }
+}
+
+// mov a, b; mov b, a, where the second move puts back what is already there
+fn reload_after_store(instrs: &[Instr], _: &Facts) -> Rewrite {
+    match instrs {
+        [first @ Instr::IMov(a, b), Instr::IMov(c, d), ..] if a == d && b == c => {
+            // the first move must not change the address of the other operand
+            let uses = |v: &Val, of: &Val| matches!(of, Val::Reg(r) if mentions(v, r));
+            let simple = |v: &Val| matches!(v, Val::Reg(_) | Val::RegOffset(..));
+            (simple(a) && simple(b) && !uses(b, a) && !uses(a, b)).then(|| (2, vec![first.clone()]))
+        },
+        _ => None,
+    }
+}
+
+// test r,1; jne where r already holds a number
+fn known_number_check(instrs: &[Instr], facts: &Facts) -> Rewrite {
+    match instrs {
+        [Instr::Test(Val::Reg(r), Val::Imm(1)), Instr::JNotEqual(_), rest @ ..] if is_number(facts, r) && flags_dead(rest) =>
+            Some((2, Vec::new())),
+        _ => None,
+    }
+}
+
+// mov r, n; shl r, k (or sar) becomes a single move of the shifted value
+fn pre_shifted_immediate(instrs: &[Instr], _: &Facts) -> Rewrite {
+    let shifted = match instrs {
+        [Instr::IMov(Val::Reg(r), Val::Imm(n)), Instr::Shl(Val::Reg(s), Val::Imm(k)), rest @ ..] if r == s && *k < 64 && flags_dead(rest) => {
+            let value = n << k;
+            // only when nothing is shifted out
+            (((value as i64) >> k) as u64 == *n).then_some((r, value))
+        },
+        [Instr::IMov(Val::Reg(r), Val::Imm(n)), Instr::Shr(Val::Reg(s), Val::Imm(k)), rest @ ..] if r == s && *k < 64 && flags_dead(rest) =>
+            Some((r, ((*n as i64) >> k) as u64)),
+        _ => None,
+    };
+    shifted.map(|(r, value)| (2, vec![Instr::IMov(Val::Reg(*r), Val::Imm(value))]))
+}
+
+// mov d, r where r holds a constant moves the constant instead, which can leave the move
+// into r dead
+fn constant_copy(instrs: &[Instr], facts: &Facts) -> Rewrite {
+    match instrs {
+        [Instr::IMov(d @ Val::Reg(_), Val::Reg(r)), ..] => match facts.get(r) {
+            Some(Fact::Const(n)) => Some((1, vec![Instr::IMov(d.clone(), Val::Imm(*n))])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// mov r, x followed by moves that leave r alone and then another mov r, y
+fn dead_move(instrs: &[Instr], _: &Facts) -> Rewrite {
+    let [Instr::IMov(Val::Reg(r), _), rest @ ..] = instrs else { return None };
+    for instr in rest {
+        match instr {
+            Instr::IMov(Val::Reg(d), src) if d == r => return (!mentions(src, r)).then(|| (1, Vec::new())),
+            Instr::IMov(dst, src) if !mentions(dst, r) && !mentions(src, r) => (),
+            _ => return None,
+        }
+    }
+    None
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, applied, removed) in &self.rules {
+            writeln!(f, "{name:<24}{applied:>6} rewrites{removed:>6} instructions removed")?;
+        }
+        Ok(())
+    }
+}
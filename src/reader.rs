@@ -0,0 +1,151 @@
+use super::types::{CompileError, ErrorKind, Span};
+
+// S-expression reader that keeps the position of every atom and list. Supports `;` line
+// comments and (nestable) `#| ... |#` block comments.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    S(String),
+    I(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(Atom, Span),
+    List(Vec<Sexp>, Span),
+}
+
+impl Sexp {
+    pub fn span(&self) -> Span {
+        match self {
+            Sexp::Atom(_, span) | Sexp::List(_, span) => *span,
+        }
+    }
+}
+
+const UNCLOSED_LIST: &str = "Invalid S-Expression, unclosed `(`";
+const UNTERMINATED_COMMENT: &str = "Invalid S-Expression, unterminated block comment";
+
+struct Reader<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: u32,
+    col: u32,
+}
+
+// reads a whole file as the list of its top-level forms
+pub fn parse(src: &str) -> Result<Sexp, CompileError> {
+    let mut reader = Reader { chars: src.chars().peekable(), line: 1, col: 1 };
+    let start = reader.pos();
+    let mut forms = Vec::new();
+    loop {
+        reader.skip_whitespace()?;
+        match reader.chars.peek() {
+            None => return Ok(Sexp::List(forms, start)),
+            Some(')') => return Err(CompileError::new(ErrorKind::Syntax, reader.pos(), "Invalid S-Expression, unexpected `)`")),
+            Some(_) => forms.push(reader.read()?),
+        }
+    }
+}
+
+// true when src only fails to read because it stops inside a list or block comment, so an
+// interactive reader should wait for more lines
+pub fn is_incomplete(src: &str) -> bool {
+    match parse(src) {
+        Err(err) => err.message == UNCLOSED_LIST || err.message == UNTERMINATED_COMMENT,
+        Ok(_) => false,
+    }
+}
+
+impl Reader<'_> {
+    fn pos(&self) -> Span {
+        Span { line: self.line, col: self.col }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), CompileError> {
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == ';' {
+                while !matches!(self.chars.peek(), None | Some('\n')) {
+                    self.bump();
+                }
+            } else if c == '#' && self.chars.clone().nth(1) == Some('|') {
+                self.skip_block_comment()?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_block_comment(&mut self) -> Result<(), CompileError> {
+        let start = self.pos();
+        self.bump();
+        self.bump();
+        let mut depth = 1;
+        while depth > 0 {
+            match self.bump() {
+                None => return Err(CompileError::new(ErrorKind::Syntax, start, UNTERMINATED_COMMENT)),
+                Some('|') if self.chars.peek() == Some(&'#') => {
+                    self.bump();
+                    depth -= 1;
+                },
+                Some('#') if self.chars.peek() == Some(&'|') => {
+                    self.bump();
+                    depth += 1;
+                },
+                Some(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Sexp, CompileError> {
+        let start = self.pos();
+        if self.chars.peek() == Some(&'(') {
+            self.bump();
+            let mut items = Vec::new();
+            loop {
+                self.skip_whitespace()?;
+                match self.chars.peek() {
+                    None => return Err(CompileError::new(ErrorKind::Syntax, start, UNCLOSED_LIST)),
+                    Some(')') => {
+                        self.bump();
+                        return Ok(Sexp::List(items, start));
+                    },
+                    Some(_) => items.push(self.read()?),
+                }
+            }
+        }
+
+        let mut token = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' || c == ';' {
+                break;
+            }
+            token.push(c);
+            self.bump();
+        }
+
+        let digits = token.strip_prefix('-').unwrap_or(&token);
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            match token.parse::<i64>() {
+                Ok(n) => Ok(Sexp::Atom(Atom::I(n), start)),
+                Err(_) => Err(CompileError::new(ErrorKind::NumberBounds, start, "Invalid - Number too large")),
+            }
+        } else {
+            Ok(Sexp::Atom(Atom::S(token), start))
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use super::types;
+use super::anf;
+
+use anf::{AExpr,CExpr,Imm};
+use types::Reg;
+use types::Val;
+
+use im::{OrdMap,OrdSet};
+
+// Liveness analysis and register allocation for the variables of an ANF function. The
+// let-bound variables of a function (its own and the temporaries of ANF conversion) are
+// colored with the registers of POOL by building the interference graph and simplifying
+// it; the ones that do not fit are spilled to stack slots. Parameters and input stay in
+// their slots. The sets are ordered so that the same program always gets the same code.
+//
+// Nothing in the pool survives a call to a snek function, and the collector only sees
+// the stack, so codegen saves the live registers to a slot of each register around calls
+// and allocations and reloads them afterwards (snek_print preserves r12 and r13).
+
+pub const POOL: [Reg; 6] = [Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13];
+
+// registers the C functions of the runtime may change
+pub const CALLER_SAVED: [Reg; 4] = [Reg::R8, Reg::R9, Reg::R10, Reg::R11];
+
+pub type Live = OrdSet<String>;
+
+// where every let-bound variable of a function lives
+pub struct Allocation {
+    pub locs: OrdMap<String, Val>,
+    // the registers used, each saved to the slot at the same position from `base`
+    pub regs: Vec<Reg>,
+    // the first slot after the saved registers and the spilled variables
+    pub next_slot: i64,
+}
+
+fn imm_uses(imm: &Imm, live: &mut Live) {
+    if let Imm::Var(name) = imm {
+        live.insert(name.clone());
+    }
+}
+
+// the variables c reads itself, leaving out the branches of an if and the body of a loop
+pub fn uses(c: &CExpr) -> Live {
+    let mut live = Live::new();
+    match c {
+        CExpr::Imm(a) | CExpr::Prim1(_, a) | CExpr::CheckNum(a) | CExpr::CheckBool(a) |
+        CExpr::If(a, _, _) | CExpr::Break(a) | CExpr::Set(_, a) => imm_uses(a, &mut live),
+        CExpr::Prim2(_, a, b) | CExpr::Index(a, b) => {
+            imm_uses(a, &mut live);
+            imm_uses(b, &mut live);
+        },
+        CExpr::Call(_, args) | CExpr::Tuple(args) => args.iter().for_each(|a| imm_uses(a, &mut live)),
+        CExpr::App(f, args) => {
+            imm_uses(f, &mut live);
+            args.iter().for_each(|a| imm_uses(a, &mut live));
+        },
+        CExpr::MakeClosure(_, _, captured) => live.extend(captured.iter().cloned()),
+        CExpr::Loop(_) | CExpr::NoMatch | CExpr::ClosureVar(_) => (),
+    }
+    live
+}
+
+// the steps of a chain of lets, the last one being the returned expression
+pub fn steps(e: &AExpr) -> Vec<(Option<&String>, &CExpr)> {
+    let mut steps = Vec::new();
+    let mut e = e;
+    loop {
+        match e {
+            AExpr::Let(name, c, body) => {
+                steps.push((Some(name), c));
+                e = body;
+            },
+            AExpr::Seq(c, body) => {
+                steps.push((None, c));
+                e = body;
+            },
+            AExpr::Ret(c) => {
+                steps.push((None, c));
+                return steps;
+            },
+        }
+    }
+}
+
+// the variables live after each step of a chain, given the ones live after the chain
+// (`out`) and after the innermost loop (`brk`)
+pub fn live_after_steps(steps: &[(Option<&String>, &CExpr)], out: &Live, brk: &Live) -> Vec<Live> {
+    let mut after = vec![Live::new(); steps.len()];
+    let mut live = out.clone();
+    for (i, (name, c)) in steps.iter().enumerate().rev() {
+        if let Some(name) = name {
+            live.remove(*name);
+        }
+        after[i] = live.clone();
+        live = live_before(c, &live, brk);
+    }
+    after
+}
+
+pub fn live_in(e: &AExpr, out: &Live, brk: &Live) -> Live {
+    let steps = steps(e);
+    let after = live_after_steps(&steps, out, brk);
+    live_before(steps[0].1, &after[0], brk)
+}
+
+// the variables live at the top of a loop, which are also the ones live after its body
+pub fn loop_live(body: &AExpr, out: &Live) -> Live {
+    let mut live = Live::new();
+    loop {
+        let next = live_in(body, &live, out);
+        if next == live {
+            return live;
+        }
+        live = next;
+    }
+}
+
+// the variables live before c, where `out` holds the ones live after it
+pub fn live_before(c: &CExpr, out: &Live, brk: &Live) -> Live {
+    match c {
+        CExpr::If(cond, thn, els) => {
+            let mut live = live_in(thn, out, brk).union(live_in(els, out, brk));
+            imm_uses(cond, &mut live);
+            live
+        },
+        CExpr::Loop(body) => loop_live(body, out),
+        CExpr::Break(_) => brk.clone().union(uses(c)),
+        CExpr::NoMatch => Live::new(),
+        CExpr::Set(name, _) => out.without(name).union(uses(c)),
+        _ => out.clone().union(uses(c)),
+    }
+}
+
+// adds an edge between `name` and every other variable of `live`
+fn interfere(graph: &mut OrdMap<String, Live>, name: &str, live: &Live) {
+    for other in live.iter().filter(|other| *other != name) {
+        graph.entry(String::from(name)).or_default().insert(other.clone());
+        graph.entry(other.clone()).or_default().insert(String::from(name));
+    }
+}
+
+fn build_aexpr(e: &AExpr, out: &Live, brk: &Live, graph: &mut OrdMap<String, Live>, defined: &mut Live) {
+    let steps = steps(e);
+    let after = live_after_steps(&steps, out, brk);
+    for ((name, c), live) in steps.iter().zip(after) {
+        if let Some(name) = name {
+            defined.insert((*name).clone());
+            interfere(graph, name, &live);
+        }
+        build_cexpr(c, &live, brk, graph, defined);
+    }
+}
+
+fn build_cexpr(c: &CExpr, out: &Live, brk: &Live, graph: &mut OrdMap<String, Live>, defined: &mut Live) {
+    match c {
+        CExpr::If(_, thn, els) => {
+            build_aexpr(thn, out, brk, graph, defined);
+            build_aexpr(els, out, brk, graph, defined);
+        },
+        CExpr::Loop(body) => build_aexpr(body, &loop_live(body, out), out, graph, defined),
+        CExpr::Set(name, _) => interfere(graph, name, out),
+        _ => (),
+    }
+}
+
+// colors the let-bound variables of a function body whose slots start at `base`
+pub fn allocate(body: &AExpr, base: i64) -> Allocation {
+    let mut graph = OrdMap::new();
+    let mut defined = Live::new();
+    build_aexpr(body, &Live::new(), &Live::new(), &mut graph, &mut defined);
+
+    // take out a variable with fewer neighbours than registers while there is one, and
+    // otherwise the one with the most, which may have to be spilled
+    let neighbours = |name: &String, remaining: &Live| -> usize {
+        graph.get(name).map_or(0, |ns: &Live| ns.iter().filter(|n| remaining.contains(*n)).count())
+    };
+    let mut remaining = defined.clone();
+    let mut stack = Vec::new();
+    while !remaining.is_empty() {
+        let next = remaining.iter().find(|name| neighbours(name, &remaining) < POOL.len()).cloned();
+        let next = next.unwrap_or_else(|| {
+            remaining.iter().max_by_key(|name| neighbours(name, &remaining)).unwrap().clone()
+        });
+        remaining.remove(&next);
+        stack.push(next);
+    }
+
+    // put them back in reverse, giving each the first register its neighbours do not have
+    let mut colors: OrdMap<String, Option<usize>> = OrdMap::new();
+    while let Some(name) = stack.pop() {
+        let taken: Vec<usize> = graph.get(&name).map_or(Vec::new(), |ns| {
+            ns.iter().filter_map(|n| colors.get(n).copied().flatten()).collect()
+        });
+        colors.insert(name, (0..POOL.len()).find(|c| !taken.contains(c)));
+    }
+
+    let mut used: Vec<usize> = colors.values().filter_map(|c| *c).collect();
+    used.sort_unstable();
+    used.dedup();
+    let regs: Vec<Reg> = used.iter().map(|c| POOL[*c]).collect();
+    let mut next_slot = base + regs.len() as i64;
+    let mut locs = OrdMap::new();
+    for (name, color) in colors {
+        let loc = match color {
+            Some(c) => Val::Reg(POOL[c]),
+            None => {
+                let slot = next_slot;
+                next_slot += 1;
+                Val::RegOffset(Reg::RBP, slot * 8)
+            },
+        };
+        locs.insert(name, loc);
+    }
+    Allocation { locs, regs, next_slot }
+}
@@ -0,0 +1,222 @@
+use super::types;
+use super::reader;
+use super::parser;
+use super::check;
+use super::closure;
+use super::driver;
+
+use reader::Atom::S;
+use reader::Sexp;
+use types::CompileError;
+use types::Definition;
+use types::ErrorKind;
+use types::Span;
+use super::Syntax;
+
+use std::io::{self, Write};
+use std::process::Command;
+
+// Interactive sessions. Every entry is compiled into a whole program together with the
+// functions and top-level bindings entered before it, then built and run. `(define x e)`
+// adds a top-level binding. Bindings persist by replaying the earlier entries in front of
+// each new one (nesting the rest of the session in a let for every define); the lines those
+// entries printed the first time are not shown again. Functions cannot be redefined, since
+// that would change what the replayed entries do, and cannot use the top-level bindings,
+// which only exist in main.
+//
+// Replaying means every entry runs all the earlier ones again, side effects and all, so
+// each entry takes longer than the one before it.
+
+pub struct Session {
+    input: String,
+    defs: Vec<Sexp>,
+    history: Vec<Entry>,
+    // lines printed by the entries in history when they are replayed
+    replayed_lines: usize,
+    dir: driver::TempDir,
+}
+
+enum Entry {
+    Expr(Sexp),
+    // name and value of a top-level binding
+    Define(Sexp, Sexp),
+}
+
+// what running one entry printed, before and including its final value
+struct Output {
+    lines: Vec<String>,
+    error: Option<String>,
+}
+
+fn atom(s: &str) -> Sexp {
+    Sexp::Atom(S(String::from(s)), Span::default())
+}
+
+fn list(items: Vec<Sexp>) -> Sexp {
+    Sexp::List(items, Span::default())
+}
+
+impl Session {
+    // `input` is passed to every entry, like the argument of a compiled program
+    pub fn new(input: &str) -> io::Result<Session> {
+        Ok(Session {
+            input: String::from(input),
+            defs: Vec::new(),
+            history: Vec::new(),
+            replayed_lines: 0,
+            dir: driver::TempDir::new()?,
+        })
+    }
+
+    // Reads every form in `src` and evaluates them in order, writing their output, final
+    // values and any errors to `out`. An entry that fails leaves the session unchanged.
+    pub fn eval(&mut self, src: &str, out: &mut dyn Write) -> io::Result<()> {
+        let forms = match reader::parse(src) {
+            Ok(Sexp::List(forms, _)) => forms,
+            Ok(_) => Vec::new(),
+            Err(err) => return writeln!(out, "{err}"),
+        };
+        for form in forms {
+            if let Err(errors) = self.eval_form(form, out)? {
+                for err in errors {
+                    writeln!(out, "{err}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_form(&mut self, form: Sexp, out: &mut dyn Write) -> io::Result<Result<(), Vec<CompileError>>> {
+        match &form {
+            Sexp::List(items, span) => match &items[..] {
+                [Sexp::Atom(S(keyword), _), Sexp::List(..), _] if keyword == "fun" => Ok(self.define_fun(form.clone())),
+                [Sexp::Atom(S(keyword), _), name @ Sexp::Atom(S(_), _), val] if keyword == "define" =>
+                    self.define(name.clone(), val.clone(), out),
+                [Sexp::Atom(S(keyword), _), ..] if keyword == "define" =>
+                    Ok(Err(vec![CompileError::new(ErrorKind::Syntax, *span, "Invalid - expected (define <name> <expr>).")])),
+                _ => self.expr(form, out),
+            },
+            Sexp::Atom(..) => self.expr(form, out),
+        }
+    }
+
+    fn define_fun(&mut self, def: Sexp) -> Result<(), Vec<CompileError>> {
+        if let Sexp::List(items, span) = &def {
+            if let [_, Sexp::List(header, _), _] = &items[..] {
+                if let Some(Sexp::Atom(S(name), _)) = header.first() {
+                    let bound = self.history.iter().any(|entry| matches!(entry, Entry::Define(Sexp::Atom(S(n), _), _) if n == name));
+                    if bound {
+                        return Err(vec![CompileError::new(ErrorKind::DuplicateBinding, *span,
+                            format!("Error - {name} is already a top-level binding."))]);
+                    }
+                }
+            }
+        }
+
+        if let Some(err) = self.uses_binding(&def) {
+            return Err(vec![err]);
+        }
+
+        // only needs to compile, there is nothing to run yet
+        self.defs.push(def);
+        let result = self.compile(Sexp::Atom(reader::Atom::I(0), Span::default()));
+        if result.is_err() {
+            self.defs.pop();
+        }
+        result.map(|_| ())
+    }
+
+    // an error for a function that refers to a top-level binding, which the compiled
+    // function could not see
+    fn uses_binding(&self, def: &Sexp) -> Option<CompileError> {
+        let mut forms = self.defs.clone();
+        forms.push(def.clone());
+        forms.push(Sexp::Atom(reader::Atom::I(0), Span::default()));
+        let p = parser::parse_program(&list(forms)).ok()?;
+        let Definition::Fun(name, params, body, span) = p.defs.last()?;
+        let free = closure::free_vars(body, &params.iter().cloned().collect());
+        let binding = free.iter().find(|var| self.history.iter().any(|entry| matches!(entry, Entry::Define(Sexp::Atom(S(n), _), _) if n == *var)))?;
+        Some(CompileError::new(ErrorKind::UnboundVariable, *span,
+            format!("Error - function {name} uses the top-level binding {binding}; functions in the REPL only see their parameters, so pass it as an argument.")))
+    }
+
+    fn define(&mut self, name: Sexp, val: Sexp, out: &mut dyn Write) -> io::Result<Result<(), Vec<CompileError>>> {
+        let body = list(vec![atom("let"), list(vec![list(vec![name.clone(), val.clone()])]), name.clone()]);
+        let output = match self.run(body)? {
+            Ok(output) => output,
+            Err(errors) => return Ok(Err(errors)),
+        };
+        // the value itself is not shown
+        let printed = output.lines.len().saturating_sub(usize::from(output.error.is_none()));
+        self.show(&output.lines[..printed], &output.error, out)?;
+        if output.error.is_none() {
+            self.replayed_lines += printed;
+            self.history.push(Entry::Define(name, val));
+        }
+        Ok(Ok(()))
+    }
+
+    fn expr(&mut self, e: Sexp, out: &mut dyn Write) -> io::Result<Result<(), Vec<CompileError>>> {
+        let output = match self.run(e.clone())? {
+            Ok(output) => output,
+            Err(errors) => return Ok(Err(errors)),
+        };
+        self.show(&output.lines, &output.error, out)?;
+        if output.error.is_none() {
+            self.replayed_lines += output.lines.len().saturating_sub(1);
+            self.history.push(Entry::Expr(e));
+        }
+        Ok(Ok(()))
+    }
+
+    fn show(&self, lines: &[String], error: &Option<String>, out: &mut dyn Write) -> io::Result<()> {
+        for line in lines {
+            writeln!(out, "{line}")?;
+        }
+        if let Some(error) = error {
+            writeln!(out, "{error}")?;
+        }
+        Ok(())
+    }
+
+    // the earlier entries followed by `last`
+    fn replay(&self, last: Sexp) -> Sexp {
+        self.history.iter().rev().fold(last, |body, entry| match entry {
+            Entry::Expr(e) => list(vec![atom("block"), e.clone(), body]),
+            Entry::Define(name, val) => list(vec![atom("let"), list(vec![list(vec![name.clone(), val.clone()])]), body]),
+        })
+    }
+
+    fn compile(&self, main: Sexp) -> Result<String, Vec<CompileError>> {
+        let mut forms = self.defs.clone();
+        forms.push(main);
+        let p = parser::parse_program(&list(forms)).map_err(|err| vec![err])?;
+        let errors = check::check_program(&p);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        super::compile(&p).map_err(|err| vec![err])
+    }
+
+    // compiles and runs `e` after the earlier entries, dropping the lines they print
+    fn run(&self, e: Sexp) -> io::Result<Result<Output, Vec<CompileError>>> {
+        let asm = match self.compile(self.replay(e)) {
+            Ok(asm) => asm,
+            Err(errors) => return Ok(Err(errors)),
+        };
+        let exe = self.dir.path().join("entry");
+        if let Err(err) = driver::build_executable(&asm, Syntax::Nasm, &exe) {
+            return Ok(Ok(Output { lines: Vec::new(), error: Some(format!("error: {err}")) }));
+        }
+
+        let output = Command::new(&exe).arg(&self.input).output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let lines = stdout.lines().skip(self.replayed_lines).map(String::from).collect();
+        let error = if output.status.success() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&output.stderr).trim().to_string())
+        };
+        Ok(Ok(Output { lines, error }))
+    }
+}
@@ -2,12 +2,24 @@ pub const TRUE_VAL:u64  = 3;
 pub const FALSE_VAL:u64 = 1;
 pub const OVERFLOW_ERROR_CODE:u64 = 5;
 pub const INVALID_ARGUMENT_ERROR_CODE:u64 = 7;
+pub const INDEX_ERROR_CODE:u64 = 9;
+pub const OUT_OF_MEMORY_ERROR_CODE:u64 = 11;
+pub const NOT_A_FUNCTION_ERROR_CODE:u64 = 13;
+pub const ARITY_ERROR_CODE:u64 = 15;
+pub const DIVIDE_BY_ZERO_ERROR_CODE:u64 = 17;
+pub const NO_MATCH_ERROR_CODE:u64 = 19;
 pub const GREATEST_VAL:i64 = 4611686018427387903;
 pub const LEAST_VAL:i64 = -4611686018427387904;
 
+// heap values are 8-byte aligned addresses with the low three bits used as a tag
+pub const TAG_MASK:u64  = 7;
+pub const TUPLE_TAG:u64 = 5;
+pub const CLOSURE_TAG:u64 = 7;
+
 use im::HashSet;
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub enum Val {
     Reg(Reg),
     Imm(u64),
@@ -15,33 +27,48 @@ pub enum Val {
     Label(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Reg {
     RAX,
     RSP,
     RDI,
     RBX,
-    RDX
+    RDX,
+    RBP,
+    RSI,
+    RCX,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Program {
     pub defs: Vec<Definition>,
     pub main: Expr,
     pub func_list: HashSet<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum Definition {
-    Fun(String, Vec<String>, Expr)
+    // name, parameters, body and the span of the whole (fun ...) form
+    Fun(String, Vec<String>, Expr, Span)
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub enum Instr {
     IMov(Val, Val),
     IAdd(Val, Val),
     ISub(Val, Val),
     IMul(Val, Val),
+    IDiv(Val),
+    Cqo(),
     Shr(Val,Val),
     Shl(Val,Val),
     Jmp(Val),
@@ -55,6 +82,8 @@ pub enum Instr {
     Test(Val,Val),
     Label(Val),
     Xor(Val,Val),
+    And(Val,Val),
+    Lea(Val,Val),
     Cmove(Val,Val),
     OverFlow(),
     Call(Val),
@@ -70,6 +99,7 @@ pub enum Op1 {
     Sub1,
     IsNum,
     IsBool,
+    Not,
     Print,
 }
 
@@ -78,6 +108,10 @@ pub enum Op2 {
     Plus,
     Minus,
     Times,
+    // division rounds towards negative infinity and the remainder takes the sign of
+    // the divisor, so (/ -7 2) is -4 and (% -7 2) is 1
+    Divide,
+    Modulo,
     Equal,
     Greater,
     GreaterEqual,
@@ -85,8 +119,33 @@ pub enum Op2 {
     LessEqual,
 }
 
+// line and column (both 1-based) of a form in the source file
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 #[derive(Debug,Clone)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum ExprKind {
     Number(u64),
     Boolean(bool),
     Id(String),
@@ -98,6 +157,185 @@ pub enum Expr {
     Break(Box<Expr>),
     Set(String, Box<Expr>),
     Block(Vec<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    // reached when no clause of a cond or case matches
+    NoMatch,
 
     Call(String,Vec<Expr>),
-}
\ No newline at end of file
+
+    Tuple(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+
+    Lambda(Vec<String>, Box<Expr>),
+    App(Box<Expr>, Vec<Expr>),
+
+    // produced by closure conversion: (code label, arity, captured variables)
+    MakeClosure(String, usize, Vec<String>),
+    // i-th captured variable of the closure passed as the hidden first argument
+    ClosureVar(usize),
+}
+
+impl fmt::Display for Op1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Op1::Add1 => "add1",
+            Op1::Sub1 => "sub1",
+            Op1::IsNum => "isnum",
+            Op1::IsBool => "isbool",
+            Op1::Not => "not",
+            Op1::Print => "print",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl fmt::Display for Op2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Op2::Plus => "+",
+            Op2::Minus => "-",
+            Op2::Times => "*",
+            Op2::Divide => "/",
+            Op2::Modulo => "%",
+            Op2::Equal => "=",
+            Op2::Greater => ">",
+            Op2::GreaterEqual => ">=",
+            Op2::Less => "<",
+            Op2::LessEqual => "<=",
+        };
+        write!(f, "{name}")
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, head: &str, items: &[Expr]) -> fmt::Result {
+    write!(f, "({head}")?;
+    for item in items {
+        write!(f, " {item}")?;
+    }
+    write!(f, ")")
+}
+
+// Prints an expression back as snek source on one line. A NoMatch prints as a cond with no
+// clauses; the nodes added by closure conversion have no source form and print in angle
+// brackets. An App whose head names a top-level function reads back as a direct Call.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(n) => write!(f, "{}", *n as i64),
+            ExprKind::Boolean(b) => write!(f, "{b}"),
+            ExprKind::Id(name) => write!(f, "{name}"),
+            ExprKind::Let(binds, body) => {
+                write!(f, "(let (")?;
+                for (i, (name, val)) in binds.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { " " };
+                    write!(f, "{sep}({name} {val})")?;
+                }
+                write!(f, ") {body})")
+            },
+            ExprKind::UnOp(op, e) => write!(f, "({op} {e})"),
+            ExprKind::BinOp(op, e1, e2) => write!(f, "({op} {e1} {e2})"),
+            ExprKind::If(cond, thn, els) => write!(f, "(if {cond} {thn} {els})"),
+            ExprKind::Loop(e) => write!(f, "(loop {e})"),
+            ExprKind::Break(e) => write!(f, "(break {e})"),
+            ExprKind::Set(name, e) => write!(f, "(set! {name} {e})"),
+            ExprKind::Block(es) => write_list(f, "block", es),
+            ExprKind::And(es) => write_list(f, "and", es),
+            ExprKind::Or(es) => write_list(f, "or", es),
+            ExprKind::NoMatch => write!(f, "(cond)"),
+            ExprKind::Call(name, args) => write_list(f, name, args),
+            ExprKind::Tuple(es) => write_list(f, "tuple", es),
+            ExprKind::Index(e1, e2) => write!(f, "(index {e1} {e2})"),
+            ExprKind::Lambda(params, body) => write!(f, "(lambda ({}) {body})", params.join(" ")),
+            // a literal cannot be the head of a list, so it goes in a block
+            ExprKind::App(func, args) => match func.kind {
+                ExprKind::Number(_) | ExprKind::Boolean(_) => write_list(f, &format!("(block {func})"), args),
+                _ => write_list(f, &func.to_string(), args),
+            },
+            ExprKind::MakeClosure(label, arity, captured) => write!(f, "<closure {label}/{arity} [{}]>", captured.join(" ")),
+            ExprKind::ClosureVar(i) => write!(f, "<captured {i}>"),
+        }
+    }
+}
+
+// one definition per line followed by the main expression
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for def in &self.defs {
+            let Definition::Fun(name, params, body, _) = def;
+            let mut head = vec![name.clone()];
+            head.extend(params.iter().cloned());
+            writeln!(f, "(fun ({}) {body})", head.join(" "))?;
+        }
+        writeln!(f, "{}", self.main)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ErrorKind {
+    // malformed s-expression or special form
+    Syntax,
+    // number literal outside the 63-bit range
+    NumberBounds,
+    // keyword used as a variable, parameter or function name
+    ReservedWord,
+    UnboundVariable,
+    UnboundFunction,
+    DuplicateBinding,
+    DuplicateFunction,
+    BreakOutsideLoop,
+    // call to a top-level function with the wrong number of arguments
+    Arity,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Syntax => "syntax",
+            ErrorKind::NumberBounds => "number-bounds",
+            ErrorKind::ReservedWord => "reserved-word",
+            ErrorKind::UnboundVariable => "unbound-variable",
+            ErrorKind::UnboundFunction => "unbound-function",
+            ErrorKind::DuplicateBinding => "duplicate-binding",
+            ErrorKind::DuplicateFunction => "duplicate-function",
+            ErrorKind::BreakOutsideLoop => "break-outside-loop",
+            ErrorKind::Arity => "arity",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// a user error found while reading, parsing or compiling a program
+#[derive(Debug,Clone)]
+pub struct CompileError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl CompileError {
+    pub fn new(kind: ErrorKind, span: Span, message: impl Into<String>) -> CompileError {
+        CompileError { kind, message: message.into(), span }
+    }
+}
+
+// printed after the file name, e.g. `prog.snek:3:5: error[syntax]: ...`
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: error[{}]: {}", self.span, self.kind, self.message)
+    }
+}
+
+// something legal that is probably a mistake; compilation goes on
+#[derive(Debug,Clone)]
+pub struct CompileWarning {
+    pub message: String,
+    pub span: Span,
+}
+
+// printed after the file name like errors, e.g. `prog.snek:1:1: warning: ...`
+impl fmt::Display for CompileWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: warning: {}", self.span, self.message)
+    }
+}
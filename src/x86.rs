@@ -0,0 +1,394 @@
+use super::types;
+
+use types::Instr;
+use types::Val;
+use types::Reg;
+
+use im::HashMap;
+use std::fmt;
+
+// x86-64 machine code for the instructions the compiler emits. Every jump, call and
+// rip-relative lea uses a 32-bit displacement, so the size of an instruction never depends
+// on where its label ends up and one pass is enough. References to labels the code does
+// not define (snek_print, snek_error, ...) are left as relocations for whoever places the
+// code: the JIT resolves them in memory, an object file would record them.
+
+#[derive(Debug)]
+pub struct EncodeError(pub String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub struct Code {
+    pub bytes: Vec<u8>,
+    // offset of every label defined in the code
+    pub labels: HashMap<String, usize>,
+    pub relocs: Vec<Reloc>,
+}
+
+// A 4-byte field that should hold the address of `symbol` relative to the end of the field,
+// which is always the end of the instruction.
+pub struct Reloc {
+    pub offset: usize,
+    pub symbol: String,
+}
+
+// operand in the r/m position of an instruction
+enum Rm {
+    Reg(u8),
+    // [base + disp]
+    Mem(u8, i32),
+    // [rel label]
+    Rip(String),
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // rel32 fields and the label they refer to, filled in once every label is known
+    fixups: Vec<(usize, String)>,
+}
+
+// opcodes of the two-operand arithmetic instructions: r/m <- reg, reg <- r/m, and the
+// /digit used with an immediate
+struct Alu {
+    rm_reg: u8,
+    reg_rm: u8,
+    digit: u8,
+}
+
+const ADD: Alu = Alu { rm_reg: 0x01, reg_rm: 0x03, digit: 0 };
+const AND: Alu = Alu { rm_reg: 0x21, reg_rm: 0x23, digit: 4 };
+const SUB: Alu = Alu { rm_reg: 0x29, reg_rm: 0x2b, digit: 5 };
+const XOR: Alu = Alu { rm_reg: 0x31, reg_rm: 0x33, digit: 6 };
+const CMP: Alu = Alu { rm_reg: 0x39, reg_rm: 0x3b, digit: 7 };
+
+pub fn encode(instrs: &[Instr]) -> Result<Code, EncodeError> {
+    let mut enc = Encoder { bytes: Vec::new(), labels: HashMap::new(), fixups: Vec::new() };
+    for i in instrs {
+        enc.instr(i)?;
+    }
+
+    let mut relocs = Vec::new();
+    for (offset, label) in enc.fixups {
+        match enc.labels.get(&label) {
+            Some(target) => {
+                let rel = *target as i64 - (offset as i64 + 4);
+                enc.bytes[offset..offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+            }
+            None => relocs.push(Reloc { offset, symbol: label }),
+        }
+    }
+    Ok(Code { bytes: enc.bytes, labels: enc.labels, relocs })
+}
+
+fn reg_num(r: &Reg) -> u8 {
+    match r {
+        Reg::RAX => 0,
+        Reg::RCX => 1,
+        Reg::RDX => 2,
+        Reg::RBX => 3,
+        Reg::RSP => 4,
+        Reg::RBP => 5,
+        Reg::RSI => 6,
+        Reg::RDI => 7,
+        Reg::R8 => 8,
+        Reg::R9 => 9,
+        Reg::R10 => 10,
+        Reg::R11 => 11,
+        Reg::R12 => 12,
+        Reg::R13 => 13,
+        Reg::R14 => 14,
+        Reg::R15 => 15,
+    }
+}
+
+fn imm32(n: u64) -> Option<i32> {
+    i32::try_from(n as i64).ok()
+}
+
+fn imm8(n: u64) -> Option<i8> {
+    i8::try_from(n as i64).ok()
+}
+
+fn unsupported(i: &Instr) -> EncodeError {
+    EncodeError(format!("cannot encode {i:?}"))
+}
+
+impl Encoder {
+    fn instr(&mut self, i: &Instr) -> Result<(), EncodeError> {
+        match i {
+            Instr::IMov(dst, src) => self.mov(i, dst, src),
+            Instr::IAdd(dst, src) => self.alu(i, ADD, dst, src),
+            Instr::ISub(dst, src) => self.alu(i, SUB, dst, src),
+            Instr::And(dst, src) => self.alu(i, AND, dst, src),
+            Instr::Xor(dst, src) => self.alu(i, XOR, dst, src),
+            Instr::Cmp(dst, src) => self.alu(i, CMP, dst, src),
+            Instr::IMul(Val::Reg(dst), Val::Imm(n)) => {
+                let dst = reg_num(dst);
+                match (imm8(*n), imm32(*n)) {
+                    (Some(n), _) => {
+                        self.op_rm(true, &[0x6b], dst, &Rm::Reg(dst));
+                        self.bytes.push(n as u8);
+                    }
+                    (None, Some(n)) => {
+                        self.op_rm(true, &[0x69], dst, &Rm::Reg(dst));
+                        self.bytes.extend(n.to_le_bytes());
+                    }
+                    _ => return Err(unsupported(i)),
+                }
+                Ok(())
+            }
+            Instr::IMul(Val::Reg(dst), src) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(true, &[0x0f, 0xaf], reg_num(dst), &src);
+                Ok(())
+            }
+            Instr::IDiv(src) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(true, &[0xf7], 7, &src);
+                Ok(())
+            }
+            Instr::Cqo() => {
+                self.bytes.extend([0x48, 0x99]);
+                Ok(())
+            }
+            Instr::Shr(dst, count) => self.shift(i, 7, dst, count),
+            Instr::Shl(dst, count) => self.shift(i, 4, dst, count),
+            Instr::Test(dst, Val::Imm(n)) => {
+                let dst = self.rm(i, dst)?;
+                let n = imm32(*n).ok_or_else(|| unsupported(i))?;
+                self.op_rm(true, &[0xf7], 0, &dst);
+                self.bytes.extend(n.to_le_bytes());
+                Ok(())
+            }
+            Instr::Test(dst, Val::Reg(src)) => {
+                let dst = self.rm(i, dst)?;
+                self.op_rm(true, &[0x85], reg_num(src), &dst);
+                Ok(())
+            }
+            Instr::Lea(Val::Reg(dst), src) => {
+                let src = match src {
+                    Val::Label(label) => Rm::Rip(label.clone()),
+                    _ => self.rm(i, src)?,
+                };
+                self.op_rm(true, &[0x8d], reg_num(dst), &src);
+                Ok(())
+            }
+            Instr::Cmove(Val::Reg(dst), src) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(true, &[0x0f, 0x44], reg_num(dst), &src);
+                Ok(())
+            }
+            Instr::Jmp(Val::Label(label)) => self.rel32(&[0xe9], label),
+            Instr::Jmp(target) => {
+                let target = self.rm(i, target)?;
+                self.op_rm(false, &[0xff], 4, &target);
+                Ok(())
+            }
+            Instr::JEqual(target) => self.jcc(i, 0x84, target),
+            Instr::JNotEqual(target) => self.jcc(i, 0x85, target),
+            Instr::JGreater(target) => self.jcc(i, 0x8f, target),
+            Instr::JGreaterEqual(target) => self.jcc(i, 0x8d, target),
+            Instr::JLess(target) => self.jcc(i, 0x8c, target),
+            Instr::JLessEqual(target) => self.jcc(i, 0x8e, target),
+            Instr::OverFlow() => self.rel32(&[0x0f, 0x80], "overflow"),
+            Instr::Call(Val::Label(label)) => self.rel32(&[0xe8], label),
+            Instr::Call(target) => {
+                let target = self.rm(i, target)?;
+                self.op_rm(false, &[0xff], 2, &target);
+                Ok(())
+            }
+            Instr::Push(Val::Reg(r)) => {
+                self.short_reg(0x50, reg_num(r));
+                Ok(())
+            }
+            Instr::Push(Val::Imm(n)) => {
+                match (imm8(*n), imm32(*n)) {
+                    (Some(n), _) => self.bytes.extend([0x6a, n as u8]),
+                    (None, Some(n)) => {
+                        self.bytes.push(0x68);
+                        self.bytes.extend(n.to_le_bytes());
+                    }
+                    _ => return Err(unsupported(i)),
+                }
+                Ok(())
+            }
+            Instr::Push(src) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(false, &[0xff], 6, &src);
+                Ok(())
+            }
+            Instr::Pop(Val::Reg(r)) => {
+                self.short_reg(0x58, reg_num(r));
+                Ok(())
+            }
+            Instr::Pop(dst) => {
+                let dst = self.rm(i, dst)?;
+                self.op_rm(false, &[0x8f], 0, &dst);
+                Ok(())
+            }
+            Instr::Ret() => {
+                self.bytes.push(0xc3);
+                Ok(())
+            }
+            Instr::Label(Val::Label(label)) => {
+                if self.labels.insert(label.clone(), self.bytes.len()).is_some() {
+                    return Err(EncodeError(format!("label {label} is defined more than once")));
+                }
+                Ok(())
+            }
+            _ => Err(unsupported(i)),
+        }
+    }
+
+    // a register or memory operand; labels only appear as jump targets and in lea
+    fn rm(&self, i: &Instr, v: &Val) -> Result<Rm, EncodeError> {
+        match v {
+            Val::Reg(r) => Ok(Rm::Reg(reg_num(r))),
+            // RegOffset(r, n) is [r - n]
+            Val::RegOffset(r, n) => {
+                let disp = n.checked_neg().and_then(|d| i32::try_from(d).ok()).ok_or_else(|| unsupported(i))?;
+                Ok(Rm::Mem(reg_num(r), disp))
+            }
+            Val::Imm(_) | Val::Label(_) => Err(unsupported(i)),
+        }
+    }
+
+    fn mov(&mut self, i: &Instr, dst: &Val, src: &Val) -> Result<(), EncodeError> {
+        match (dst, src) {
+            (_, Val::Reg(src)) => {
+                let dst = self.rm(i, dst)?;
+                self.op_rm(true, &[0x89], reg_num(src), &dst);
+            }
+            (Val::Reg(dst), Val::RegOffset(..)) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(true, &[0x8b], reg_num(dst), &src);
+            }
+            (_, Val::Imm(n)) => match (imm32(*n), dst) {
+                (Some(n), _) => {
+                    let dst = self.rm(i, dst)?;
+                    self.op_rm(true, &[0xc7], 0, &dst);
+                    self.bytes.extend(n.to_le_bytes());
+                }
+                // movabs
+                (None, Val::Reg(r)) => {
+                    let r = reg_num(r);
+                    self.bytes.extend([0x48 | (r >> 3), 0xb8 + (r & 7)]);
+                    self.bytes.extend(n.to_le_bytes());
+                }
+                (None, _) => return Err(unsupported(i)),
+            },
+            _ => return Err(unsupported(i)),
+        }
+        Ok(())
+    }
+
+    fn alu(&mut self, i: &Instr, op: Alu, dst: &Val, src: &Val) -> Result<(), EncodeError> {
+        match (dst, src) {
+            (_, Val::Reg(src)) => {
+                let dst = self.rm(i, dst)?;
+                self.op_rm(true, &[op.rm_reg], reg_num(src), &dst);
+            }
+            (Val::Reg(dst), Val::RegOffset(..)) => {
+                let src = self.rm(i, src)?;
+                self.op_rm(true, &[op.reg_rm], reg_num(dst), &src);
+            }
+            (_, Val::Imm(n)) => {
+                let dst = self.rm(i, dst)?;
+                match (imm8(*n), imm32(*n)) {
+                    (Some(n), _) => {
+                        self.op_rm(true, &[0x83], op.digit, &dst);
+                        self.bytes.push(n as u8);
+                    }
+                    (None, Some(n)) => {
+                        self.op_rm(true, &[0x81], op.digit, &dst);
+                        self.bytes.extend(n.to_le_bytes());
+                    }
+                    _ => return Err(unsupported(i)),
+                }
+            }
+            _ => return Err(unsupported(i)),
+        }
+        Ok(())
+    }
+
+    // sar and shl, by a constant or by cl
+    fn shift(&mut self, i: &Instr, digit: u8, dst: &Val, count: &Val) -> Result<(), EncodeError> {
+        let dst = self.rm(i, dst)?;
+        match count {
+            Val::Imm(n) if *n < 64 => {
+                self.op_rm(true, &[0xc1], digit, &dst);
+                self.bytes.push(*n as u8);
+            }
+            Val::Reg(Reg::RCX) => self.op_rm(true, &[0xd3], digit, &dst),
+            _ => return Err(unsupported(i)),
+        }
+        Ok(())
+    }
+
+    fn jcc(&mut self, i: &Instr, cc: u8, target: &Val) -> Result<(), EncodeError> {
+        match target {
+            Val::Label(label) => self.rel32(&[0x0f, cc], label),
+            _ => Err(unsupported(i)),
+        }
+    }
+
+    fn rel32(&mut self, opcode: &[u8], label: &str) -> Result<(), EncodeError> {
+        self.bytes.extend(opcode);
+        self.fixups.push((self.bytes.len(), String::from(label)));
+        self.bytes.extend([0; 4]);
+        Ok(())
+    }
+
+    // push and pop, which encode the register in the opcode
+    fn short_reg(&mut self, opcode: u8, r: u8) {
+        if r >= 8 {
+            self.bytes.push(0x41);
+        }
+        self.bytes.push(opcode + (r & 7));
+    }
+
+    // an instruction with a ModRM byte: `reg` is a register number or an opcode extension
+    fn op_rm(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: &Rm) {
+        let base = match rm {
+            Rm::Reg(r) | Rm::Mem(r, _) => *r,
+            Rm::Rip(_) => 0,
+        };
+        let rex = 0x40 | (u8::from(wide) << 3) | ((reg >> 3) << 2) | (base >> 3);
+        if rex != 0x40 {
+            self.bytes.push(rex);
+        }
+        self.bytes.extend(opcode);
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Rm::Reg(r) => self.bytes.push(0xc0 | reg | (r & 7)),
+            Rm::Mem(base, disp) => {
+                // rbp (and r13) as a base always need a displacement, rsp (and r12) need a SIB byte
+                let mode = match disp {
+                    0 if base & 7 != 5 => 0x00,
+                    -128..=127 => 0x40,
+                    _ => 0x80,
+                };
+                self.bytes.push(mode | reg | (base & 7));
+                if base & 7 == 4 {
+                    self.bytes.push(0x24);
+                }
+                match mode {
+                    0x40 => self.bytes.push(*disp as u8),
+                    0x80 => self.bytes.extend(disp.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            Rm::Rip(label) => {
+                self.bytes.push(reg | 0x05);
+                self.fixups.push((self.bytes.len(), label.clone()));
+                self.bytes.extend([0; 4]);
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+// The build, run and repl subcommands and the --jit mode of the binary, which do not go
+// through the Makefile.
+
+fn diamondback(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_diamondback"))
+        .args(args)
+        .output()
+        .expect("could not run the compiler")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap().trim().to_string()
+}
+
+#[test]
+fn cli_run_forwards_input() {
+    let output = diamondback(&["run", "tests/cobra_input0.snek", "123"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "123");
+}
+
+#[test]
+fn cli_run_exit_status() {
+    let output = diamondback(&["run", "tests/arith_divide_by_zero_fail0.snek", "5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("division by zero"));
+}
+
+#[test]
+fn cli_build_default_output() {
+    let src = Path::new("tests/cli_build_default.snek");
+    let exe = src.with_extension("run");
+    std::fs::copy("tests/adder_num.snek", src).unwrap();
+    let _ = std::fs::remove_file(&exe);
+
+    let output = diamondback(&["build", src.to_str().unwrap()]);
+    assert!(output.status.success());
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(stdout(&run), "644");
+
+    std::fs::remove_file(src).unwrap();
+    std::fs::remove_file(exe).unwrap();
+}
+
+#[test]
+fn cli_build_missing_nasm() {
+    let output = Command::new(env!("CARGO_BIN_EXE_diamondback"))
+        .args(["build", "tests/adder_num.snek", "-o", "tests/cli_missing_nasm.run"])
+        .env("PATH", "")
+        .output()
+        .expect("could not run the compiler");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("could not find `nasm`"));
+}
+
+#[test]
+fn cli_repl_keeps_definitions() {
+    let script = "(fun (double x) (* x 2))
+(define y (print (double 21)))
+(+ y 1)
+(set! y 10)
+y
+(add1 true)
+(double 1 2)
+(+ y
+   input)
+";
+    let mut child = Command::new(env!("CARGO_BIN_EXE_diamondback"))
+        .args(["repl", "5"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("could not run the compiler");
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let out = stdout(&output).replace(". ", "").replace("> ", "");
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[..5], ["42", "43", "10", "10", "an error ocurred - invalid argument"]);
+    assert!(lines[5].contains("error[arity]"));
+    assert_eq!(lines[6], "15");
+}
+
+#[test]
+fn cli_repl_functions_cannot_use_bindings() {
+    let script = "(define x 5)
+(fun (f y) (+ y x))
+(fun (g x) (+ x 1))
+(g x)
+";
+    let mut child = Command::new(env!("CARGO_BIN_EXE_diamondback"))
+        .args(["repl"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("could not run the compiler");
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    // a parameter of the same name is not the binding
+    let out = stdout(&output).replace("> ", "");
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].contains("function f uses the top-level binding x"));
+    assert_eq!(lines[1], "6");
+}
+
+#[test]
+fn cli_jit_matches_executable() {
+    let output = diamondback(&["--jit", "tests/cobra_input0.snek", "123"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "123");
+
+    let output = diamondback(&["--jit", "tests/arith_divide_by_zero_fail0.snek", "5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("division by zero"));
+}
+
+#[test]
+fn cli_builtin_assembler() {
+    let output = diamondback(&["run", "--assembler", "builtin", "tests/cobra_input0.snek", "42"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "42");
+
+    let obj = Path::new("tests/cli_builtin_assembler.o");
+    let output = diamondback(&["--emit", "obj", "tests/adder_num.snek", obj.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(std::fs::read(obj).unwrap().starts_with(b"\x7fELF"));
+    std::fs::remove_file(obj).unwrap();
+}
+
+#[test]
+fn cli_gas_syntax() {
+    let output = diamondback(&["run", "--syntax", "gas", "tests/cobra_input0.snek", "-7"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "-7");
+
+    let asm = Path::new("tests/cli_gas_syntax.s");
+    let output = diamondback(&["--syntax", "gas", "tests/adder_num.snek", asm.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(asm).unwrap().contains(".intel_syntax noprefix"));
+    std::fs::remove_file(asm).unwrap();
+}
+
+#[test]
+fn cli_c_target() {
+    let output = diamondback(&["run", "--target", "c", "tests/cobra_input0.snek", "-7"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "-7");
+
+    let output = diamondback(&["run", "--target", "c", "tests/arith_divide_by_zero_fail0.snek", "5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("division by zero"));
+
+    let src = Path::new("tests/cli_c_target.c");
+    let output = diamondback(&["--target", "c", "tests/adder_num.snek", src.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(src).unwrap().contains("our_code_starts_here"));
+    std::fs::remove_file(src).unwrap();
+
+    let output = diamondback(&["run", "--target", "c", "--syntax", "gas", "tests/adder_num.snek"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn cli_emit_anf() {
+    let anf = Path::new("tests/cli_emit_anf.anf");
+    let output = diamondback(&["--emit", "anf", "tests/cobra_set_expr3.snek", anf.to_str().unwrap()]);
+    assert!(output.status.success());
+    let text = std::fs::read_to_string(anf).unwrap();
+    assert!(text.starts_with("(main)"));
+    assert!(text.contains("x = false\n  (set! x input)"));
+    std::fs::remove_file(anf).unwrap();
+
+    let output = diamondback(&["--emit", "anf", "--target", "c", "tests/adder_num.snek", "tests/cli_emit_anf.c"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn cli_emit_cfg_dot() {
+    let dot = Path::new("tests/cli_emit_cfg_dot.dot");
+    let output = diamondback(&["--emit", "cfg-dot", "tests/cobra_loop_expr0.snek", dot.to_str().unwrap()]);
+    assert!(output.status.success());
+    let text = std::fs::read_to_string(dot).unwrap();
+    assert!(text.starts_with("digraph cfg {"));
+    assert!(text.contains("subgraph \"cluster_our_code_starts_here\""));
+    // the condition of the if inside the loop branches to one of two blocks
+    assert!(text.contains("[label=\"jg\"]"));
+    std::fs::remove_file(dot).unwrap();
+}
+
+#[test]
+fn cli_constant_folding() {
+    // the sum is folded away, the overflow and the invalid argument are left for run time
+    let anf = Path::new("tests/cli_constant_folding.anf");
+    let output = diamondback(&["--emit", "anf", "tests/boa_binding_nested.snek", anf.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(anf).unwrap().trim(), "(main)\n  1");
+    std::fs::remove_file(anf).unwrap();
+
+    let output = diamondback(&["run", "tests/cobra_number_overflow_fail0.snek"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("overflow"));
+    let output = diamondback(&["run", "tests/cobra_invalid_argument_fail0.snek"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid argument"));
+}
+
+#[test]
+fn cli_unused_functions() {
+    let asm = Path::new("tests/cli_unused_functions.s");
+    let output = diamondback(&["tests/diamondback_many_unused_functions.snek", asm.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("tests/diamondback_many_unused_functions.snek:1:1: warning: function function1 is never used."));
+    assert!(!stderr.contains("function42 is never used"));
+    let text = std::fs::read_to_string(asm).unwrap();
+    assert!(text.contains("\nfun_function42:"));
+    assert!(!text.contains("\nfun_function1:"));
+    std::fs::remove_file(asm).unwrap();
+
+    // a call that folding removes does not count as a use
+    let output = diamondback(&["tests/diamondback_folded_away_call.snek", asm.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("tests/diamondback_folded_away_call.snek:1:1: warning: function f is never used."));
+    std::fs::remove_file(asm).unwrap();
+
+    let dot = Path::new("tests/cli_unused_functions.dot");
+    let output = diamondback(&["--emit", "callgraph", "tests/diamondback_fun_mutual_recursion.snek", dot.to_str().unwrap()]);
+    assert!(output.status.success());
+    let text = std::fs::read_to_string(dot).unwrap();
+    assert!(text.starts_with("digraph callgraph {"));
+    assert!(text.contains("\"(main)\" -> "));
+    std::fs::remove_file(dot).unwrap();
+}
+
+#[test]
+fn cli_inlining() {
+    // (shadow y) is a call without --opt-level and is inlined and folded to 11 with it; fact is recursive and stays a call
+    let anf = Path::new("tests/cli_inlining.anf");
+    let output = diamondback(&["--emit", "anf", "--opt-level", "1", "tests/inline_capture.snek", anf.to_str().unwrap()]);
+    assert!(output.status.success());
+    let text = std::fs::read_to_string(anf).unwrap();
+    assert!(text.contains("(print 11)"));
+    assert!(text.contains("(fact 5)"));
+    let output = diamondback(&["--emit", "anf", "tests/inline_capture.snek", anf.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(anf).unwrap().contains("(shadow 10)"));
+    std::fs::remove_file(anf).unwrap();
+
+    let output = diamondback(&["run", "--opt-level", "2", "tests/inline_capture.snek"]);
+    assert_eq!(stdout(&output), "11\n12\n11\n10\n120\n(tuple 8 12)");
+    let output = diamondback(&["run", "--opt-level", "3", "tests/inline_capture.snek"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn cli_peephole_stats() {
+    let asm = Path::new("tests/cli_peephole_stats.s");
+    let output = diamondback(&["--peephole-stats", "tests/diamondback_recursive_fibonacci.snek", asm.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for rule in diamondback::peephole::RULES {
+        assert!(stderr.contains(rule.name));
+    }
+    // the constant operand of (<= n 1) goes straight to rbx without a number check
+    let text = std::fs::read_to_string(asm).unwrap();
+    assert!(text.contains("\nmov rbx, 2\n"));
+    assert!(!text.contains("\nmov rax, 2\ntest rax,1"));
+    std::fs::remove_file(asm).unwrap();
+
+    let output = diamondback(&["--target", "c", "--peephole-stats", "tests/adder_num.snek", "tests/cli_peephole_stats.c"]);
+    assert_eq!(output.status.code(), Some(2));
+}
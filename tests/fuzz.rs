@@ -0,0 +1,153 @@
+use std::{
+    env,
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use diamondback::fuzz;
+use diamondback::Program;
+
+// Differential testing: random programs are compiled, assembled and run, and the result
+// is compared with the reference interpreter (`diamondback --interp`). FUZZ_SEED and
+// FUZZ_COUNT select the programs, e.g.
+//
+//     FUZZ_SEED=7 FUZZ_COUNT=500 cargo test --test fuzz
+//
+// A program on which the two disagree is shrunk and written to tests/fuzz_fail_<seed>.snek,
+// with the input and both results in comments at the top, and the test fails.
+
+const DEFAULT_SEED: u64 = 231;
+const DEFAULT_COUNT: u64 = 20;
+const SIZE: usize = 5;
+
+// a run that takes longer than this counts as neither a pass nor a failure
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(PartialEq)]
+enum Outcome {
+    Exit { success: bool, stdout: String, stderr: String },
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Exit { success, stdout, stderr } => write!(
+                f, "{} stdout `{}` stderr `{}`",
+                if *success { "ok" } else { "failed" },
+                stdout.trim().replace('\n', "\\n"),
+                stderr.trim().replace('\n', "\\n"),
+            ),
+            Outcome::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+#[test]
+fn fuzz_compiled_against_interpreter() {
+    let seed = env_u64("FUZZ_SEED", DEFAULT_SEED);
+    let count = env_u64("FUZZ_COUNT", DEFAULT_COUNT);
+
+    let mut failures = Vec::new();
+    for case in seed..seed + count {
+        let mut rng = fuzz::Rng::new(case);
+        let p = fuzz::gen_program(&mut rng, SIZE);
+        let input = match rng.below(10) {
+            0 => String::from("true"),
+            1 => String::from("false"),
+            _ => (rng.below(41) as i64 - 20).to_string(),
+        };
+
+        let name = format!("fuzz_{case}");
+        if mismatch(&name, &p, &input).is_none() {
+            continue;
+        }
+        let small = fuzz::shrink(&p, |candidate| mismatch(&name, candidate, &input).is_some());
+        let (compiled, interpreted) = mismatch(&name, &small, &input).expect("the shrunk program stopped failing");
+
+        let path = Path::new("tests").join(format!("fuzz_fail_{case}.snek"));
+        let report = format!(
+            "; found by tests/fuzz.rs with FUZZ_SEED={case}, run with input {input}\n; compiled:    {compiled}\n; interpreted: {interpreted}\n{small}"
+        );
+        std::fs::write(&path, report).expect("could not write the failing program");
+        failures.push(path);
+    }
+    assert!(failures.is_empty(), "the compiled programs and the interpreter disagree, see {failures:?}");
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    env::var(name).map_or(default, |val| val.parse().expect("expected a number"))
+}
+
+// runs p both ways, returning the two outcomes when they differ
+fn mismatch(name: &str, p: &Program, input: &str) -> Option<(Outcome, Outcome)> {
+    let snek = mk_path(name, "snek");
+    let run = mk_path(name, "run");
+
+    let src = p.to_string();
+    if let Err(err) = diamondback::compile_source(&src) {
+        panic!("generated program does not compile: {err}\n{src}");
+    }
+    std::fs::write(&snek, &src).expect("could not write the program");
+
+    let compiler = env!("CARGO_BIN_EXE_diamondback");
+    let output = Command::new(compiler).arg("build").arg(&snek).arg("-o").arg(&run).output().expect("could not run the compiler");
+    assert!(output.status.success(), "building failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let compiled = run_with_timeout(Command::new(&run).arg(input));
+    let interpreted = run_with_timeout(Command::new(compiler).arg("--interp").arg(&snek).arg(input));
+    clean(name);
+
+    if compiled == Outcome::Timeout || interpreted == Outcome::Timeout || compiled == interpreted {
+        None
+    } else {
+        Some((compiled, interpreted))
+    }
+}
+
+// the pipes are read on their own threads while we wait, so a program that prints more
+// than fits in a pipe does not block until the deadline
+fn run_with_timeout(cmd: &mut Command) -> Outcome {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("could not start the program");
+    let stdout = read_in_background(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_in_background(child.stderr.take().expect("stderr is piped"));
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("could not wait for the program") {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Outcome::Timeout;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    Outcome::Exit {
+        success: status.success(),
+        stdout: stdout.join().expect("could not read the program output"),
+        stderr: stderr.join().expect("could not read the program output"),
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+fn clean(name: &str) {
+    for ext in ["snek", "run"] {
+        let _ = std::fs::remove_file(mk_path(name, ext));
+    }
+}
+
+fn mk_path(name: &str, ext: &str) -> PathBuf {
+    Path::new("tests").join(format!("{name}.{ext}"))
+}
@@ -3,6 +3,11 @@ use std::{
     process::Command,
 };
 
+use diamondback::CompileError;
+use diamondback::Program;
+use diamondback::Syntax;
+use diamondback::jit::JitError;
+
 pub(crate) enum TestKind {
     Success,
     RuntimeError,
@@ -32,6 +37,7 @@ macro_rules! tests {
                 name: $name:ident,
                 file: $file:literal,
                 $(input: $input:literal,)?
+                $(heap_size: $heap_size:literal,)?
                 expected: $expected:literal $(,)?
                 $(" $(tt:$tt)* ")?
             }
@@ -44,8 +50,11 @@ macro_rules! tests {
                 #[allow(unused_assignments, unused_mut)]
                 let mut input = None;
                 $(input = Some($input);)?
+                #[allow(unused_assignments, unused_mut)]
+                let mut heap_size = None;
+                $(heap_size = Some($heap_size);)?
                 let kind = $crate::infra::TestKind::$kind;
-                $crate::infra::run_test(stringify!($name), $file, input, $expected, kind);
+                $crate::infra::run_test(stringify!($name), $file, input, heap_size, $expected, kind);
             }
         )*
     };
@@ -55,22 +64,24 @@ pub(crate) fn run_test(
     name: &str,
     file: &str,
     input: Option<&str>,
+    heap_size: Option<usize>,
     expected: &str,
     kind: TestKind,
 ) {
     let file = Path::new("tests").join(file);
     match kind {
-        TestKind::Success => run_success_test(name, &file, expected, input),
-        TestKind::RuntimeError => run_runtime_error_test(name, &file, expected, input),
+        TestKind::Success => run_success_test(name, &file, expected, input, heap_size),
+        TestKind::RuntimeError => run_runtime_error_test(name, &file, expected, input, heap_size),
         TestKind::StaticError => run_static_error_test(name, &file, expected),
     }
 }
 
-fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input) {
+fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>, heap_size: Option<usize>) {
+    let p = match compile(name, file) {
+        Ok(p) => p,
+        Err(errors) => panic!("expected a successful compilation, but got an error: `{}`", errors[0]),
+    };
+    match run(name, &p, input, heap_size) {
         Err(err) => {
             panic!("expected a successful execution, but got an error: `{err}`");
         }
@@ -78,58 +89,138 @@ fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>
             diff(expected, actual_output);
         }
     }
+    if heap_size.is_none() {
+        match interp(file, input) {
+            Err(err) => {
+                panic!("expected a successful interpretation, but got an error: `{err}`");
+            }
+            Ok(actual_output) => {
+                diff(expected, actual_output);
+            }
+        }
+    }
 }
 
-fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input) {
+fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option<&str>, heap_size: Option<usize>) {
+    let p = match compile(name, file) {
+        Ok(p) => p,
+        Err(errors) => panic!("expected a successful compilation, but got an error: `{}`", errors[0]),
+    };
+    match run(name, &p, input, heap_size) {
         Ok(out) => {
             panic!("expected a runtime error, but program executed succesfully - expected error: `{expected}`, output: `{out}`");
         }
         Err(err) => check_error_msg(&err, expected),
     }
+    if heap_size.is_none() {
+        match interp(file, input) {
+            Ok(out) => {
+                panic!("expected a runtime error, but program was interpreted succesfully - expected error: `{expected}`, output: `{out}`");
+            }
+            Err(err) => check_error_msg(&err, expected),
+        }
+    }
 }
 
 fn run_static_error_test(name: &str, file: &Path, expected: &str) {
     match compile(name, file) {
-        Ok(()) => {
+        Ok(_) => {
             panic!(
                 "expected a static error, but compilation succeeded - expected error: `{expected}`"
             )
         }
-        Err(err) => check_error_msg(&err, expected),
+        Err(err) => check_error_kind(&err, expected),
     }
 }
 
-fn compile(name: &str, file: &Path) -> Result<(), String> {
-    // Run the compiler
-    let compiler: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
-    let output = Command::new(&compiler)
-        .arg(file)
-        .arg(&mk_path(name, Ext::Asm))
-        .output()
-        .expect("could not run the compiler");
-    if !output.status.success() {
-        return Err(String::from_utf8(output.stderr).unwrap());
+// How the compiled programs are run, picked with SNEK_TEST_MODE:
+//
+//     jit       in process (the default), needs no external tools
+//     nasm      assembled with nasm and linked with the runtime
+//     gas       written for GNU as instead, then linked the same way
+//     builtin   encoded straight to an ELF object and linked with the runtime
+//     c         translated to C by the C backend and built with cc
+#[derive(Copy, Clone)]
+enum Mode {
+    Jit,
+    Text(Syntax),
+    Builtin,
+    C,
+}
+
+fn mode() -> Mode {
+    match std::env::var("SNEK_TEST_MODE").as_deref() {
+        Err(_) | Ok("jit") => Mode::Jit,
+        Ok("nasm") => Mode::Text(Syntax::Nasm),
+        Ok("gas") => Mode::Text(Syntax::Gas),
+        Ok("builtin") => Mode::Builtin,
+        Ok("c") => Mode::C,
+        Ok(mode) => panic!("unknown SNEK_TEST_MODE `{mode}`, expected jit, nasm, gas, builtin or c"),
     }
+}
 
-    // Assemble and link
-    let output = Command::new("make")
-        .arg(&mk_path(name, Ext::Run))
-        .output()
-        .expect("could not run make");
-    assert!(output.status.success(), "linking failed");
+// The optimization level the programs are compiled at, picked with SNEK_OPT_LEVEL (0, the
+// default, to 2) the same way as with --opt-level
+fn inline_threshold() -> usize {
+    match std::env::var("SNEK_OPT_LEVEL").as_deref() {
+        Err(_) => 0,
+        Ok(level) => match level.parse() {
+            Ok(level @ 0..=2) => diamondback::inline::threshold(level),
+            _ => panic!("unknown SNEK_OPT_LEVEL `{level}`, expected 0, 1 or 2"),
+        },
+    }
+}
 
-    Ok(())
+fn compile(name: &str, file: &Path) -> Result<Program, Vec<CompileError>> {
+    // Run the compiler through the library, keeping every well-formedness error
+    let src = std::fs::read_to_string(file).expect("could not read the test program");
+    let p = diamondback::parse_program(&src).map_err(|err| vec![err])?;
+    let errors = diamondback::check_program(&p);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let p = diamondback::inline::program(&p, inline_threshold());
+    let syntax = match mode() {
+        Mode::Text(syntax) => syntax,
+        Mode::Jit | Mode::Builtin | Mode::C => Syntax::Nasm,
+    };
+    let asm = diamondback::compile_with(&p, syntax).map_err(|err| vec![err])?;
+    std::fs::write(mk_path(name, Ext::Asm), &asm).expect("could not write the assembly");
+
+    // Assemble and link
+    let linked = match mode() {
+        Mode::Jit => return Ok(p),
+        Mode::Text(syntax) => diamondback::driver::build_executable(&asm, syntax, &mk_path(name, Ext::Run)),
+        Mode::Builtin => {
+            let instrs = diamondback::compiler::compile(&p).map_err(|err| vec![err])?;
+            let obj = diamondback::elf::object(&instrs).unwrap_or_else(|err| panic!("encoding failed: {err}"));
+            diamondback::driver::link_object(&obj, &mk_path(name, Ext::Run))
+        }
+        Mode::C => {
+            let src = diamondback::c::compile(&p).map_err(|err| vec![err])?;
+            diamondback::driver::build_c(&src, &mk_path(name, Ext::Run))
+        }
+    };
+    if let Err(err) = linked {
+        panic!("linking failed: {err}");
+    }
+    Ok(p)
 }
 
-fn run(name: &str, input: Option<&str>) -> Result<String, String> {
-    let mut cmd = Command::new(&mk_path(name, Ext::Run));
+fn run(name: &str, p: &Program, input: Option<&str>, heap_size: Option<usize>) -> Result<String, String> {
+    if let Mode::Jit = mode() {
+        return run_jit(p, input, heap_size);
+    }
+    let mut cmd = Command::new(mk_path(name, Ext::Run));
     if let Some(input) = input {
         cmd.arg(input);
     }
+    if let Some(heap_size) = heap_size {
+        if input.is_none() {
+            cmd.arg("false");
+        }
+        cmd.arg(heap_size.to_string());
+    }
     let output = cmd.output().unwrap();
     if output.status.success() {
         Ok(String::from_utf8(output.stdout).unwrap().trim().to_string())
@@ -138,6 +229,31 @@ fn run(name: &str, input: Option<&str>) -> Result<String, String> {
     }
 }
 
+// Runs the compiled program in process, so the tests need neither nasm nor a linker
+fn run_jit(p: &Program, input: Option<&str>, heap_size: Option<usize>) -> Result<String, String> {
+    let input = diamondback::interp::parse_input(input.unwrap_or("false")).expect("invalid input");
+    let heap_size = heap_size.unwrap_or(diamondback::jit::DEFAULT_HEAP_SIZE);
+    let mut out = Vec::new();
+    match diamondback::jit::run(p, input, heap_size, &mut out) {
+        Ok(()) => Ok(String::from_utf8(out).unwrap().trim().to_string()),
+        Err(JitError::Runtime(err)) => Err(err.to_string()),
+        Err(err) => panic!("could not run the program: {err}"),
+    }
+}
+
+// Runs the program with the reference interpreter, which must agree with the compiled
+// program. Tests that set a heap size are skipped since the interpreter's heap is unbounded.
+fn interp(file: &Path, input: Option<&str>) -> Result<String, String> {
+    let src = std::fs::read_to_string(file).expect("could not read the test program");
+    let p = diamondback::parse_program(&src).expect("the test program was already compiled");
+    let input = diamondback::interp::parse_input(input.unwrap_or("false")).expect("invalid input");
+    let mut out = Vec::new();
+    match diamondback::interp::run(&p, input, &mut out) {
+        Ok(()) => Ok(String::from_utf8(out).unwrap().trim().to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 fn check_error_msg(found: &str, expected: &str) {
     let lower_found = found.trim().to_lowercase();
     let lower_expected = expected.trim().to_lowercase();
@@ -147,6 +263,35 @@ fn check_error_msg(found: &str, expected: &str) {
     );
 }
 
+// Static errors are sorted by location. `expected` is an error kind, optionally followed by
+// `@line:col` to also check the location. A single entry is matched against the first error;
+// a comma-separated list must match every error in order.
+fn check_error_kind(found: &[CompileError], expected: &str) {
+    let expected: Vec<&str> = expected.split(',').map(str::trim).collect();
+    if expected.len() > 1 {
+        assert_eq!(
+            found.len(), expected.len(),
+            "the number of reported errors differs - found: `{found:?}`, expected: `{expected:?}`",
+        );
+    }
+    for (err, expected) in found.iter().zip(expected) {
+        let (expected_kind, expected_loc) = match expected.split_once('@') {
+            Some((kind, loc)) => (kind, Some(loc)),
+            None => (expected, None),
+        };
+        assert_eq!(
+            err.kind.to_string(), expected_kind,
+            "the reported error kind differs - found: `{err}`, expected: `{expected}`",
+        );
+        if let Some(loc) = expected_loc {
+            assert_eq!(
+                err.span.to_string(), loc,
+                "the reported error location differs - found: `{err}`, expected: `{expected}`",
+            );
+        }
+    }
+}
+
 fn diff(expected: &str, found: String) {
     let expected = expected.trim();
 